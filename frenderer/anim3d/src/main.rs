@@ -1,8 +1,15 @@
 #![allow(dead_code)]
 
 use frenderer::animation::{AnimationSettings, AnimationState};
-use frenderer::assets::{AnimRef, Texture};
+use frenderer::assets::{AnimRef, FontRef, RenderTargetRef, Texture, TextureRef};
+use frenderer::font::{Align, TextStyle};
+use frenderer::mesh_builder::MeshBuilder;
 use frenderer::camera::{Camera, FPCamera};
+use frenderer::renderer::skybox::Sky;
+use frenderer::renderer::overlay::{Anchor, Placement};
+use frenderer::renderer::particles::{Curve, Emitter, EmitterSettings};
+use frenderer::renderer::post::PostEffect;
+use frenderer::renderer::{Fog, FogMode, Outline, RenderMode};
 use frenderer::renderer::textured::Model;
 use frenderer::types::*;
 use frenderer::{Engine, Key, MousePos, Result, WindowSettings};
@@ -44,8 +51,12 @@ const ROOM_LENGTH: f32 = 295.0;
 const PLAYER_HEIGHT: f32 = WALL_HEIGHT / 2.;
 
 const FIX: Vec2 = Vec2::new(45.0, 45.0);
+// a little below where the walls stand, as the old floor model was
+const FLOOR_Y: f32 = -17.0;
 
 //let's call this the radius of each
+// how far above the player the minimap camera floats
+const MINIMAP_HEIGHT: f32 = ROOM_WIDTH * 2.0;
 const COLLIS_THRESHHOLD: f32 = (WALL_WIDTH / 2.) + (PLAYER_HEIGHT / 2.);
 
 struct Circle {
@@ -186,7 +197,6 @@ impl Player {
         self.find_current_room();
 
         if self.current_room == 5 {
-            println!("\n \n \n \n YOU WIN!!! \n \n \n \n ");
             self.game_won = true;
             return;
         }
@@ -211,7 +221,7 @@ impl Player {
             let keys = self.map.room_keys.get_mut(&self.current_room).unwrap();
             dbg!(&keys);
 
-            if pos < keys.len() && pos < textureds.len() - 3 {
+            if pos < keys.len() && pos < textureds.len() - 2 {
                 let key = keys.remove(pos);
                 key.pick_up(self);
 
@@ -349,6 +359,7 @@ fn gen_key_pair(
     let texture = Textured {
         trf,
         model: Rc::clone(model),
+        sparkle: None,
     };
 
     let key = RoomKey {
@@ -490,6 +501,24 @@ pub struct World {
     sprites: Vec<Sprite>,
     flats: Vec<Flat>,
     textured: Vec<Textured>,
+    show_collision: bool,
+    // cycled with V, to inspect meshes
+    render_mode: RenderMode,
+    // cycled with B
+    skies: Vec<Sky>,
+    sky: usize,
+    hud: Hud,
+    // one-shot bursts of dust from opening doors
+    dust: Vec<Emitter>,
+    dust_settings: EmitterSettings,
+}
+// Textures for the screen overlay
+pub struct Hud {
+    key_icon: TextureRef,
+    win: TextureRef,
+    font: FontRef,
+    // a top-down view around the player
+    minimap: RenderTargetRef,
 }
 pub struct Flat {
     trf: Similarity3,
@@ -507,6 +536,7 @@ impl fmt::Debug for Flat {
 pub struct Textured {
     trf: Similarity3,
     model: Rc<frenderer::renderer::textured::Model>,
+    sparkle: Option<Emitter>,
 }
 
 impl fmt::Debug for Textured {
@@ -517,6 +547,179 @@ impl fmt::Debug for Textured {
     }
 }
 
+impl World {
+    fn render_hud(
+        &self,
+        assets: &frenderer::assets::Assets,
+        rs: &mut frenderer::renderer::RenderState,
+    ) {
+        let whole = Rect::new(0.0, 1.0, 1.0, 1.0);
+        // one key icon per key collected, along the top left corner
+        for i in 0..self.player.keys_grabbed.len() {
+            rs.render_overlay(
+                self.hud.key_icon,
+                whole,
+                Placement::pixels(
+                    Anchor::TopLeft,
+                    Vec2::new(16.0 + i as f32 * 88.0, 16.0),
+                    Vec2::new(80.0, 30.0),
+                ),
+                0,
+                i,
+            );
+        }
+        let keys = self.player.keys_grabbed.len();
+        rs.render_overlay_text(
+            self.hud.font,
+            &format!("Keys: {}", keys),
+            TextStyle {
+                size: 28.0,
+                ..TextStyle::default()
+            },
+            Anchor::TopRight,
+            Vec2::new(16.0, 16.0),
+            0,
+            keys,
+        );
+        rs.render_overlay(
+            assets.render_target(self.hud.minimap).texture,
+            whole,
+            Placement::pixels(Anchor::BottomLeft, Vec2::new(16.0, 16.0), Vec2::new(192.0, 192.0)),
+            0,
+            keys + 3,
+        );
+        if self.player.game_won {
+            rs.render_overlay(
+                self.hud.win,
+                whole,
+                Placement::normalized(Anchor::Center, Vec2::zero(), Vec2::new(0.6, 0.6)),
+                1,
+                keys + 1,
+            );
+            rs.render_overlay_text(
+                self.hud.font,
+                "YOU WIN!!!",
+                TextStyle {
+                    size: 64.0,
+                    color: Vec4::new(1.0, 0.85, 0.2, 1.0),
+                    align: Align::Center,
+                    ..TextStyle::default()
+                },
+                Anchor::Bottom,
+                Vec2::new(0.0, 48.0),
+                2,
+                keys + 2,
+            );
+        }
+    }
+    // Everything in the level, for the main view and the minimap alike
+    fn render_scene(&mut self, rs: &mut frenderer::renderer::RenderState) {
+        for (obj_i, obj) in self.things.iter_mut().enumerate() {
+            rs.render_skinned(obj.model.clone(), obj.animation, obj.state, obj.trf, obj_i);
+        }
+        for (s_i, s) in self.sprites.iter_mut().enumerate() {
+            rs.render_sprite(s.tex, s.cel, s.trf, s.size, s_i);
+        }
+        let obj = &self.player.object;
+        rs.render_skinned(obj.model.clone(), obj.animation, obj.state, obj.trf, 0);
+        for (m_i, m) in self.flats.iter_mut().enumerate() {
+            let mut rendered = false;
+            for key in self.player.keys_grabbed.iter_mut() {
+                if key.opens_wallid.eq(&m_i) {
+                    //also change the wall vec to be none for this one
+                    self.player.map.walls[m_i].wall = None;
+                    rs.render_flat(m.open_model.clone(), m.trf, m_i);
+                    rendered = true;
+                    break;
+                }
+            }
+            if !rendered {
+                rs.render_flat(m.model.clone(), m.trf, m_i);
+            }
+        }
+        // color-code the sparkling keys so they're easier to tell apart
+        let key_tints = [
+            Vec4::new(1.0, 0.85, 0.4, 1.0),
+            Vec4::new(0.5, 0.8, 1.0, 1.0),
+            Vec4::new(1.0, 0.5, 0.6, 1.0),
+        ];
+        // outline whatever Player::grab would pick up right now
+        let player_pos = self.player.object.trf.translation;
+        let grabbable = self
+            .textured
+            .iter()
+            .position(|t| distance(player_pos, t.trf.translation) < GRAB_THRESHOLD);
+        for (t_i, t) in self.textured.iter_mut().enumerate() {
+            let tint = if t.sparkle.is_some() {
+                key_tints[t_i % key_tints.len()]
+            } else {
+                Vec4::one()
+            };
+            let outline = if grabbable == Some(t_i) && t.sparkle.is_some() {
+                Some(Outline {
+                    color: Vec4::new(1.0, 1.0, 0.6, 1.0),
+                    width: 1.5,
+                })
+            } else {
+                None
+            };
+            rs.render_textured(t.model.clone(), t.trf, t_i)
+                .tint(tint)
+                .outline(outline);
+            if let Some(sparkle) = &t.sparkle {
+                rs.render_particles(sparkle, t_i);
+            }
+        }
+        for (d_i, dust) in self.dust.iter().enumerate() {
+            rs.render_particles(dust, self.textured.len() + d_i);
+        }
+    }
+    // Draw the wall boxes, room bounds and player circle used for collision
+    fn render_collision(&self, rs: &mut frenderer::renderer::RenderState) {
+        let floor_y = -15.0;
+        let wall_color = Vec4::new(1.0, 0.2, 0.2, 1.0);
+        for wall in self.player.map.walls.iter() {
+            if let Some(r) = &wall.wall {
+                rs.debug_aabb(
+                    Vec3::new(r.center.x - r.half_widths.x, floor_y, r.center.y - r.half_widths.y),
+                    Vec3::new(
+                        r.center.x + r.half_widths.x,
+                        floor_y + WALL_HEIGHT,
+                        r.center.y + r.half_widths.y,
+                    ),
+                    wall_color,
+                );
+            }
+        }
+        for (id, room) in self.player.map.rooms_list.iter() {
+            let color = if *id == self.player.current_room {
+                Vec4::new(0.2, 1.0, 0.2, 1.0)
+            } else {
+                Vec4::new(0.2, 0.2, 1.0, 1.0)
+            };
+            let [x, z] = room.bottom_left_corner;
+            rs.debug_aabb(
+                Vec3::new(x, floor_y, z),
+                Vec3::new(x + ROOM_WIDTH, floor_y, z + ROOM_LENGTH),
+                color,
+            );
+        }
+        let shape = self.player.shape();
+        rs.debug_depth_test(false);
+        rs.debug_sphere(
+            Vec3::new(shape.center.x, floor_y, shape.center.y),
+            shape.radius.max(COLLIS_THRESHHOLD),
+            Vec4::new(1.0, 1.0, 0.0, 1.0),
+        );
+        rs.debug_axes(Similarity3::new(
+            self.player.object.trf.translation,
+            self.player.object.trf.rotation,
+            GRAB_THRESHOLD,
+        ));
+        rs.debug_depth_test(true);
+    }
+}
+
 impl frenderer::World for World {
     fn update(&mut self, input: &frenderer::Input, _assets: &mut frenderer::assets::Assets) {
         //let yaw = input.key_axis(Key::Q, Key::W) * PI / 4.0 * DT as f32;
@@ -537,6 +740,15 @@ impl frenderer::World for World {
         let move_z = input.key_axis(Key::S, Key::W) as f32;
         let move_x = input.key_axis(Key::D, Key::A) as f32;
         let grab = input.is_key_released(Key::Space);
+        if input.is_key_pressed(Key::C) {
+            self.show_collision = !self.show_collision;
+        }
+        if input.is_key_pressed(Key::V) {
+            self.render_mode = self.render_mode.next();
+        }
+        if input.is_key_pressed(Key::B) {
+            self.sky = (self.sky + 1) % self.skies.len();
+        }
         let find_room = input.is_key_released(Key::F);
         self.player.find_current_room();
         let is_in_doorway = self.player.is_in_doorway(&self);
@@ -545,8 +757,23 @@ impl frenderer::World for World {
         // }
 
         if grab {
+            let had_keys = self.player.keys_grabbed.len();
             self.player.grab(&mut self.textured);
+            for key in self.player.keys_grabbed[had_keys..].iter() {
+                let door = self.flats[key.opens_wallid].trf.translation;
+                let mut dust = Emitter::new(self.dust_settings.clone(), door);
+                dust.active = false;
+                dust.burst(60);
+                self.dust.push(dust);
+            }
+        }
+        for sparkle in self.textured.iter_mut().filter_map(|t| t.sparkle.as_mut()) {
+            sparkle.tick(DT);
         }
+        for dust in self.dust.iter_mut() {
+            dust.tick(DT);
+        }
+        self.dust.retain(|d| !d.is_finished());
 
         let player_shape = &self.player.shape();
         let player = &mut self.player.object;
@@ -662,43 +889,66 @@ impl frenderer::World for World {
     }
     fn render(
         &mut self,
-        _a: &mut frenderer::assets::Assets,
+        assets: &mut frenderer::assets::Assets,
         rs: &mut frenderer::renderer::RenderState,
     ) {
         rs.set_camera(self.camera);
-        for (obj_i, obj) in self.things.iter_mut().enumerate() {
-            rs.render_skinned(obj.model.clone(), obj.animation, obj.state, obj.trf, obj_i);
-        }
-        for (s_i, s) in self.sprites.iter_mut().enumerate() {
-            rs.render_sprite(s.tex, s.cel, s.trf, s.size, s_i);
-        }
-        let obj = &self.player.object;
-        rs.render_skinned(obj.model.clone(), obj.animation, obj.state, obj.trf, 0);
-        for (m_i, m) in self.flats.iter_mut().enumerate() {
-            let mut rendered = false;
-            for key in self.player.keys_grabbed.iter_mut() {
-                if key.opens_wallid.eq(&m_i) {
-                    //also change the wall vec to be none for this one
-                    self.player.map.walls[m_i].wall = None;
-                    rs.render_flat(m.open_model.clone(), m.trf, m_i);
-                    rendered = true;
-                    break;
-                }
-            }
-            if !rendered {
-                rs.render_flat(m.model.clone(), m.trf, m_i);
-            }
-        }
-        for (t_i, t) in self.textured.iter_mut().enumerate() {
-            rs.render_textured(t.model.clone(), t.trf, t_i);
+        rs.set_render_mode(self.render_mode);
+        rs.set_sky(Some(self.skies[self.sky]));
+        // hide the far end of long sightlines across the room grid
+        rs.set_fog(Some(Fog {
+            mode: FogMode::Linear,
+            color: Vec4::new(0.75, 0.85, 0.95, 1.0),
+            density: 0.0,
+            start: ROOM_WIDTH,
+            end: ROOM_WIDTH * 3.0,
+        }));
+        let player_pos = self.player.object.trf.translation;
+        let minimap = rs.render_target(
+            self.hud.minimap,
+            Camera::look_at(
+                player_pos + Vec3::new(0.0, MINIMAP_HEIGHT, 0.0),
+                player_pos,
+                Vec3::unit_z(),
+            ),
+        );
+        minimap.set_clear_color(Vec4::new(0.1, 0.1, 0.15, 1.0));
+        self.render_scene(minimap);
+        self.render_scene(rs);
+        if self.show_collision {
+            self.render_collision(rs);
         }
+        self.render_hud(assets, rs);
     }
 }
 
 fn main() -> Result<()> {
     frenderer::color_eyre::install()?;
 
-    let mut engine: Engine = Engine::new(WindowSettings::default(), DT);
+    // `--screenshot out.png` renders a frame without opening a window, then quits
+    let screenshot = std::env::args()
+        .skip_while(|a| a != "--screenshot")
+        .nth(1);
+    let ws = WindowSettings {
+        post_effects: vec![
+            PostEffect::Bloom {
+                threshold: 0.9,
+                intensity: 0.6,
+            },
+            PostEffect::Tonemap { exposure: 1.2 },
+            PostEffect::Vignette {
+                strength: 0.4,
+                radius: 0.6,
+            },
+            PostEffect::Fxaa,
+        ],
+        ..WindowSettings::default()
+    };
+    let mut engine: Engine = if screenshot.is_some() {
+        Engine::headless(ws, DT)
+    } else {
+        Engine::new(ws, DT)
+    };
 
     let camera = Camera::look_at(
         Vec3::new(0., 100., 100.),
@@ -711,8 +961,8 @@ fn main() -> Result<()> {
     let key_meshes = engine.load_textured(std::path::Path::new("content/silver-key.obj"))?;
     let key = engine.create_textured_model(key_meshes, vec![key_tex]);
     let floor_tex = engine.load_texture(std::path::Path::new("content/marble-floor.png"))?;
-    let floor_meshes = engine.load_textured(std::path::Path::new("content/floor.obj"))?;
-    let floor = engine.create_textured_model(floor_meshes, vec![floor_tex]);
+    // the marble is close enough to 2:1 to stand in for a panorama
+    let marble_sky = Sky::Equirectangular(floor_tex);
 
     let wall_with_door_closed_model = engine.load_flat(std::path::Path::new(
         "content/walls/wall_with_door_closed.glb",
@@ -720,28 +970,41 @@ fn main() -> Result<()> {
     let wall_with_door_opened_model = engine.load_flat(std::path::Path::new(
         "content/walls/wall_with_door_opened.glb",
     ))?;
-    let wall_no_door_model =
-        engine.load_flat(std::path::Path::new("content/walls/wall_no_door.glb"))?;
+    // the plain wall, modeled like the ones with doors: wooden posts with
+    // stone panels between them, 3 by 1 before scaling
+    let mut wall_stone = MeshBuilder::new();
+    wall_stone.append(
+        &MeshBuilder::cuboid(Vec3::new(0.05, 1.0, 3.0)),
+        Similarity3::new(Vec3::new(-0.45, 0.5, 0.0), Rotor3::identity(), 1.0),
+    );
+    let mut wall_wood = MeshBuilder::new();
+    for (z0, z1) in [(-1.5, -1.4), (-0.6, -0.4), (0.4, 0.6), (1.4, 1.5)] {
+        wall_wood.append(
+            &MeshBuilder::cuboid(Vec3::new(0.1, 1.0, z1 - z0)),
+            Similarity3::new(Vec3::new(-0.45, 0.5, (z0 + z1) / 2.0), Rotor3::identity(), 1.0),
+        );
+    }
+    let wall_meshes = vec![
+        engine.create_flat_mesh(&wall_stone)?,
+        engine.create_flat_mesh(&wall_wood)?,
+    ];
+    let wall_materials = vec![
+        engine.create_material("wall stone", Vec4::new(0.604, 0.733, 0.788, 1.0))?,
+        engine.create_material("wall wood", Vec4::new(0.91, 0.549, 0.349, 1.0))?,
+    ];
+    let wall_no_door_model = engine.create_flat_model(wall_meshes, wall_materials);
 
-    let trophy_tex = engine.load_texture(std::path::Path::new("content/gold-trophy.png"))?;
-    let trophy_meshes =
-        engine.load_textured(std::path::Path::new("content/trophyobjectfile.obj"))?;
-    let trophy = engine.create_textured_model(trophy_meshes, vec![trophy_tex, trophy_tex]);
+    let trophy =
+        engine.load_textured_model(std::path::Path::new("content/trophyobjectfile.obj"))?;
     let trophy_texture = Textured {
         trf: Similarity3::new(Vec3::new(-200., 0.0, 610.), Rotor3::from_rotation_xz(-std::f32::consts::FRAC_PI_2), 5.0),
         model: Rc::clone(&trophy),
+        sparkle: None,
     };
 
     let win_tex = engine.load_texture(std::path::Path::new("content/you_win.png"))?;
-    let win_meshes =
-        engine.load_textured(std::path::Path::new("content/floor.obj"))?;
-    let win = engine.create_textured_model(win_meshes, vec![win_tex]);
-    let rot = Rotor3::from_rotation_yz(-std::f32::consts::FRAC_PI_2);
-    let trf = Similarity3::new(Vec3::new(-1024.5, 1.0, 600.), rot, 1.0);
-    let win_texture = Textured {
-        trf,
-        model: Rc::clone(&win),
-    };
+    let font = engine.load_font(std::path::Path::new("content/DejaVuSans.ttf"), 64.0)?;
+    let minimap = engine.create_render_target(256, 256)?;
 
     let tex = engine.load_texture(std::path::Path::new("content/robot.png"))?;
     let meshes = engine.load_skinned(
@@ -795,6 +1058,26 @@ fn main() -> Result<()> {
         );
     }
 
+    // one floor for every room, tiled with a marble slab per 100 units
+    let mut floors = MeshBuilder::new();
+    let room_floor = MeshBuilder::plane(
+        Vec2::new(ROOM_WIDTH, ROOM_LENGTH),
+        Vec2::new(ROOM_WIDTH, ROOM_LENGTH) / 100.0,
+    );
+    for room in map.rooms_list.values() {
+        let [x, z] = room.bottom_left_corner;
+        floors.append(
+            &room_floor,
+            Similarity3::new(
+                Vec3::new(x + ROOM_WIDTH / 2.0, FLOOR_Y, z + ROOM_LENGTH / 2.0),
+                Rotor3::identity(),
+                1.0,
+            ),
+        );
+    }
+    let floor_mesh = engine.create_textured_mesh(&floors)?;
+    let floor = engine.create_textured_model(vec![floor_mesh], vec![floor_tex]);
+
     let mut flats_vec: Vec<Flat> = vec![];
     let mut walls_vec: Vec<Wall> = vec![];
     let flats = json.get("flats").unwrap();
@@ -889,6 +1172,40 @@ fn main() -> Result<()> {
 
     map.add_mult_keys(keys);
 
+    let sparkle_tex = engine.load_texture(std::path::Path::new("content/sparkle.png"))?;
+    let sparkle_settings = EmitterSettings {
+        spawn_rate: 12.0,
+        lifetime: (0.6, 1.2),
+        spawn_extent: Vec3::new(6.0, 3.0, 6.0),
+        velocity: Vec3::new(0.0, 8.0, 0.0),
+        velocity_spread: Vec3::new(4.0, 4.0, 4.0),
+        color: Curve::new(vec![
+            (0.0, Vec4::new(1.0, 0.9, 0.4, 0.0)),
+            (0.2, Vec4::new(1.0, 0.9, 0.4, 1.0)),
+            (1.0, Vec4::new(1.0, 0.8, 0.2, 0.0)),
+        ]),
+        size: Curve::linear(4.0, 1.0),
+        additive: true,
+        ..EmitterSettings::new(sparkle_tex)
+    };
+    for key in key_textureds.iter_mut() {
+        key.sparkle = Some(Emitter::new(sparkle_settings.clone(), key.trf.translation));
+    }
+    let dust_settings = EmitterSettings {
+        lifetime: (1.0, 2.0),
+        spawn_extent: Vec3::new(20.0, 10.0, 20.0),
+        velocity: Vec3::zero(),
+        velocity_spread: Vec3::new(40.0, 15.0, 40.0),
+        gravity: Vec3::new(0.0, -10.0, 0.0),
+        drag: 1.5,
+        color: Curve::linear(
+            Vec4::new(0.6, 0.55, 0.45, 0.5),
+            Vec4::new(0.6, 0.55, 0.45, 0.0),
+        ),
+        size: Curve::linear(10.0, 30.0),
+        ..EmitterSettings::new(sparkle_tex)
+    };
+
     let mut all_textureds = vec![];
 
     //start w just the floor and then add keys
@@ -899,11 +1216,11 @@ fn main() -> Result<()> {
     // let mut all_textureds = vec![];
     all_textureds.append(&mut key_textureds);
     all_textureds.append(&mut vec![Textured {
-        trf: Similarity3::new(Vec3::new(0.0, -32.0, 0.0), Rotor3::identity(), 15.0),
+        trf: Similarity3::identity(),
         model: floor,
+        sparkle: None,
     }]);
     all_textureds.push(trophy_texture);
-    all_textureds.push(win_texture);
     // For testing purposes
 
     // let new_flat = Flat {
@@ -921,7 +1238,7 @@ fn main() -> Result<()> {
     // };
     // flats_vec.push(new_flat_2);
 
-    let world = World {
+    let mut world = World {
         camera,
         fp_camera,
         things: vec![],
@@ -935,8 +1252,29 @@ fn main() -> Result<()> {
         sprites: vec![],
         flats: flats_vec,
         textured: all_textureds,
+        show_collision: false,
+        render_mode: RenderMode::Shaded,
+        skies: vec![Sky::default(), marble_sky],
+        sky: 0,
+        hud: Hud {
+            key_icon: key_tex,
+            win: win_tex,
+            font,
+            minimap,
+        },
+        dust: vec![],
+        dust_settings,
     };
 
+    if let Some(path) = screenshot {
+        // a second of game time, so the camera has settled behind the player
+        for _ in 0..60 {
+            engine.step(&mut world);
+        }
+        engine.render_image()?.save_png(std::path::Path::new(&path))?;
+        return Ok(());
+    }
+
     // load and play background music
     let mut audio_manager = AudioManager::new(AudioManagerSettings::default()).unwrap();
 
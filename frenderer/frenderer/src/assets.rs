@@ -1,7 +1,11 @@
 use crate::animation;
 use crate::color_eyre::eyre::{ensure, eyre};
+use crate::font::Font;
 use crate::image::Image;
-use crate::renderer::{flat, skinned, textured};
+use crate::mesh_builder::MeshBuilder;
+use crate::renderer::terrain::{Terrain, TerrainSettings};
+use crate::renderer::{flat, pbr, skinned, textured};
+use crate::sprite_sheet::SpriteSheet;
 use crate::types::*;
 use crate::vulkan::Vulkan;
 use crate::Result;
@@ -11,20 +15,274 @@ use std::rc::Rc;
 use std::sync::Arc;
 use thunderdome::{Arena, Index};
 use vulkano::image::immutable::ImmutableImage;
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::render_pass::Framebuffer;
+use vulkano::sampler::Sampler;
 use vulkano::sync::GpuFuture;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TextureFilter {
+    Nearest,
+    Linear,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TextureWrap {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SamplerSettings {
+    pub filter: TextureFilter,
+    pub wrap: TextureWrap,
+    // generate a full mip chain on upload
+    pub mipmaps: bool,
+    // clamped to what the device supports; ignored if anisotropic filtering isn't available
+    pub anisotropy: Option<f32>,
+}
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            filter: TextureFilter::Linear,
+            wrap: TextureWrap::Repeat,
+            mipmaps: true,
+            anisotropy: Some(16.0),
+        }
+    }
+}
+impl SamplerSettings {
+    // Crisp texels for pixel art sprites: no filtering, no mips
+    pub fn pixel_art() -> Self {
+        Self {
+            filter: TextureFilter::Nearest,
+            wrap: TextureWrap::ClampToEdge,
+            mipmaps: false,
+            anisotropy: None,
+        }
+    }
+    pub(crate) fn create_sampler(&self, vulkan: &Vulkan) -> Result<Arc<Sampler>> {
+        use vulkano::sampler::{
+            Filter, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, LOD_CLAMP_NONE,
+        };
+        let (filter, mipmap_mode) = match self.filter {
+            TextureFilter::Nearest => (Filter::Nearest, SamplerMipmapMode::Nearest),
+            TextureFilter::Linear => (Filter::Linear, SamplerMipmapMode::Linear),
+        };
+        let address_mode = match self.wrap {
+            TextureWrap::Repeat => SamplerAddressMode::Repeat,
+            TextureWrap::MirroredRepeat => SamplerAddressMode::MirroredRepeat,
+            TextureWrap::ClampToEdge => SamplerAddressMode::ClampToEdge,
+        };
+        let anisotropy = if vulkan.device.enabled_features().sampler_anisotropy {
+            let max = vulkan
+                .device
+                .physical_device()
+                .properties()
+                .max_sampler_anisotropy;
+            self.anisotropy.map(|a| a.clamp(1.0, max))
+        } else {
+            None
+        };
+        let sampler = Sampler::new(
+            vulkan.device.clone(),
+            SamplerCreateInfo {
+                mag_filter: filter,
+                min_filter: filter,
+                mipmap_mode,
+                address_mode: [address_mode; 3],
+                anisotropy,
+                lod: 0.0..=if self.mipmaps { LOD_CLAMP_NONE } else { 0.0 },
+                ..Default::default()
+            },
+        )?;
+        Ok(sampler)
+    }
+}
+
 pub struct Texture {
-    pub image: Image,
-    pub texture: Arc<ImmutableImage>,
+    // the pixels as loaded; render targets only live on the GPU
+    pub image: Option<Image>,
+    pub size: (u32, u32),
+    pub view: Arc<dyn ImageViewAbstract>,
+    pub sampler: Arc<Sampler>,
+    pub settings: SamplerSettings,
+}
+impl Texture {
+    // The image behind `view`
+    pub fn texture(&self) -> Arc<dyn vulkano::image::ImageAccess> {
+        self.view.image()
+    }
+}
+// Per-vertex normals, or zeros if neither the file nor assimp provided them
+fn mesh_normals(mesh: &russimp::mesh::Mesh) -> Vec<[f32; 3]> {
+    if mesh.normals.len() == mesh.vertices.len() {
+        mesh.normals.iter().map(|n| [n.x, n.y, n.z]).collect()
+    } else {
+        vec![[0.0; 3]; mesh.vertices.len()]
+    }
+}
+// Bind-pose bounds of a mesh's vertices, for culling
+fn mesh_bounds(mesh: &russimp::mesh::Mesh) -> Bounds {
+    let points: Vec<Vec3> = mesh
+        .vertices
+        .iter()
+        .map(|v| Vec3::new(v.x, v.y, v.z))
+        .collect();
+    Bounds::from_points(&points)
+}
+// Vertex and index buffers for a mesh built in code
+#[allow(clippy::type_complexity)]
+fn upload_built<V: bytemuck::Pod + Send + Sync + 'static>(
+    verts: Vec<V>,
+    builder: &MeshBuilder,
+    vulkan: &mut Vulkan,
+) -> Result<(
+    Arc<vulkano::buffer::ImmutableBuffer<[V]>>,
+    Arc<vulkano::buffer::ImmutableBuffer<[u32]>>,
+)> {
+    ensure!(!builder.is_empty(), "Built mesh has no triangles");
+    let (vb, vb_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
+        verts.into_iter(),
+        vulkano::buffer::BufferUsage::vertex_buffer(),
+        vulkan.queue.clone(),
+    )?;
+    let (ib, ib_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
+        builder.indices().iter().copied(),
+        vulkano::buffer::BufferUsage::index_buffer(),
+        vulkan.queue.clone(),
+    )?;
+    vulkan.wait_for(Box::new(vb_fut.join(ib_fut)));
+    Ok((vb, ib))
+}
+fn built_bounds(builder: &MeshBuilder) -> Bounds {
+    let points: Vec<Vec3> = builder.vertices().iter().map(|v| v.position).collect();
+    Bounds::from_points(&points)
+}
+// A color property of a loaded material, e.g. `$clr.base`; missing alpha is 1
+fn material_color(mat: &russimp::material::Material, key: &str) -> Option<Vec4> {
+    mat.properties
+        .iter()
+        .find(|p| p.key == key)
+        .and_then(|p| match &p.data {
+            russimp::material::PropertyTypeInfo::FloatArray(fs) if fs.len() >= 3 => Some(
+                Vec4::new(fs[0], fs[1], fs[2], fs.get(3).copied().unwrap_or(1.0)),
+            ),
+            _ => None,
+        })
+}
+// Per-vertex colors from the first color channel, or white if there isn't one
+fn mesh_colors(mesh: &russimp::mesh::Mesh) -> Vec<[f32; 4]> {
+    match mesh.colors.first() {
+        Some(Some(cs)) if cs.len() == mesh.vertices.len() => {
+            cs.iter().map(|c| [c.r, c.g, c.b, c.a]).collect()
+        }
+        _ => vec![[1.0; 4]; mesh.vertices.len()],
+    }
+}
+// A float property of a loaded material, from the first of `keys` it has
+fn material_float(mat: &russimp::material::Material, keys: &[&str]) -> Option<f32> {
+    keys.iter().find_map(|key| {
+        mat.properties
+            .iter()
+            .find(|p| p.key == *key)
+            .and_then(|p| match &p.data {
+                russimp::material::PropertyTypeInfo::FloatArray(fs) => fs.first().copied(),
+                _ => None,
+            })
+    })
+}
+// A per-texture float property, e.g. a normal map's `$tex.scale`
+fn texture_float(
+    mat: &russimp::material::Material,
+    key: &str,
+    kinds: &[russimp::material::TextureType],
+) -> Option<f32> {
+    mat.properties
+        .iter()
+        .find(|p| p.key == key && p.index == 0 && kinds.contains(&p.semantic))
+        .and_then(|p| match &p.data {
+            russimp::material::PropertyTypeInfo::FloatArray(fs) => fs.first().copied(),
+            _ => None,
+        })
+}
+// Per-vertex tangents with the bitangent's handedness in w, or zeros if
+// assimp couldn't make them (e.g. the mesh has no texture coordinates)
+fn mesh_tangents(mesh: &russimp::mesh::Mesh) -> Vec<[f32; 4]> {
+    let n = mesh.vertices.len();
+    if mesh.tangents.len() != n || mesh.bitangents.len() != n {
+        return vec![[0.0; 4]; n];
+    }
+    mesh.tangents
+        .iter()
+        .zip(mesh.bitangents.iter())
+        .zip(mesh_normals(mesh))
+        .map(|((t, b), normal)| {
+            let t = Vec3::new(t.x, t.y, t.z);
+            let b = Vec3::new(b.x, b.y, b.z);
+            let w = if Vec3::from(normal).cross(t).dot(b) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            [t.x, t.y, t.z, w]
+        })
+        .collect()
+}
+// The pixels of a texture inside the model file: compressed (e.g. a PNG
+// in a .glb) or raw texels
+fn embedded_image(tex: &russimp::material::Texture) -> Result<Image> {
+    match &tex.data {
+        russimp::material::DataContent::Bytes(bytes) => Image::from_bytes(bytes),
+        russimp::material::DataContent::Texel(texels) => Ok(Image::from_rgba(
+            tex.width,
+            tex.height,
+            texels.iter().flat_map(|t| [t.r, t.g, t.b, t.a]).collect(),
+        )),
+    }
+}
+// A flat material's colors, for its shaders
+fn material_buffer(
+    color: Vec4,
+    emissive: Vec3,
+    vulkan: &mut Vulkan,
+) -> Result<Arc<vulkano::buffer::ImmutableBuffer<flat::MaterialData>>> {
+    let (buffer, fut) = vulkano::buffer::ImmutableBuffer::from_data(
+        flat::MaterialData {
+            color: color.into(),
+            emissive: [emissive.x, emissive.y, emissive.z, 0.0],
+        },
+        vulkano::buffer::BufferUsage::uniform_buffer(),
+        vulkan.queue.clone(),
+    )?;
+    vulkan.wait_for(Box::new(fut));
+    Ok(buffer)
+}
+
+pub struct RenderTarget {
+    // what to draw to show the target's contents
+    pub texture: TextureRef,
+    pub(crate) framebuffer: Arc<Framebuffer>,
 }
 pub struct Assets {
     skinned_meshes: Arena<skinned::Mesh>,
     textured_meshes: Arena<textured::Mesh>,
+    dynamic_textured_meshes: Arena<textured::DynamicMesh>,
     animations: Arena<animation::Animation>,
     textures: Arena<Texture>,
+    // the files `load_texture` has loaded with the default settings
+    textures_by_path: HashMap<std::path::PathBuf, TextureRef>,
+    // made the first time a model's material has no diffuse texture
+    white_texture: Option<TextureRef>,
     materials: Arena<flat::Material>,
     materials_by_name: HashMap<String, MaterialRef<flat::Material>>,
+    pbr_meshes: Arena<pbr::Mesh>,
+    pbr_materials: Arena<pbr::Material>,
     flat_meshes: Arena<flat::Mesh>,
+    dynamic_flat_meshes: Arena<flat::DynamicMesh>,
+    fonts: Arena<Font>,
+    sprite_sheets: Arena<SpriteSheet>,
+    render_targets: Arena<RenderTarget>,
+    terrains: Arena<Terrain>,
 }
 impl Assets {
     #[allow(clippy::new_without_default)]
@@ -32,19 +290,83 @@ impl Assets {
         Self {
             skinned_meshes: Arena::new(),
             textured_meshes: Arena::new(),
+            dynamic_textured_meshes: Arena::new(),
             animations: Arena::new(),
             textures: Arena::new(),
+            textures_by_path: HashMap::new(),
+            white_texture: None,
             flat_meshes: Arena::new(),
+            dynamic_flat_meshes: Arena::new(),
             materials: Arena::new(),
             materials_by_name: HashMap::new(),
+            pbr_meshes: Arena::new(),
+            pbr_materials: Arena::new(),
+            fonts: Arena::new(),
+            sprite_sheets: Arena::new(),
+            render_targets: Arena::new(),
+            terrains: Arena::new(),
         }
     }
+    // Loading the same file again returns the texture loaded the first time
     pub fn load_texture(
         &mut self,
         path: &std::path::Path,
         vulkan: &mut Vulkan,
+    ) -> Result<TextureRef> {
+        if let Some(t) = self.textures_by_path.get(path) {
+            return Ok(*t);
+        }
+        let tex = self.load_texture_with(path, SamplerSettings::default(), vulkan)?;
+        self.textures_by_path.insert(path.to_path_buf(), tex);
+        Ok(tex)
+    }
+    pub fn load_texture_with(
+        &mut self,
+        path: &std::path::Path,
+        settings: SamplerSettings,
+        vulkan: &mut Vulkan,
     ) -> Result<TextureRef> {
         let img = Image::from_file(path)?;
+        self.create_texture(img, settings, vulkan)
+    }
+    fn create_texture(
+        &mut self,
+        img: Image,
+        settings: SamplerSettings,
+        vulkan: &mut Vulkan,
+    ) -> Result<TextureRef> {
+        self.create_texture_in(
+            img,
+            settings,
+            vulkano::format::Format::R8G8B8A8_SRGB,
+            vulkan,
+        )
+    }
+    // A 1x1 white texture, so untextured materials show their meshes as is
+    fn white_texture(&mut self, vulkan: &mut Vulkan) -> Result<TextureRef> {
+        if let Some(t) = self.white_texture {
+            return Ok(t);
+        }
+        let img = Image::from_colors(1, 1, vec![Color(255, 255, 255, 255)]);
+        let tex = self.create_texture(img, SamplerSettings::default(), vulkan)?;
+        self.white_texture = Some(tex);
+        Ok(tex)
+    }
+    // Data like normal maps mustn't be treated as sRGB colors
+    fn create_texture_in(
+        &mut self,
+        img: Image,
+        settings: SamplerSettings,
+        format: vulkano::format::Format,
+        vulkan: &mut Vulkan,
+    ) -> Result<TextureRef> {
+        // With more than one mip level, vulkano fills in the rest of the
+        // chain with linear blits after copying in level 0.
+        let mips = if settings.mipmaps {
+            vulkano::image::MipmapsCount::Log2
+        } else {
+            vulkano::image::MipmapsCount::One
+        };
         let (vulk_img, fut) = ImmutableImage::from_iter(
             img.as_slice().iter().copied(),
             vulkano::image::ImageDimensions::Dim2d {
@@ -52,14 +374,225 @@ impl Assets {
                 height: img.sz.y,
                 array_layers: 1,
             },
-            vulkano::image::MipmapsCount::One,
-            vulkano::format::Format::R8G8B8A8_SRGB,
+            mips,
+            format,
             vulkan.queue.clone(),
         )?;
         vulkan.wait_for(Box::new(fut));
+        let sampler = settings.create_sampler(vulkan)?;
+        let tid = self.textures.insert(Texture {
+            size: (img.sz.x, img.sz.y),
+            image: Some(img),
+            view: ImageView::new_default(vulk_img)?,
+            sampler,
+            settings,
+        });
+        Ok(TextureRef(tid))
+    }
+    // Rasterizes the glyphs of a TrueType/OpenType font at `px_size` pixels high
+    pub fn load_font(
+        &mut self,
+        path: &std::path::Path,
+        px_size: f32,
+        vulkan: &mut Vulkan,
+    ) -> Result<FontRef> {
+        let data = std::fs::read(path)?;
+        let settings = SamplerSettings {
+            wrap: TextureWrap::ClampToEdge,
+            mipmaps: false,
+            ..SamplerSettings::default()
+        };
+        let font = Font::from_ttf(&data, px_size, |img| {
+            self.create_texture(img, settings, vulkan)
+        })?;
+        Ok(FontRef(self.fonts.insert(font)))
+    }
+    // A monospaced font drawn as a grid of `cell_w` by `cell_h` glyphs, in
+    // character order starting from `first`
+    pub fn load_bitmap_font(
+        &mut self,
+        path: &std::path::Path,
+        first: char,
+        cell_w: u32,
+        cell_h: u32,
+        vulkan: &mut Vulkan,
+    ) -> Result<FontRef> {
+        let img = Image::from_file(path)?;
+        let font = Font::from_grid(img, first, (cell_w, cell_h), |img| {
+            self.create_texture(img, SamplerSettings::pixel_art(), vulkan)
+        })?;
+        Ok(FontRef(self.fonts.insert(font)))
+    }
+    // Aseprite-style JSON next to the image it names in `meta.image`
+    pub fn load_sprite_sheet(
+        &mut self,
+        path: &std::path::Path,
+        settings: SamplerSettings,
+        vulkan: &mut Vulkan,
+    ) -> Result<SpriteSheetRef> {
+        let json: serde_json::Value = serde_json::from_reader(std::fs::File::open(path)?)?;
+        let image = json["meta"]["image"]
+            .as_str()
+            .ok_or_else(|| eyre!("Sprite sheet {:?} doesn't name its image", path))?;
+        let image_path = path
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new(""))
+            .join(image);
+        let texture = self.load_texture_with(&image_path, settings, vulkan)?;
+        let sheet = SpriteSheet::from_json(texture, &json)?;
+        Ok(SpriteSheetRef(self.sprite_sheets.insert(sheet)))
+    }
+    // Cuts `texture` into `cell_w` by `cell_h` frames numbered in reading
+    // order; each sequence is a name, its frame numbers, and seconds per frame
+    pub fn create_sprite_grid(
+        &mut self,
+        texture: TextureRef,
+        cell_w: u32,
+        cell_h: u32,
+        sequences: &[(&str, &[u32], f32)],
+    ) -> Result<SpriteSheetRef> {
+        let sz = self.texture(texture).size;
+        let sheet = SpriteSheet::from_grid(texture, sz, (cell_w, cell_h), sequences)?;
+        Ok(SpriteSheetRef(self.sprite_sheets.insert(sheet)))
+    }
+    // An offscreen image to draw a separate scene into; its texture can be
+    // shown by any renderer
+    pub fn create_render_target(
+        &mut self,
+        width: u32,
+        height: u32,
+        vulkan: &mut Vulkan,
+    ) -> Result<RenderTargetRef> {
+        ensure!(
+            width > 0 && height > 0,
+            "Render target must not be empty: {}x{}",
+            width,
+            height
+        );
+        let (view, framebuffer) = vulkan.create_render_target([width, height]);
+        // redrawn every frame, so there's no mip chain to keep up to date
+        let settings = SamplerSettings {
+            wrap: TextureWrap::ClampToEdge,
+            mipmaps: false,
+            ..SamplerSettings::default()
+        };
+        let sampler = settings.create_sampler(vulkan)?;
+        let texture = TextureRef(self.textures.insert(Texture {
+            image: None,
+            size: (width, height),
+            view,
+            sampler,
+            settings,
+        }));
+        let rtid = self.render_targets.insert(RenderTarget {
+            texture,
+            framebuffer,
+        });
+        Ok(RenderTargetRef(rtid))
+    }
+    // Six square faces in +X, -X, +Y, -Y, +Z, -Z order, made into a cube
+    // image for sampling with a samplerCube
+    pub fn load_cubemap(
+        &mut self,
+        paths: [&std::path::Path; 6],
+        vulkan: &mut Vulkan,
+    ) -> Result<TextureRef> {
+        use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+        use vulkano::command_buffer::{
+            AutoCommandBufferBuilder, CommandBufferUsage, PrimaryCommandBuffer,
+        };
+        use vulkano::image::view::{ImageViewCreateInfo, ImageViewType};
+        use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageLayout, ImageUsage};
+        let faces = paths
+            .iter()
+            .map(|p| Image::from_file(p))
+            .collect::<Result<Vec<_>>>()?;
+        let size = faces[0].sz.x;
+        ensure!(
+            faces.iter().all(|f| f.sz.x == size && f.sz.y == size),
+            "Cubemap faces must all be the same size and square: {:?}",
+            paths
+        );
+        // the faces as they were loaded, stacked top to bottom
+        let img = Image::from_colors(
+            size,
+            size * 6,
+            faces
+                .iter()
+                .flat_map(|f| f.as_slice().iter().copied())
+                .collect(),
+        );
+        let settings = SamplerSettings {
+            wrap: TextureWrap::ClampToEdge,
+            ..SamplerSettings::default()
+        };
+        let (vulk_img, init) = ImmutableImage::uninitialized(
+            vulkan.device.clone(),
+            ImageDimensions::Dim2d {
+                width: size,
+                height: size,
+                array_layers: 6,
+            },
+            vulkano::format::Format::R8G8B8A8_SRGB,
+            vulkano::image::MipmapsCount::Log2,
+            ImageUsage {
+                transfer_destination: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+            ImageCreateFlags {
+                cube_compatible: true,
+                ..ImageCreateFlags::none()
+            },
+            ImageLayout::ShaderReadOnlyOptimal,
+            vulkan.device.active_queue_families(),
+        )?;
+        // vulkano only blits mip chains for the images it creates itself, so
+        // each level is halved here and copied in with all six faces at once
+        let mut builder = AutoCommandBufferBuilder::primary(
+            vulkan.device.clone(),
+            vulkan.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        let mut level_faces = faces;
+        for level in 0..vulk_img.mip_levels() {
+            let level_size = level_faces[0].sz.x;
+            let buf = CpuAccessibleBuffer::from_iter(
+                vulkan.device.clone(),
+                BufferUsage::transfer_source(),
+                false,
+                level_faces
+                    .iter()
+                    .flat_map(|f| f.as_slice().iter().copied())
+                    .collect::<Vec<_>>(),
+            )?;
+            builder.copy_buffer_to_image_dimensions(
+                buf,
+                init.clone(),
+                [0, 0, 0],
+                [level_size, level_size, 1],
+                0,
+                6,
+                level,
+            )?;
+            level_faces = level_faces.iter().map(|f| f.half_size()).collect();
+        }
+        let fut = builder.build()?.execute(vulkan.queue.clone())?;
+        vulkan.wait_for(Box::new(fut));
+        let sampler = settings.create_sampler(vulkan)?;
+        let view = ImageView::new(
+            vulk_img.clone(),
+            ImageViewCreateInfo {
+                view_type: ImageViewType::Cube,
+                ..ImageViewCreateInfo::from_image(&vulk_img)
+            },
+        )?;
         let tid = self.textures.insert(Texture {
-            image: img,
-            texture: vulk_img,
+            size: (size, size),
+            image: Some(img),
+            view,
+            sampler,
+            settings,
         });
         Ok(TextureRef(tid))
     }
@@ -69,6 +602,33 @@ impl Assets {
         node_root: &[&str],
         vulkan: &mut Vulkan,
     ) -> Result<Vec<MeshRef<skinned::Mesh>>> {
+        Ok(self.import_skinned(path, node_root, vulkan)?.0)
+    }
+    // Skinned meshes ready to draw, each with its material's diffuse texture
+    pub fn load_skinned_model(
+        &mut self,
+        path: &std::path::Path,
+        node_root: &[&str],
+        vulkan: &mut Vulkan,
+    ) -> Result<Rc<skinned::Model>> {
+        let (meshes, materials) = self.import_skinned(path, node_root, vulkan)?;
+        let material_indices: Vec<u32> = meshes
+            .iter()
+            .map(|m| self.skinned_meshes[m.0].mesh.material_index)
+            .collect();
+        let textures = self.diffuse_textures(path, &materials, &material_indices, vulkan)?;
+        Ok(Rc::new(skinned::Model::new(meshes, textures)))
+    }
+    // The meshes, and the scene's materials they index into
+    fn import_skinned(
+        &mut self,
+        path: &std::path::Path,
+        node_root: &[&str],
+        vulkan: &mut Vulkan,
+    ) -> Result<(
+        Vec<MeshRef<skinned::Mesh>>,
+        Vec<russimp::material::Material>,
+    )> {
         use russimp::scene::{PostProcess, Scene};
         let scene = Scene::from_file(
             path.to_str()
@@ -77,6 +637,7 @@ impl Assets {
                 PostProcess::GenerateUVCoords,
                 PostProcess::Triangulate,
                 PostProcess::JoinIdenticalVertices,
+                PostProcess::GenerateSmoothNormals,
                 PostProcess::FlipUVs,
                 PostProcess::LimitBoneWeights,
             ],
@@ -138,9 +699,11 @@ impl Assets {
                         .zip(uvs.into_iter())
                         .zip(bone_weights.iter())
                         .zip(bone_usage.iter())
-                        .map(|(((pos, uv), weights), usage)| skinned::Vertex {
+                        .zip(mesh_normals(&mesh))
+                        .map(|((((pos, uv), weights), usage), normal)| skinned::Vertex {
                             position: [pos.x, pos.y, pos.z],
                             uv: [uv.x, uv.y],
+                            normal,
                             bone_weights: {
                                 let w: f32 = weights.iter().sum();
                                 [
@@ -167,22 +730,54 @@ impl Assets {
                 let load_fut = vb_fut.join(ib_fut);
                 vulkan.wait_for(Box::new(load_fut));
 
+                let bounds = mesh_bounds(&mesh);
                 let mid = self.skinned_meshes.insert(skinned::Mesh {
                     mesh,
                     rig,
                     verts: vb,
                     idx: ib,
+                    bounds,
                 });
                 Ok(MeshRef(mid, PhantomData))
             })
             .collect();
-        meshes
+        Ok((meshes?, scene.materials))
     }
     pub fn load_textured(
         &mut self,
         path: &std::path::Path,
         vulkan: &mut Vulkan,
     ) -> Result<Vec<MeshRef<textured::Mesh>>> {
+        Ok(self.import_textured(path, vulkan)?.0)
+    }
+    // Meshes ready to draw, each with its material's diffuse texture
+    pub fn load_textured_model(
+        &mut self,
+        path: &std::path::Path,
+        vulkan: &mut Vulkan,
+    ) -> Result<Rc<textured::Model>> {
+        let (meshes, materials) = self.import_textured(path, vulkan)?;
+        let material_indices: Vec<u32> = meshes
+            .iter()
+            .map(|m| {
+                self.textured_meshes[m.0]
+                    .mesh
+                    .as_ref()
+                    .map(|mesh| mesh.material_index)
+                    .unwrap_or(0)
+            })
+            .collect();
+        let textures = self.diffuse_textures(path, &materials, &material_indices, vulkan)?;
+        Ok(Rc::new(textured::Model::new(meshes, textures)))
+    }
+    fn import_textured(
+        &mut self,
+        path: &std::path::Path,
+        vulkan: &mut Vulkan,
+    ) -> Result<(
+        Vec<MeshRef<textured::Mesh>>,
+        Vec<russimp::material::Material>,
+    )> {
         use russimp::scene::{PostProcess, Scene};
         let scene = Scene::from_file(
             path.to_str()
@@ -191,6 +786,7 @@ impl Assets {
                 PostProcess::GenerateUVCoords,
                 PostProcess::Triangulate,
                 PostProcess::JoinIdenticalVertices,
+                PostProcess::GenerateSmoothNormals,
                 PostProcess::FlipUVs,
             ],
         )?;
@@ -226,12 +822,15 @@ impl Assets {
                     .flat_map(|v| v.0.iter().copied())
                     .collect();
                 let (vb, vb_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    verts.iter().zip(uvs.into_iter()).map(|(pos, uv)| {
-                        crate::renderer::textured::Vertex {
+                    verts
+                        .iter()
+                        .zip(uvs.into_iter())
+                        .zip(mesh_normals(&mesh))
+                        .map(|((pos, uv), normal)| crate::renderer::textured::Vertex {
                             position: [pos.x, pos.y, pos.z],
                             uv: [uv.x, uv.y],
-                        }
-                    }),
+                            normal,
+                        }),
                     vulkano::buffer::BufferUsage::vertex_buffer(),
                     vulkan.queue.clone(),
                 )?;
@@ -244,17 +843,19 @@ impl Assets {
                 let load_fut = vb_fut.join(ib_fut);
                 vulkan.wait_for(Box::new(load_fut));
 
+                let bounds = mesh_bounds(&mesh);
                 let mid = self
                     .textured_meshes
                     .insert(crate::renderer::textured::Mesh {
-                        mesh,
+                        mesh: Some(mesh),
                         verts: vb,
                         idx: ib,
+                        bounds,
                     });
                 Ok(MeshRef(mid, PhantomData))
             })
             .collect();
-        meshes
+        Ok((meshes?, scene.materials))
     }
     pub fn load_anim(
         &mut self,
@@ -295,6 +896,7 @@ impl Assets {
             vec![
                 PostProcess::Triangulate,
                 PostProcess::JoinIdenticalVertices,
+                PostProcess::GenerateSmoothNormals,
                 PostProcess::LimitBoneWeights,
             ],
         )?;
@@ -302,18 +904,10 @@ impl Assets {
             .materials
             .into_iter()
             .map(|mat| {
-                let color = mat
-                    .properties
-                    .iter()
-                    .find(|p| p.key == "$clr.base")
-                    .and_then(|p| {
-                        if let russimp::material::PropertyTypeInfo::FloatArray(fs) = &p.data {
-                            Some(Vec4::new(fs[0], fs[1], fs[2], fs[3]))
-                        } else {
-                            None
-                        }
-                    })
-                    .unwrap_or(Vec4::new(1., 1., 1., 1.));
+                let color = material_color(&mat, "$clr.base").unwrap_or(Vec4::new(1., 1., 1., 1.));
+                let emissive = material_color(&mat, "$clr.emissive")
+                    .map(|c| c.xyz())
+                    .unwrap_or_else(Vec3::zero);
                 let name = mat
                     .properties
                     .iter()
@@ -336,16 +930,10 @@ impl Assets {
                         *e.get()
                     }
                     std::collections::hash_map::Entry::Vacant(e) => {
-                        let (buffer, fut) = vulkano::buffer::ImmutableBuffer::from_data(
-                            color.into(),
-                            vulkano::buffer::BufferUsage::uniform_buffer(),
-                            vulkan.queue.clone(),
-                        )
-                        .unwrap();
-                        vulkan.wait_for(Box::new(fut));
+                        let buffer = material_buffer(color, emissive, vulkan).unwrap();
                         let mat_ref = self
                             .materials
-                            .insert(flat::Material::new(color, name, buffer));
+                            .insert(flat::Material::new(color, emissive, name, buffer));
                         let mat_ref = MaterialRef(mat_ref, PhantomData);
                         e.insert(mat_ref);
                         mat_ref
@@ -371,9 +959,15 @@ impl Assets {
                     .flat_map(|v| v.0.iter().copied())
                     .collect();
                 let (vb, vb_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    verts.iter().map(|pos| flat::Vertex {
-                        position: [pos.x, pos.y, pos.z],
-                    }),
+                    verts
+                        .iter()
+                        .zip(mesh_normals(&mesh))
+                        .zip(mesh_colors(&mesh))
+                        .map(|((pos, normal), color)| flat::Vertex {
+                            position: [pos.x, pos.y, pos.z],
+                            normal,
+                            color,
+                        }),
                     vulkano::buffer::BufferUsage::vertex_buffer(),
                     vulkan.queue.clone(),
                 )?;
@@ -387,10 +981,12 @@ impl Assets {
                 vulkan.wait_for(Box::new(load_fut));
 
                 let mat = mats[mesh.material_index as usize];
+                let bounds = mesh_bounds(&mesh);
                 let mid = self.flat_meshes.insert(flat::Mesh {
-                    mesh,
+                    mesh: Some(mesh),
                     verts: vb,
                     idx: ib,
+                    bounds,
                 });
                 Ok((MeshRef(mid, PhantomData), mat))
             })
@@ -401,6 +997,406 @@ impl Assets {
             meshes.iter().map(|(_, m)| m).copied().collect(),
         )))
     }
+    // Meshes with glTF metallic-roughness materials.  Textures come from
+    // inside a .glb, or from files next to the model.
+    pub fn load_pbr(
+        &mut self,
+        path: &std::path::Path,
+        vulkan: &mut Vulkan,
+    ) -> Result<Rc<pbr::Model>> {
+        use russimp::scene::{PostProcess, Scene};
+        let scene = Scene::from_file(
+            path.to_str()
+                .ok_or_else(|| eyre!("Mesh path can't be converted to string: {:?}", path))?,
+            vec![
+                PostProcess::GenerateUVCoords,
+                PostProcess::Triangulate,
+                PostProcess::JoinIdenticalVertices,
+                PostProcess::GenerateSmoothNormals,
+                PostProcess::CalculateTangentSpace,
+                PostProcess::FlipUVs,
+            ],
+        )?;
+        let dir = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+        // textures shared by several of the file's materials get loaded once
+        let mut loaded = HashMap::new();
+        let mats = scene
+            .materials
+            .iter()
+            .map(|mat| self.load_pbr_material(mat, dir, &mut loaded, vulkan))
+            .collect::<Result<Vec<_>>>()?;
+        let mut meshes = vec![];
+        let mut materials = vec![];
+        for mesh in scene.meshes.into_iter() {
+            let verts = &mesh.vertices;
+            ensure!(
+                mesh.faces[0].0.len() == 3,
+                "Mesh face has too many indices: {:?}",
+                mesh.faces[0]
+            );
+            let uvs = match mesh.texture_coords.first() {
+                Some(Some(uvs)) => uvs.iter().map(|uv| [uv.x, uv.y]).collect(),
+                _ => vec![[0.0, 0.0]; verts.len()],
+            };
+            // This is safe to allow because we need an ExactSizeIterator of faces
+            #[allow(clippy::needless_collect)]
+            let faces: Vec<u32> = mesh
+                .faces
+                .iter()
+                .flat_map(|v| v.0.iter().copied())
+                .collect();
+            let (vb, vb_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
+                verts
+                    .iter()
+                    .zip(uvs.into_iter())
+                    .zip(mesh_normals(&mesh))
+                    .zip(mesh_tangents(&mesh))
+                    .map(|(((pos, uv), normal), tangent)| pbr::Vertex {
+                        position: [pos.x, pos.y, pos.z],
+                        uv,
+                        normal,
+                        tangent,
+                    }),
+                vulkano::buffer::BufferUsage::vertex_buffer(),
+                vulkan.queue.clone(),
+            )?;
+            let (ib, ib_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
+                faces.into_iter(),
+                vulkano::buffer::BufferUsage::index_buffer(),
+                vulkan.queue.clone(),
+            )?;
+            vulkan.wait_for(Box::new(vb_fut.join(ib_fut)));
+
+            materials.push(mats[mesh.material_index as usize]);
+            let bounds = mesh_bounds(&mesh);
+            let mid = self.pbr_meshes.insert(pbr::Mesh {
+                mesh: Some(mesh),
+                verts: vb,
+                idx: ib,
+                bounds,
+            });
+            meshes.push(MeshRef(mid, PhantomData));
+        }
+        Ok(Rc::new(pbr::Model::new(meshes, materials)))
+    }
+    fn load_pbr_material(
+        &mut self,
+        mat: &russimp::material::Material,
+        dir: &std::path::Path,
+        loaded: &mut HashMap<(String, bool), TextureRef>,
+        vulkan: &mut Vulkan,
+    ) -> Result<MaterialRef<pbr::Material>> {
+        use russimp::material::TextureType;
+        let name = mat
+            .properties
+            .iter()
+            .find(|p| p.key == "?mat.name")
+            .and_then(|p| match &p.data {
+                russimp::material::PropertyTypeInfo::String(n) => Some(n.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| "BLANK".to_string());
+        // assimp's glTF importer has used both the generic and the PBR texture slots
+        let base_color_texture = self.material_texture(
+            mat,
+            &[TextureType::BaseColor, TextureType::Diffuse],
+            true,
+            dir,
+            loaded,
+            vulkan,
+        )?;
+        let metallic_roughness_texture = self.material_texture(
+            mat,
+            &[
+                TextureType::Unknown,
+                TextureType::Metalness,
+                TextureType::Roughness,
+            ],
+            false,
+            dir,
+            loaded,
+            vulkan,
+        )?;
+        let normal_kinds = [TextureType::Normals, TextureType::NormalCamera];
+        let normal_texture =
+            self.material_texture(mat, &normal_kinds, false, dir, loaded, vulkan)?;
+        let occlusion_kinds = [TextureType::LightMap, TextureType::AmbientOcclusion];
+        let occlusion_texture =
+            self.material_texture(mat, &occlusion_kinds, false, dir, loaded, vulkan)?;
+        let emissive_texture = self.material_texture(
+            mat,
+            &[TextureType::Emissive, TextureType::EmissionColor],
+            true,
+            dir,
+            loaded,
+            vulkan,
+        )?;
+        let base_color = material_color(mat, "$clr.base")
+            .or_else(|| material_color(mat, "$clr.diffuse"))
+            .unwrap_or(Vec4::new(1., 1., 1., 1.));
+        // files without PBR factors get a rough dielectric
+        let metallic = material_float(
+            mat,
+            &[
+                "$mat.metallicFactor",
+                "$mat.gltf.pbrMetallicRoughness.metallicFactor",
+            ],
+        )
+        .unwrap_or(0.0);
+        let roughness = material_float(
+            mat,
+            &[
+                "$mat.roughnessFactor",
+                "$mat.gltf.pbrMetallicRoughness.roughnessFactor",
+            ],
+        )
+        .unwrap_or(1.0);
+        // an emissive map without a factor should still glow
+        let emissive = material_color(mat, "$clr.emissive")
+            .map(|c| c.xyz())
+            .unwrap_or_else(|| {
+                if emissive_texture.is_some() {
+                    Vec3::one()
+                } else {
+                    Vec3::zero()
+                }
+            });
+        let normal_scale = texture_float(mat, "$tex.scale", &normal_kinds).unwrap_or(1.0);
+        let occlusion_strength =
+            texture_float(mat, "$tex.strength", &occlusion_kinds).unwrap_or(1.0);
+        let (buffer, fut) = vulkano::buffer::ImmutableBuffer::from_data(
+            pbr::MaterialData {
+                base_color: base_color.into(),
+                emissive: [emissive.x, emissive.y, emissive.z, 0.0],
+                factors: [metallic, roughness, normal_scale, occlusion_strength],
+            },
+            vulkano::buffer::BufferUsage::uniform_buffer(),
+            vulkan.queue.clone(),
+        )?;
+        vulkan.wait_for(Box::new(fut));
+        let mid = self.pbr_materials.insert(pbr::Material {
+            name,
+            base_color,
+            metallic,
+            roughness,
+            emissive,
+            normal_scale,
+            occlusion_strength,
+            base_color_texture,
+            metallic_roughness_texture,
+            normal_texture,
+            occlusion_texture,
+            emissive_texture,
+            buffer,
+        });
+        Ok(MaterialRef(mid, PhantomData))
+    }
+    // Each mesh's diffuse texture, by the index of its material, or plain
+    // white for materials without one
+    fn diffuse_textures(
+        &mut self,
+        path: &std::path::Path,
+        materials: &[russimp::material::Material],
+        material_indices: &[u32],
+        vulkan: &mut Vulkan,
+    ) -> Result<Vec<TextureRef>> {
+        use russimp::material::TextureType;
+        let dir = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+        let mut loaded = HashMap::new();
+        material_indices
+            .iter()
+            .map(|i| {
+                let mat = materials
+                    .get(*i as usize)
+                    .ok_or_else(|| eyre!("Mesh material {} missing from {:?}", i, path))?;
+                match self.material_texture(
+                    mat,
+                    &[TextureType::Diffuse, TextureType::BaseColor],
+                    true,
+                    dir,
+                    &mut loaded,
+                    vulkan,
+                )? {
+                    Some(tex) => Ok(tex),
+                    None => self.white_texture(vulkan),
+                }
+            })
+            .collect()
+    }
+    // The material's texture of the first of `kinds` it has, embedded in the
+    // model file or beside it
+    fn material_texture(
+        &mut self,
+        mat: &russimp::material::Material,
+        kinds: &[russimp::material::TextureType],
+        srgb: bool,
+        dir: &std::path::Path,
+        loaded: &mut HashMap<(String, bool), TextureRef>,
+        vulkan: &mut Vulkan,
+    ) -> Result<Option<TextureRef>> {
+        for kind in kinds {
+            let file = mat
+                .properties
+                .iter()
+                .find(|p| p.key == "$tex.file" && p.index == 0 && p.semantic == *kind)
+                .and_then(|p| match &p.data {
+                    russimp::material::PropertyTypeInfo::String(f) => Some(f.clone()),
+                    _ => None,
+                });
+            let embedded = mat.textures.get(kind);
+            let name = match (file, embedded) {
+                (Some(f), _) => f,
+                (None, Some(t)) => t.borrow().filename.clone(),
+                (None, None) => continue,
+            };
+            if let Some(t) = loaded.get(&(name.clone(), srgb)) {
+                return Ok(Some(*t));
+            }
+            let img = match embedded {
+                Some(t) => embedded_image(&t.borrow())?,
+                None => {
+                    // names like *0 refer to textures inside the file
+                    ensure!(
+                        !name.starts_with('*'),
+                        "Embedded texture {} is missing from its model",
+                        name
+                    );
+                    // exporters sometimes write the author's absolute path,
+                    // so fall back to the bare file name beside the model
+                    let mut file = dir.join(&name);
+                    if !file.exists() {
+                        if let Some(base) = std::path::Path::new(&name).file_name() {
+                            file = dir.join(base);
+                        }
+                    }
+                    if srgb {
+                        // shared with every other model and caller using this file
+                        let tex = self.load_texture(&file, vulkan)?;
+                        loaded.insert((name, srgb), tex);
+                        return Ok(Some(tex));
+                    }
+                    Image::from_file(&file)?
+                }
+            };
+            let format = if srgb {
+                vulkano::format::Format::R8G8B8A8_SRGB
+            } else {
+                vulkano::format::Format::R8G8B8A8_UNORM
+            };
+            let tex = self.create_texture_in(img, SamplerSettings::default(), format, vulkan)?;
+            loaded.insert((name, srgb), tex);
+            return Ok(Some(tex));
+        }
+        Ok(None)
+    }
+    // Meshes built in code, for models made with `Engine::create_textured_model`
+    // or `create_flat_model`
+    pub fn create_textured_mesh(
+        &mut self,
+        builder: &MeshBuilder,
+        vulkan: &mut Vulkan,
+    ) -> Result<MeshRef<textured::Mesh>> {
+        let (verts, idx) = upload_built(builder.textured_vertices(), builder, vulkan)?;
+        let mid = self.textured_meshes.insert(textured::Mesh {
+            mesh: None,
+            verts,
+            idx,
+            bounds: built_bounds(builder),
+        });
+        Ok(MeshRef(mid, PhantomData))
+    }
+    pub fn create_flat_mesh(
+        &mut self,
+        builder: &MeshBuilder,
+        vulkan: &mut Vulkan,
+    ) -> Result<MeshRef<flat::Mesh>> {
+        let (verts, idx) = upload_built(builder.flat_vertices(), builder, vulkan)?;
+        let mid = self.flat_meshes.insert(flat::Mesh {
+            mesh: None,
+            verts,
+            idx,
+            bounds: built_bounds(builder),
+        });
+        Ok(MeshRef(mid, PhantomData))
+    }
+    // A material for flat models that aren't loaded from files, e.g. dynamic ones
+    pub fn create_material(
+        &mut self,
+        name: &str,
+        color: Vec4,
+        vulkan: &mut Vulkan,
+    ) -> Result<MaterialRef<flat::Material>> {
+        self.create_emissive_material(name, color, Vec3::zero(), vulkan)
+    }
+    // One that glows with `emissive` on top of its shaded color
+    pub fn create_emissive_material(
+        &mut self,
+        name: &str,
+        color: Vec4,
+        emissive: Vec3,
+        vulkan: &mut Vulkan,
+    ) -> Result<MaterialRef<flat::Material>> {
+        ensure!(
+            !self.materials_by_name.contains_key(name),
+            "Material {:?} already exists",
+            name
+        );
+        let buffer = material_buffer(color, emissive, vulkan)?;
+        let mat_ref = MaterialRef(
+            self.materials.insert(flat::Material::new(
+                color,
+                emissive,
+                name.to_string(),
+                buffer,
+            )),
+            PhantomData,
+        );
+        self.materials_by_name.insert(name.to_string(), mat_ref);
+        Ok(mat_ref)
+    }
+    // Empty meshes to fill in and change later with `DynamicMesh::set`
+    pub fn create_dynamic_textured_mesh(&mut self) -> MeshRef<textured::DynamicMesh> {
+        MeshRef(
+            self.dynamic_textured_meshes
+                .insert(textured::DynamicMesh::new()),
+            PhantomData,
+        )
+    }
+    pub fn create_dynamic_flat_mesh(&mut self) -> MeshRef<flat::DynamicMesh> {
+        MeshRef(
+            self.dynamic_flat_meshes.insert(flat::DynamicMesh::new()),
+            PhantomData,
+        )
+    }
+    // A terrain's splat map, kept linear and unpremultiplied so its weights
+    // sample back as painted
+    pub fn load_splat_map(
+        &mut self,
+        path: &std::path::Path,
+        vulkan: &mut Vulkan,
+    ) -> Result<TextureRef> {
+        let img = Image::from_file_straight(path)?;
+        let settings = SamplerSettings {
+            wrap: TextureWrap::ClampToEdge,
+            ..SamplerSettings::default()
+        };
+        self.create_texture_in(
+            img,
+            settings,
+            vulkano::format::Format::R8G8B8A8_UNORM,
+            vulkan,
+        )
+    }
+    // Heights come from the heightmap's red channel, one sample per pixel
+    pub fn create_terrain(
+        &mut self,
+        heightmap: &Image,
+        settings: TerrainSettings,
+        vulkan: &mut Vulkan,
+    ) -> Result<TerrainRef> {
+        let terrain = Terrain::new(heightmap, settings, vulkan)?;
+        Ok(TerrainRef(self.terrains.insert(terrain)))
+    }
     pub fn skinned_mesh(&self, m: MeshRef<skinned::Mesh>) -> &skinned::Mesh {
         &self.skinned_meshes[m.0]
     }
@@ -410,15 +1406,54 @@ impl Assets {
     pub fn flat_mesh(&self, m: MeshRef<flat::Mesh>) -> &flat::Mesh {
         &self.flat_meshes[m.0]
     }
+    pub fn dynamic_textured_mesh(
+        &self,
+        m: MeshRef<textured::DynamicMesh>,
+    ) -> &textured::DynamicMesh {
+        &self.dynamic_textured_meshes[m.0]
+    }
+    pub fn dynamic_textured_mesh_mut(
+        &mut self,
+        m: MeshRef<textured::DynamicMesh>,
+    ) -> &mut textured::DynamicMesh {
+        &mut self.dynamic_textured_meshes[m.0]
+    }
+    pub fn dynamic_flat_mesh(&self, m: MeshRef<flat::DynamicMesh>) -> &flat::DynamicMesh {
+        &self.dynamic_flat_meshes[m.0]
+    }
+    pub fn dynamic_flat_mesh_mut(
+        &mut self,
+        m: MeshRef<flat::DynamicMesh>,
+    ) -> &mut flat::DynamicMesh {
+        &mut self.dynamic_flat_meshes[m.0]
+    }
     pub fn material(&self, m: MaterialRef<flat::Material>) -> &flat::Material {
         &self.materials[m.0]
     }
+    pub fn pbr_mesh(&self, m: MeshRef<pbr::Mesh>) -> &pbr::Mesh {
+        &self.pbr_meshes[m.0]
+    }
+    pub fn pbr_material(&self, m: MaterialRef<pbr::Material>) -> &pbr::Material {
+        &self.pbr_materials[m.0]
+    }
     pub fn texture(&self, m: TextureRef) -> &Texture {
         &self.textures[m.0]
     }
     pub fn animation(&self, m: AnimRef) -> &animation::Animation {
         &self.animations[m.0]
     }
+    pub fn font(&self, m: FontRef) -> &Font {
+        &self.fonts[m.0]
+    }
+    pub fn sprite_sheet(&self, m: SpriteSheetRef) -> &SpriteSheet {
+        &self.sprite_sheets[m.0]
+    }
+    pub fn render_target(&self, m: RenderTargetRef) -> &RenderTarget {
+        &self.render_targets[m.0]
+    }
+    pub fn terrain(&self, m: TerrainRef) -> &Terrain {
+        &self.terrains[m.0]
+    }
 }
 
 pub struct MeshRef<M>(Index, PhantomData<M>);
@@ -465,7 +1500,15 @@ impl<M> std::hash::Hash for MaterialRef<M> {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct TextureRef(Index);
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct AnimRef(Index);
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FontRef(Index);
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpriteSheetRef(Index);
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RenderTargetRef(Index);
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TerrainRef(Index);
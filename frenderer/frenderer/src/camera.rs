@@ -23,12 +23,17 @@ impl Camera {
     pub fn set_ratio(&mut self, r: f32) {
         self.ratio = r;
     }
+    pub fn projection(&self) -> Mat4 {
+        ultraviolet::projection::rh_yup::perspective_reversed_infinite_z_vk(
+            self.fov, self.ratio, 0.1,
+        )
+    }
     pub fn as_matrix(&self) -> Mat4 {
         // projection * view
-        let proj = ultraviolet::projection::rh_yup::perspective_reversed_infinite_z_vk(
-            self.fov, self.ratio, 0.1,
-        );
-        proj * self.transform.into_homogeneous_matrix()
+        self.projection() * self.transform.into_homogeneous_matrix()
+    }
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(self.as_matrix())
     }
     pub fn interpolate(&self, other: &Self, r: f32) -> Self {
         Self {
@@ -39,6 +44,27 @@ impl Camera {
     }
 }
 
+// The planes around what a camera can see, facing inwards.  The
+// projection has no far plane, so neither does this.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [Vec4; 5],
+}
+impl Frustum {
+    pub fn from_matrix(viewproj: Mat4) -> Self {
+        let rows = viewproj.transposed().cols;
+        let (x, y, z, w) = (rows[0], rows[1], rows[2], rows[3]);
+        // left, right, bottom, top, and near (depth is reversed, so near is z = w)
+        let planes = [w + x, w - x, w + y, w - y, w - z].map(|p| p / p.xyz().mag());
+        Self { planes }
+    }
+    pub fn contains_sphere(&self, center: Vec3, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|p| p.xyz().dot(center) + p.w >= -radius)
+    }
+}
+
 pub struct FPCamera {
     pub pitch: f32,
     player_pos: Vec3,
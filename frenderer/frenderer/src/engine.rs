@@ -1,11 +1,22 @@
 use crate::animation;
 use crate::assets::{self, Assets};
-use crate::input::Input;
+use crate::image::Image;
+use crate::input::{Input, Key};
+use crate::mesh_builder::MeshBuilder;
 use crate::renderer;
 use crate::types::*;
 use crate::vulkan::Vulkan;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{ensure, eyre, Result};
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{
+    AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, SubpassContents,
+};
+use vulkano::format::Format;
+use vulkano::image::ImageAccess;
+use vulkano::pipeline::graphics::viewport::Viewport;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
@@ -14,6 +25,14 @@ pub struct WindowSettings {
     pub w: usize,
     pub h: usize,
     pub title: String,
+    // MSAA sample count; falls back to the highest count the device supports
+    pub samples: u32,
+    // saves a screenshot in the working directory
+    pub screenshot_key: Option<Key>,
+    // starts and stops recording a frame sequence into a new directory
+    pub record_key: Option<Key>,
+    // full-screen effects applied to each frame, in order
+    pub post_effects: Vec<renderer::post::PostEffect>,
 }
 
 impl Default for WindowSettings {
@@ -22,6 +41,10 @@ impl Default for WindowSettings {
             w: 1024,
             h: 768,
             title: "Engine Window".to_string(),
+            samples: 4,
+            screenshot_key: Some(Key::F12),
+            record_key: Some(Key::F11),
+            post_effects: vec![],
         }
     }
 }
@@ -34,33 +57,53 @@ pub struct Engine {
     // 1 is new, 0 is old
     render_states: [crate::renderer::RenderState; 2],
     interpolated_state: crate::renderer::RenderState,
-    skinned_renderer: crate::renderer::skinned::Renderer,
-    sprites_renderer: crate::renderer::sprites::Renderer,
-    textured_renderer: crate::renderer::textured::Renderer,
-    flat_renderer: crate::renderer::flat::Renderer,
+    renderers: Renderers,
+    post: renderer::post::Renderer,
+    post_effects: Vec<renderer::post::PostEffect>,
     dt: f64,
     acc: f64,
     last_frame: std::time::Instant,
+    screenshot_key: Option<Key>,
+    record_key: Option<Key>,
+    // where to save the next presented frame
+    screenshot: Option<PathBuf>,
+    // the directory a frame sequence is going into, and the next frame's number
+    recording: Option<(PathBuf, usize)>,
+    pending_capture: Option<PendingCapture>,
 }
 
 impl Engine {
     pub fn new(ws: WindowSettings, dt: f64) -> Self {
-        use crate::camera::Camera;
-        use crate::types::Vec3;
         let event_loop = EventLoop::new();
         let wb = WindowBuilder::new()
             .with_inner_size(winit::dpi::LogicalSize::new(ws.w as f32, ws.h as f32))
             .with_title(ws.title);
+        let vulkan = Vulkan::new(wb, &event_loop, ws.samples);
+        let mut engine = Self::with_vulkan(vulkan, Some(event_loop), dt);
+        engine.screenshot_key = ws.screenshot_key;
+        engine.record_key = ws.record_key;
+        engine.post_effects = ws.post_effects;
+        engine
+    }
+    // An engine with no window that draws `ws.w` by `ws.h` frames offscreen.
+    // It can't `play`; drive it with `step` and `render_image` instead.
+    pub fn headless(ws: WindowSettings, dt: f64) -> Self {
+        let vulkan = Vulkan::headless(ws.w as u32, ws.h as u32, ws.samples);
+        let mut engine = Self::with_vulkan(vulkan, None, dt);
+        engine.post_effects = ws.post_effects;
+        engine
+    }
+    fn with_vulkan(mut vulkan: Vulkan, event_loop: Option<EventLoop<()>>, dt: f64) -> Self {
+        use crate::camera::Camera;
+        use crate::types::Vec3;
         let input = Input::new();
         let default_cam =
             Camera::look_at(Vec3::new(0., 0., 0.), Vec3::new(0., 0., 1.), Vec3::unit_y());
-        let mut vulkan = Vulkan::new(wb, &event_loop);
         Self {
             assets: Assets::new(),
-            skinned_renderer: crate::renderer::skinned::Renderer::new(&mut vulkan),
-            sprites_renderer: crate::renderer::sprites::Renderer::new(&mut vulkan),
-            textured_renderer: crate::renderer::textured::Renderer::new(&mut vulkan),
-            flat_renderer: crate::renderer::flat::Renderer::new(&mut vulkan),
+            renderers: Renderers::new(&mut vulkan),
+            post: renderer::post::Renderer::new(&mut vulkan),
+            post_effects: vec![],
             vulkan,
             render_states: [
                 crate::renderer::RenderState::new(default_cam),
@@ -68,14 +111,22 @@ impl Engine {
             ],
             interpolated_state: crate::renderer::RenderState::new(default_cam),
             dt,
-            event_loop: Some(event_loop),
+            event_loop,
             input,
             acc: 0.0,
             last_frame: std::time::Instant::now(),
+            screenshot_key: None,
+            record_key: None,
+            screenshot: None,
+            recording: None,
+            pending_capture: None,
         }
     }
     pub fn play(mut self, mut w: impl crate::World + 'static) -> Result<()> {
-        let ev = self.event_loop.take().unwrap();
+        let ev = self
+            .event_loop
+            .take()
+            .ok_or_else(|| eyre!("Headless engines can't play; use step and render_image"))?;
         self.last_frame = std::time::Instant::now();
         ev.run(move |event, _, control_flow| {
             match event {
@@ -102,6 +153,9 @@ impl Engine {
                         },
                     ..
                 } => {
+                    if in_event.state == winit::event::ElementState::Pressed {
+                        self.handle_capture_key(in_event.virtual_keycode);
+                    }
                     self.input.handle_key_event(in_event);
                 }
                 Event::WindowEvent {
@@ -120,45 +174,162 @@ impl Engine {
                 Event::MainEventsCleared => {
                     // track DT, accumulator, ...
                     {
-                        self.acc += self.last_frame.elapsed().as_secs_f64();
+                        if self.recording.is_some() {
+                            // one step per recorded frame, however long capturing takes
+                            self.acc += self.dt;
+                        } else {
+                            self.acc += self.last_frame.elapsed().as_secs_f64();
+                        }
                         self.last_frame = std::time::Instant::now();
                         while self.acc >= self.dt {
                             w.update(&self.input, &mut self.assets);
                             self.input.next_frame();
                             if self.acc <= self.dt * 2.0 {
-                                self.render_states[0].clear();
-                                w.render(&mut self.assets, &mut self.render_states[0]);
-                                self.render_states.swap(0, 1);
+                                self.record(&mut w);
                             }
                             self.acc -= self.dt;
                         }
                     }
                     self.render3d();
                 }
+                Event::LoopDestroyed => {
+                    // dropping the last frame's future waits for its copy to land
+                    self.vulkan.previous_frame_end.take();
+                    self.save_pending_capture();
+                }
                 _ => (),
             }
         });
     }
+    // Takes down what `w` wants drawn as the newest render state
+    fn record(&mut self, w: &mut impl crate::World) {
+        self.render_states[0].clear();
+        w.render(&mut self.assets, &mut self.render_states[0]);
+        if let Some(path) = self.render_states[0].screenshot.take() {
+            self.screenshot = Some(path);
+        }
+        self.render_states.swap(0, 1);
+    }
+    // One fixed timestep of `w`, for driving headless engines
+    pub fn step(&mut self, w: &mut impl crate::World) {
+        w.update(&self.input, &mut self.assets);
+        self.input.next_frame();
+        self.record(w);
+    }
     fn render3d(&mut self) {
-        use vulkano::command_buffer::{
-            AutoCommandBufferBuilder, CommandBufferUsage, SubpassContents,
+        self.vulkan.recreate_swapchain_if_necessary();
+        // the last frame has finished by now, so whatever it copied back is ready
+        self.save_pending_capture();
+        let image_num = match self.vulkan.get_next_image() {
+            Some(i) => i,
+            None => return,
         };
-
-        let vulkan = &mut self.vulkan;
-        vulkan.recreate_swapchain_if_necessary();
-        let image_num = vulkan.get_next_image();
-        if image_num.is_none() {
+        let r = (self.acc / self.dt) as f32;
+        let mut builder = self.draw_frame(image_num, r);
+        let paths = self.capture_paths();
+        if !paths.is_empty() {
+            match copy_frame(&self.vulkan, &mut builder, image_num) {
+                Ok((buf, size)) => {
+                    self.pending_capture = Some(PendingCapture {
+                        buf,
+                        size,
+                        bgra: matches!(
+                            self.vulkan.color_format,
+                            Format::B8G8R8A8_SRGB | Format::B8G8R8A8_UNORM
+                        ),
+                        paths,
+                    })
+                }
+                Err(e) => eprintln!("Failed to capture frame: {:?}", e),
+            }
+        }
+        let command_buffer = builder.build().unwrap();
+        self.vulkan.execute_commands(command_buffer, image_num);
+    }
+    fn save_pending_capture(&mut self) {
+        if let Some(capture) = self.pending_capture.take() {
+            if let Err(e) = capture.save() {
+                eprintln!("Failed to save capture: {:?}", e);
+            }
+        }
+    }
+    // Saves the next frame shown as a PNG at `path`
+    pub fn screenshot(&mut self, path: impl Into<PathBuf>) {
+        self.screenshot = Some(path.into());
+    }
+    // Saves every frame shown as a numbered PNG in `dir` until
+    // `stop_recording`.  The game steps exactly once per recorded frame, so
+    // the sequence plays back at 1/dt frames per second even if capturing
+    // slows things down.
+    pub fn start_recording(&mut self, dir: impl Into<PathBuf>) -> Result<()> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        self.recording = Some((dir, 0));
+        Ok(())
+    }
+    pub fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+    fn handle_capture_key(&mut self, key: Option<Key>) {
+        if key.is_none() {
             return;
         }
-        let image_num = image_num.unwrap();
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        if key == self.screenshot_key {
+            self.screenshot(format!("screenshot-{}.png", stamp));
+        } else if key == self.record_key {
+            if self.is_recording() {
+                self.stop_recording();
+            } else if let Err(e) = self.start_recording(format!("recording-{}", stamp)) {
+                eprintln!("Failed to start recording: {:?}", e);
+            }
+        }
+    }
+    // Everywhere this frame should be saved to
+    fn capture_paths(&mut self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.screenshot.take().into_iter().collect();
+        if let Some((dir, frame)) = &mut self.recording {
+            paths.push(dir.join(format!("frame-{:06}.png", frame)));
+            *frame += 1;
+        }
+        paths
+    }
+    // Draws the most recent step into the headless engine's image and reads it back
+    pub fn render_image(&mut self) -> Result<Image> {
+        ensure!(
+            self.vulkan.offscreen.is_some(),
+            "Only headless engines can render to images"
+        );
+        self.vulkan.recreate_swapchain_if_necessary();
+        // no interpolation, so the picture only depends on what was stepped
+        let mut builder = self.draw_frame(0, 1.0);
+        let (buf, (w, h)) = copy_frame(&self.vulkan, &mut builder, 0)?;
+        let command_buffer = builder.build()?;
+        self.vulkan.execute_and_wait(command_buffer);
+        let pixels = opaque_pixels(&buf.read()?, false);
+        Ok(Image::from_colors(w, h, pixels))
+    }
+    // Records the interpolated scene, render targets first, into framebuffer `image_num`
+    fn draw_frame(
+        &mut self,
+        image_num: usize,
+        r: f32,
+    ) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer> {
+        use vulkano::command_buffer::CommandBufferUsage;
+
+        let vulkan = &mut self.vulkan;
         let mut builder = AutoCommandBufferBuilder::primary(
             vulkan.device.clone(),
             vulkan.queue.family(),
             CommandBufferUsage::OneTimeSubmit,
         )
         .unwrap();
-        let r = (self.acc / self.dt) as f32;
-        // let r = 1.0;
         let ar = vulkan.viewport.dimensions[0] / vulkan.viewport.dimensions[1];
         self.interpolated_state.camera_mut().set_ratio(ar);
         for rs in self.render_states.iter_mut() {
@@ -167,49 +338,102 @@ impl Engine {
         self.interpolated_state
             .interpolate_from(&self.render_states[0], &self.render_states[1], r);
 
-        self.skinned_renderer.prepare(
-            &self.interpolated_state,
-            &self.assets,
-            &self.interpolated_state.camera,
-        );
-        self.sprites_renderer.prepare(
-            &self.interpolated_state,
-            &self.assets,
-            &self.interpolated_state.camera,
-        );
-        self.flat_renderer.prepare(
-            &self.interpolated_state,
+        // render targets first, so the main scene can sample them
+        draw_targets(
+            &mut self.renderers,
             &self.assets,
-            &self.interpolated_state.camera,
+            vulkan,
+            &mut self.interpolated_state,
+            &mut builder,
         );
-        self.textured_renderer.prepare(
+        self.renderers.prepare(
             &self.interpolated_state,
             &self.assets,
-            &self.interpolated_state.camera,
+            Vec2::new(vulkan.viewport.dimensions[0], vulkan.viewport.dimensions[1]),
         );
 
         builder
             .begin_render_pass(
-                vulkan.framebuffers[image_num].clone(),
+                vulkan.scene_framebuffer.clone(),
                 SubpassContents::Inline,
-                vec![[0.0, 0.0, 0.0, 0.0].into(), (0.0).into()],
+                vulkan.clear_values(self.interpolated_state.clear_color.into()),
             )
             .unwrap()
             .set_viewport(0, [vulkan.viewport.clone()]);
 
-        self.skinned_renderer.draw(&mut builder);
-        self.sprites_renderer.draw(&mut builder);
-        self.flat_renderer.draw(&mut builder);
-        self.textured_renderer.draw(&mut builder);
+        self.renderers.draw(&mut builder);
 
         builder.end_render_pass().unwrap();
-
-        let command_buffer = builder.build().unwrap();
-        vulkan.execute_commands(command_buffer, image_num);
+        self.post.draw(
+            &mut builder,
+            vulkan,
+            &self.assets,
+            &self.post_effects,
+            image_num,
+        );
+        builder
+    }
+    pub fn post_effects(&self) -> &[renderer::post::PostEffect] {
+        &self.post_effects
+    }
+    pub fn set_post_effects(&mut self, effects: Vec<renderer::post::PostEffect>) {
+        self.post_effects = effects;
     }
     pub fn load_texture(&mut self, path: &std::path::Path) -> Result<assets::TextureRef> {
         self.assets.load_texture(path, &mut self.vulkan)
     }
+    pub fn load_texture_with(
+        &mut self,
+        path: &std::path::Path,
+        settings: assets::SamplerSettings,
+    ) -> Result<assets::TextureRef> {
+        self.assets
+            .load_texture_with(path, settings, &mut self.vulkan)
+    }
+    // Rasterizes a TrueType/OpenType font at `px_size` pixels high
+    pub fn load_font(&mut self, path: &std::path::Path, px_size: f32) -> Result<assets::FontRef> {
+        self.assets.load_font(path, px_size, &mut self.vulkan)
+    }
+    pub fn create_render_target(
+        &mut self,
+        width: u32,
+        height: u32,
+    ) -> Result<assets::RenderTargetRef> {
+        self.assets
+            .create_render_target(width, height, &mut self.vulkan)
+    }
+    pub fn load_bitmap_font(
+        &mut self,
+        path: &std::path::Path,
+        first: char,
+        cell_w: u32,
+        cell_h: u32,
+    ) -> Result<assets::FontRef> {
+        self.assets
+            .load_bitmap_font(path, first, cell_w, cell_h, &mut self.vulkan)
+    }
+    pub fn load_sprite_sheet(
+        &mut self,
+        path: &std::path::Path,
+        settings: assets::SamplerSettings,
+    ) -> Result<assets::SpriteSheetRef> {
+        self.assets
+            .load_sprite_sheet(path, settings, &mut self.vulkan)
+    }
+    pub fn create_sprite_grid(
+        &mut self,
+        texture: assets::TextureRef,
+        cell_w: u32,
+        cell_h: u32,
+        sequences: &[(&str, &[u32], f32)],
+    ) -> Result<assets::SpriteSheetRef> {
+        self.assets
+            .create_sprite_grid(texture, cell_w, cell_h, sequences)
+    }
+    // Faces in +X, -X, +Y, -Y, +Z, -Z order, for use with `Sky::Cubemap`
+    pub fn load_cubemap(&mut self, paths: [&std::path::Path; 6]) -> Result<assets::TextureRef> {
+        self.assets.load_cubemap(paths, &mut self.vulkan)
+    }
     pub fn load_skinned(
         &mut self,
         path: &std::path::Path,
@@ -248,7 +472,252 @@ impl Engine {
         assert_eq!(meshes.len(), textures.len());
         Rc::new(renderer::textured::Model::new(meshes, textures))
     }
+    // Models whose meshes use the textures their materials name
+    pub fn load_textured_model(
+        &mut self,
+        path: &std::path::Path,
+    ) -> Result<Rc<renderer::textured::Model>> {
+        self.assets.load_textured_model(path, &mut self.vulkan)
+    }
+    pub fn load_skinned_model(
+        &mut self,
+        path: &std::path::Path,
+        node_root: &[&str],
+    ) -> Result<Rc<renderer::skinned::Model>> {
+        self.assets
+            .load_skinned_model(path, node_root, &mut self.vulkan)
+    }
     pub fn load_flat(&mut self, path: &std::path::Path) -> Result<Rc<renderer::flat::Model>> {
         self.assets.load_flat(path, &mut self.vulkan)
     }
+    pub fn load_pbr(&mut self, path: &std::path::Path) -> Result<Rc<renderer::pbr::Model>> {
+        self.assets.load_pbr(path, &mut self.vulkan)
+    }
+    pub fn create_textured_mesh(
+        &mut self,
+        builder: &MeshBuilder,
+    ) -> Result<assets::MeshRef<renderer::textured::Mesh>> {
+        self.assets.create_textured_mesh(builder, &mut self.vulkan)
+    }
+    pub fn create_flat_mesh(
+        &mut self,
+        builder: &MeshBuilder,
+    ) -> Result<assets::MeshRef<renderer::flat::Mesh>> {
+        self.assets.create_flat_mesh(builder, &mut self.vulkan)
+    }
+    pub fn create_flat_model(
+        &self,
+        meshes: Vec<assets::MeshRef<renderer::flat::Mesh>>,
+        materials: Vec<assets::MaterialRef<renderer::flat::Material>>,
+    ) -> Rc<renderer::flat::Model> {
+        assert_eq!(meshes.len(), materials.len());
+        Rc::new(renderer::flat::Model::new(meshes, materials))
+    }
+    pub fn create_material(
+        &mut self,
+        name: &str,
+        color: Vec4,
+    ) -> Result<assets::MaterialRef<renderer::flat::Material>> {
+        self.assets.create_material(name, color, &mut self.vulkan)
+    }
+    pub fn create_emissive_material(
+        &mut self,
+        name: &str,
+        color: Vec4,
+        emissive: Vec3,
+    ) -> Result<assets::MaterialRef<renderer::flat::Material>> {
+        self.assets
+            .create_emissive_material(name, color, emissive, &mut self.vulkan)
+    }
+    // For `TerrainSettings::splat`
+    pub fn load_splat_map(&mut self, path: &std::path::Path) -> Result<assets::TextureRef> {
+        self.assets.load_splat_map(path, &mut self.vulkan)
+    }
+    pub fn create_terrain(
+        &mut self,
+        heightmap: &std::path::Path,
+        settings: renderer::terrain::TerrainSettings,
+    ) -> Result<assets::TerrainRef> {
+        let img = Image::from_file(heightmap)?;
+        self.assets.create_terrain(&img, settings, &mut self.vulkan)
+    }
+    // Dynamic meshes start out empty; fill them in from `World::update` or
+    // `World::render` through `Assets::dynamic_textured_mesh_mut` and friends
+    pub fn create_dynamic_textured_mesh(
+        &mut self,
+    ) -> assets::MeshRef<renderer::textured::DynamicMesh> {
+        self.assets.create_dynamic_textured_mesh()
+    }
+    pub fn create_dynamic_flat_mesh(&mut self) -> assets::MeshRef<renderer::flat::DynamicMesh> {
+        self.assets.create_dynamic_flat_mesh()
+    }
+    pub fn create_dynamic_textured_model(
+        &self,
+        mesh: assets::MeshRef<renderer::textured::DynamicMesh>,
+        texture: assets::TextureRef,
+    ) -> Rc<renderer::textured::Model> {
+        Rc::new(renderer::textured::Model::dynamic(mesh, texture))
+    }
+    pub fn create_dynamic_flat_model(
+        &self,
+        mesh: assets::MeshRef<renderer::flat::DynamicMesh>,
+        material: assets::MaterialRef<renderer::flat::Material>,
+    ) -> Rc<renderer::flat::Model> {
+        Rc::new(renderer::flat::Model::dynamic(mesh, material))
+    }
+}
+
+// One of each renderer, drawing a whole scene into whichever render pass is going
+struct Renderers {
+    skinned: crate::renderer::skinned::Renderer,
+    sprites: crate::renderer::sprites::Renderer,
+    textured: crate::renderer::textured::Renderer,
+    pbr: crate::renderer::pbr::Renderer,
+    flat: crate::renderer::flat::Renderer,
+    skybox: crate::renderer::skybox::Renderer,
+    terrain: crate::renderer::terrain::Renderer,
+    debug: crate::renderer::debug::Renderer,
+    text: crate::renderer::text::Renderer,
+    particles: crate::renderer::particles::Renderer,
+    overlay: crate::renderer::overlay::Renderer,
+}
+
+impl Renderers {
+    fn new(vulkan: &mut Vulkan) -> Self {
+        Self {
+            skinned: crate::renderer::skinned::Renderer::new(vulkan),
+            sprites: crate::renderer::sprites::Renderer::new(vulkan),
+            textured: crate::renderer::textured::Renderer::new(vulkan),
+            pbr: crate::renderer::pbr::Renderer::new(vulkan),
+            flat: crate::renderer::flat::Renderer::new(vulkan),
+            skybox: crate::renderer::skybox::Renderer::new(vulkan),
+            terrain: crate::renderer::terrain::Renderer::new(vulkan),
+            debug: crate::renderer::debug::Renderer::new(vulkan),
+            text: crate::renderer::text::Renderer::new(vulkan),
+            particles: crate::renderer::particles::Renderer::new(vulkan),
+            overlay: crate::renderer::overlay::Renderer::new(vulkan),
+        }
+    }
+    fn prepare(&mut self, rs: &renderer::RenderState, assets: &Assets, screen: Vec2) {
+        self.skinned.prepare(rs, assets, &rs.camera);
+        self.sprites.prepare(rs, assets, &rs.camera);
+        self.flat.prepare(rs, assets, &rs.camera);
+        self.textured.prepare(rs, assets, &rs.camera);
+        self.pbr.prepare(rs, assets, &rs.camera);
+        self.terrain.prepare(rs, assets, &rs.camera);
+        self.skybox.prepare(rs, assets, &rs.camera);
+        self.text.prepare(rs, assets, &rs.camera);
+        self.particles.prepare(rs, assets, &rs.camera);
+        self.debug.prepare(rs, assets, &rs.camera);
+        self.overlay.prepare(rs, assets, screen);
+    }
+    fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        self.skinned.draw(builder);
+        self.sprites.draw(builder);
+        self.flat.draw(builder);
+        self.textured.draw(builder);
+        self.pbr.draw(builder);
+        self.terrain.draw(builder);
+        // sky goes last so it only touches pixels left at infinite depth
+        self.skybox.draw(builder);
+        // text blends over whatever is behind it, sky included
+        self.text.draw(builder);
+        self.particles.draw(builder);
+        self.debug.draw(builder);
+        // the HUD sits on top of everything, including the debug lines
+        self.overlay.draw(builder);
+    }
+}
+
+// Draws each render target's scene into its framebuffer, innermost first
+fn draw_targets(
+    renderers: &mut Renderers,
+    assets: &Assets,
+    vulkan: &Vulkan,
+    rs: &mut renderer::RenderState,
+    builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+) {
+    for (target, trs) in rs.targets.iter_mut() {
+        draw_targets(renderers, assets, vulkan, trs, builder);
+        let rt = assets.render_target(*target);
+        debug_assert!(
+            !trs.samples_texture(rt.texture, assets),
+            "A render target's scene can't show the target's own texture"
+        );
+        let (w, h) = assets.texture(rt.texture).size;
+        let (w, h) = (w as f32, h as f32);
+        trs.camera_mut().set_ratio(w / h);
+        renderers.prepare(trs, assets, Vec2::new(w, h));
+        builder
+            .begin_render_pass(
+                rt.framebuffer.clone(),
+                SubpassContents::Inline,
+                vulkan.clear_values(trs.clear_color.into()),
+            )
+            .unwrap()
+            .set_viewport(
+                0,
+                [Viewport {
+                    origin: [0.0, 0.0],
+                    dimensions: [w, h],
+                    depth_range: 0.0..1.0,
+                }],
+            );
+        renderers.draw(builder);
+        builder.end_render_pass().unwrap();
+    }
+}
+
+// Adds a copy of framebuffer `image_num`'s final image into host memory to `builder`
+#[allow(clippy::type_complexity)]
+fn copy_frame(
+    vulkan: &Vulkan,
+    builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    image_num: usize,
+) -> Result<(Arc<CpuAccessibleBuffer<[Color]>>, (u32, u32))> {
+    let image = vulkan.frame_image(image_num);
+    let [w, h] = image.dimensions().width_height();
+    let buf = CpuAccessibleBuffer::from_iter(
+        vulkan.device.clone(),
+        BufferUsage::transfer_destination(),
+        false,
+        (0..w * h).map(|_| Color(0, 0, 0, 0)),
+    )?;
+    builder.copy_image_to_buffer(image, buf.clone())?;
+    Ok((buf, (w, h)))
+}
+
+// The window shows frames as opaque whatever their alpha, so saved
+// frames should be too
+fn opaque_pixels(pixels: &[Color], bgra: bool) -> Vec<Color> {
+    pixels
+        .iter()
+        .map(|&Color(r, g, b, _a)| {
+            if bgra {
+                Color(b, g, r, 255)
+            } else {
+                Color(r, g, b, 255)
+            }
+        })
+        .collect()
+}
+
+// A presented frame on its way back from the GPU, saved once the frame is done
+struct PendingCapture {
+    buf: Arc<CpuAccessibleBuffer<[Color]>>,
+    size: (u32, u32),
+    // swapchain images are often BGRA
+    bgra: bool,
+    paths: Vec<PathBuf>,
+}
+
+impl PendingCapture {
+    fn save(&self) -> Result<()> {
+        let pixels = opaque_pixels(&self.buf.read()?, self.bgra);
+        let image = Image::from_colors(self.size.0, self.size.1, pixels);
+        for path in self.paths.iter() {
+            image.save_png(path)?;
+        }
+        Ok(())
+    }
 }
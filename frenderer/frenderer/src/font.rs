@@ -0,0 +1,324 @@
+use crate::assets::TextureRef;
+use crate::color_eyre::eyre::ensure;
+use crate::image::Image;
+use crate::types::*;
+use crate::Result;
+use std::collections::HashMap;
+
+// Where one character lives in the font's atlas, in pixels at the font's native size
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Glyph {
+    // same convention as sprite regions
+    pub region: Rect,
+    pub size: Vec2,
+    // from the pen position on the baseline to the glyph's top left, y down
+    pub offset: Vec2,
+    pub advance: f32,
+}
+
+// A set of glyphs packed into one texture
+pub struct Font {
+    pub texture: TextureRef,
+    // the pixel height the glyphs were rasterized (or drawn) at
+    pub px_size: f32,
+    pub ascent: f32,
+    pub line_height: f32,
+    glyphs: HashMap<char, Glyph>,
+    kerning: HashMap<(char, char), f32>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TextStyle {
+    // in pixels for overlay text, or world units for text in the scene
+    pub size: f32,
+    pub color: Vec4,
+    pub align: Align,
+    // wrap lines at spaces once they get wider than this
+    pub max_width: Option<f32>,
+    // multiplier on the font's line height
+    pub line_spacing: f32,
+}
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            size: 32.0,
+            color: Vec4::one(),
+            align: Align::Left,
+            max_width: None,
+            line_spacing: 1.0,
+        }
+    }
+}
+impl TextStyle {
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        Self {
+            size: self.size.lerp(other.size, r),
+            color: self.color.lerp(other.color, r),
+            ..*other
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PositionedGlyph {
+    pub region: Rect,
+    // top left corner relative to the block's top left, y down
+    pub pos: Vec2,
+    pub size: Vec2,
+}
+pub struct TextLayout {
+    pub glyphs: Vec<PositionedGlyph>,
+    // the bounding box of the whole block
+    pub size: Vec2,
+}
+
+// Characters rasterized from TTF/OTF files: printable ASCII and Latin-1
+fn ttf_charset() -> impl Iterator<Item = char> {
+    (' '..='~').chain('\u{a1}'..='\u{ff}')
+}
+
+const ATLAS_PADDING: u32 = 1;
+
+impl Font {
+    // Rasterizes the font at `px_size` pixels high into a new atlas, which
+    // `upload` turns into a texture
+    pub(crate) fn from_ttf(
+        data: &[u8],
+        px_size: f32,
+        upload: impl FnOnce(Image) -> Result<TextureRef>,
+    ) -> Result<Self> {
+        use ab_glyph::{Font as _, FontRef, PxScale, ScaleFont};
+        let face = FontRef::try_from_slice(data)?;
+        let scaled = face.as_scaled(PxScale::from(px_size));
+        let outlines: Vec<_> = ttf_charset()
+            .map(|c| {
+                let glyph = scaled.scaled_glyph(c);
+                let advance = scaled.h_advance(glyph.id);
+                (c, advance, face.outline_glyph(glyph))
+            })
+            .collect();
+        // shelf-pack the glyph bitmaps in rows
+        let width = 512.max((px_size.ceil() as u32 * 16).next_power_of_two());
+        let mut placed = Vec::with_capacity(outlines.len());
+        let (mut x, mut y, mut row_h) = (ATLAS_PADDING, ATLAS_PADDING, 0);
+        for (_c, _adv, outline) in outlines.iter() {
+            let (w, h) = match outline {
+                Some(o) => {
+                    let b = o.px_bounds();
+                    (b.width() as u32, b.height() as u32)
+                }
+                None => (0, 0),
+            };
+            ensure!(
+                w + 2 * ATLAS_PADDING <= width,
+                "Glyphs too large for atlas at {}px",
+                px_size
+            );
+            if x + w + ATLAS_PADDING > width {
+                x = ATLAS_PADDING;
+                y += row_h + ATLAS_PADDING;
+                row_h = 0;
+            }
+            placed.push((x, y, w, h));
+            x += w + ATLAS_PADDING;
+            row_h = row_h.max(h);
+        }
+        let height = (y + row_h + ATLAS_PADDING).next_power_of_two();
+        let mut pixels = vec![Color(0, 0, 0, 0); (width * height) as usize];
+        let mut glyphs = HashMap::new();
+        for ((c, advance, outline), (x, y, w, h)) in outlines.iter().zip(placed) {
+            let mut glyph = Glyph {
+                region: Rect::new(0.0, 0.0, 0.0, 0.0),
+                size: Vec2::zero(),
+                offset: Vec2::zero(),
+                advance: *advance,
+            };
+            if let Some(o) = outline {
+                o.draw(|gx, gy, coverage| {
+                    if gx < w && gy < h {
+                        // white, premultiplied by coverage
+                        let v = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+                        pixels[((y + gy) * width + x + gx) as usize] = Color(v, v, v, v);
+                    }
+                });
+                let b = o.px_bounds();
+                glyph.region = Rect::new(
+                    x as f32 / width as f32,
+                    1.0 - y as f32 / height as f32,
+                    w as f32 / width as f32,
+                    h as f32 / height as f32,
+                );
+                glyph.size = Vec2::new(w as f32, h as f32);
+                glyph.offset = Vec2::new(b.min.x, b.min.y);
+            }
+            glyphs.insert(*c, glyph);
+        }
+        let mut kerning = HashMap::new();
+        for a in ttf_charset() {
+            for b in ttf_charset() {
+                let k = scaled.kern(scaled.glyph_id(a), scaled.glyph_id(b));
+                if k != 0.0 {
+                    kerning.insert((a, b), k);
+                }
+            }
+        }
+        let texture = upload(Image::from_colors(width, height, pixels))?;
+        Ok(Self {
+            texture,
+            px_size,
+            ascent: scaled.ascent(),
+            line_height: scaled.height() + scaled.line_gap(),
+            glyphs,
+            kerning,
+        })
+    }
+    // A monospaced bitmap font: `image` is a grid of `cell`-sized glyphs in
+    // reading order, starting with `first`
+    pub(crate) fn from_grid(
+        image: Image,
+        first: char,
+        cell: (u32, u32),
+        upload: impl FnOnce(Image) -> Result<TextureRef>,
+    ) -> Result<Self> {
+        let (cw, ch) = cell;
+        let (width, height) = (image.sz.x, image.sz.y);
+        ensure!(
+            cw > 0 && ch > 0 && width % cw == 0 && height % ch == 0,
+            "Bitmap font image ({}x{}) isn't a grid of {}x{} cells",
+            width,
+            height,
+            cw,
+            ch
+        );
+        let cols = width / cw;
+        let count = cols * (height / ch);
+        let mut glyphs = HashMap::new();
+        for i in 0..count {
+            let c = match char::from_u32(first as u32 + i) {
+                Some(c) => c,
+                None => continue,
+            };
+            let (x, y) = ((i % cols) * cw, (i / cols) * ch);
+            glyphs.insert(
+                c,
+                Glyph {
+                    region: Rect::new(
+                        x as f32 / width as f32,
+                        1.0 - y as f32 / height as f32,
+                        cw as f32 / width as f32,
+                        ch as f32 / height as f32,
+                    ),
+                    size: Vec2::new(cw as f32, ch as f32),
+                    offset: Vec2::new(0.0, -(ch as f32)),
+                    advance: cw as f32,
+                },
+            );
+        }
+        let texture = upload(image)?;
+        Ok(Self {
+            texture,
+            px_size: ch as f32,
+            ascent: ch as f32,
+            line_height: ch as f32,
+            glyphs,
+            kerning: HashMap::new(),
+        })
+    }
+    // Missing characters fall back to '?' if the font has one
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs.get(&c).or_else(|| self.glyphs.get(&'?'))
+    }
+    pub fn kerning(&self, a: char, b: char) -> f32 {
+        self.kerning.get(&(a, b)).copied().unwrap_or(0.0)
+    }
+    // Width of a single line in the font's pixels
+    fn line_width(&self, line: &str) -> f32 {
+        let mut w = 0.0;
+        let mut prev = None;
+        for c in line.chars() {
+            if let Some(p) = prev {
+                w += self.kerning(p, c);
+            }
+            w += self.glyph(c).map(|g| g.advance).unwrap_or(0.0);
+            prev = Some(c);
+        }
+        w
+    }
+    fn wrap<'s>(&self, text: &'s str, max_width: Option<f32>) -> Vec<std::borrow::Cow<'s, str>> {
+        let max_width = match max_width {
+            Some(m) => m,
+            None => return text.lines().map(|l| l.into()).collect(),
+        };
+        let mut lines = vec![];
+        for paragraph in text.lines() {
+            let mut line = String::new();
+            for word in paragraph.split(' ') {
+                let candidate = if line.is_empty() {
+                    word.to_string()
+                } else {
+                    format!("{} {}", line, word)
+                };
+                // a word wider than the whole line gets a line to itself
+                if !line.is_empty() && self.line_width(&candidate) > max_width {
+                    lines.push(std::mem::replace(&mut line, word.to_string()).into());
+                } else {
+                    line = candidate;
+                }
+            }
+            lines.push(line.into());
+        }
+        lines
+    }
+    // Positions each glyph of `text`, scaled to `style.size`
+    pub fn layout(&self, text: &str, style: &TextStyle) -> TextLayout {
+        let scale = style.size / self.px_size;
+        let lines = self.wrap(text, style.max_width.map(|w| w / scale));
+        let widths: Vec<f32> = lines.iter().map(|l| self.line_width(l)).collect();
+        let block_w = widths.iter().copied().fold(0.0, f32::max);
+        let line_step = self.line_height * style.line_spacing;
+        let mut glyphs = vec![];
+        for (i, (line, w)) in lines.iter().zip(widths).enumerate() {
+            let baseline = i as f32 * line_step + self.ascent;
+            let mut pen = match style.align {
+                Align::Left => 0.0,
+                Align::Center => (block_w - w) / 2.0,
+                Align::Right => block_w - w,
+            };
+            let mut prev = None;
+            for c in line.chars() {
+                if let Some(p) = prev {
+                    pen += self.kerning(p, c);
+                }
+                prev = Some(c);
+                let g = match self.glyph(c) {
+                    Some(g) => g,
+                    None => continue,
+                };
+                if g.size.x > 0.0 && g.size.y > 0.0 {
+                    glyphs.push(PositionedGlyph {
+                        region: g.region,
+                        pos: (Vec2::new(pen, baseline) + g.offset) * scale,
+                        size: g.size * scale,
+                    });
+                }
+                pen += g.advance;
+            }
+        }
+        let lines_h = if lines.is_empty() {
+            0.0
+        } else {
+            (lines.len() - 1) as f32 * line_step + self.line_height
+        };
+        TextLayout {
+            glyphs,
+            size: Vec2::new(block_w, lines_h) * scale,
+        }
+    }
+}
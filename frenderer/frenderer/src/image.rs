@@ -16,17 +16,42 @@ impl Image {
             sz: Vec2i { x: w, y: h },
         }
     }
+    pub(crate) fn from_colors(w: u32, h: u32, buffer: Vec<Color>) -> Self {
+        assert_eq!(buffer.len(), (w * h) as usize);
+        Self {
+            buffer: buffer.into_boxed_slice(),
+            sz: Vec2i { x: w, y: h },
+        }
+    }
     pub fn as_slice(&self) -> &[Color] {
         &self.buffer
     }
     pub fn from_file(p: &std::path::Path) -> Result<Self> {
         let img = image_reading::open(p)?.into_rgba8();
-        let sz = Vec2i {
-            x: img.width(),
-            y: img.height(),
-        };
-        let img = img.into_vec();
-        Ok(Self {
+        Ok(Self::from_rgba(img.width(), img.height(), img.into_vec()))
+    }
+    // An encoded image, e.g. a PNG embedded in a .glb
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let img = image_reading::load_from_memory(bytes)?.into_rgba8();
+        Ok(Self::from_rgba(img.width(), img.height(), img.into_vec()))
+    }
+    // RGBA exactly as stored, without premultiplying, for images that hold
+    // data rather than colors
+    pub(crate) fn from_file_straight(p: &std::path::Path) -> Result<Self> {
+        let img = image_reading::open(p)?.into_rgba8();
+        let (w, h) = (img.width(), img.height());
+        let buffer = img
+            .into_vec()
+            .chunks_exact(4)
+            .map(|px| Color(px[0], px[1], px[2], px[3]))
+            .collect();
+        Ok(Self::from_colors(w, h, buffer))
+    }
+    // Straight-alpha RGBA bytes, premultiplied on the way in
+    pub(crate) fn from_rgba(w: u32, h: u32, img: Vec<u8>) -> Self {
+        assert_eq!(img.len(), (w * h * 4) as usize);
+        let sz = Vec2i { x: w, y: h };
+        Self {
             buffer: img
                 .chunks_exact(4)
                 .map(|px| {
@@ -38,6 +63,56 @@ impl Image {
                 })
                 .collect(),
             sz,
-        })
+        }
+    }
+    // Half the size in each direction (but at least 1x1), averaging each
+    // 2x2 block; for building mip chains by hand
+    pub(crate) fn half_size(&self) -> Self {
+        let (w, h) = ((self.sz.x / 2).max(1), (self.sz.y / 2).max(1));
+        let px = |x: u32, y: u32| {
+            self.buffer[(y.min(self.sz.y - 1) * self.sz.x + x.min(self.sz.x - 1)) as usize]
+        };
+        let mut buffer = Vec::with_capacity((w * h) as usize);
+        for y in 0..h {
+            for x in 0..w {
+                let block = [
+                    px(x * 2, y * 2),
+                    px(x * 2 + 1, y * 2),
+                    px(x * 2, y * 2 + 1),
+                    px(x * 2 + 1, y * 2 + 1),
+                ];
+                let avg = |c: fn(&Color) -> u8| {
+                    (block.iter().map(|p| c(p) as u32).sum::<u32>() / 4) as u8
+                };
+                buffer.push(Color(
+                    avg(|p| p.0),
+                    avg(|p| p.1),
+                    avg(|p| p.2),
+                    avg(|p| p.3),
+                ));
+            }
+        }
+        Self::from_colors(w, h, buffer)
+    }
+    // Writes the image as a PNG, undoing the premultiplied alpha
+    pub fn save_png(&self, p: &std::path::Path) -> Result<()> {
+        let bytes: Vec<u8> = self
+            .buffer
+            .iter()
+            .flat_map(|&Color(r, g, b, a)| {
+                let un = |c: u8| {
+                    if a == 0 {
+                        0
+                    } else {
+                        ((c as u32 * 255) / a as u32).min(255) as u8
+                    }
+                };
+                [un(r), un(g), un(b), a]
+            })
+            .collect();
+        let img = image_reading::RgbaImage::from_raw(self.sz.x, self.sz.y, bytes)
+            .expect("Image buffer doesn't match its size");
+        img.save_with_format(p, image_reading::ImageFormat::Png)?;
+        Ok(())
     }
 }
@@ -5,10 +5,14 @@ pub mod assets;
 pub mod camera;
 mod engine;
 pub use engine::{Engine, WindowSettings};
+pub mod font;
 mod image;
+pub use image::Image;
 mod input;
 pub use input::{Input, Key, MousePos};
+pub mod mesh_builder;
 pub mod renderer;
+pub mod sprite_sheet;
 pub mod types;
 mod vulkan;
 
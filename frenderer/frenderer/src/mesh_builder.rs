@@ -0,0 +1,224 @@
+use crate::renderer::{flat, textured};
+use crate::types::*;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MeshVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+}
+
+// Geometry made in code rather than loaded from a file.  Register it with
+// `Engine::create_textured_mesh` or `create_flat_mesh`, or hand its
+// vertices and indices to a dynamic mesh.  Front faces wind
+// counterclockwise, and the primitives are centered on the origin.
+#[derive(Clone, Default, Debug)]
+pub struct MeshBuilder {
+    verts: Vec<MeshVertex>,
+    idxs: Vec<u32>,
+}
+
+impl MeshBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn vertex(&mut self, position: Vec3, normal: Vec3, uv: Vec2) -> u32 {
+        self.verts.push(MeshVertex {
+            position,
+            normal,
+            uv,
+        });
+        (self.verts.len() - 1) as u32
+    }
+    pub fn triangle(&mut self, a: u32, b: u32, c: u32) -> &mut Self {
+        self.idxs.extend_from_slice(&[a, b, c]);
+        self
+    }
+    // Triangles a-b-c and a-c-d
+    pub fn quad(&mut self, a: u32, b: u32, c: u32, d: u32) -> &mut Self {
+        self.triangle(a, b, c).triangle(a, c, d)
+    }
+    // Adds `other`'s triangles, moved by `trf`
+    pub fn append(&mut self, other: &MeshBuilder, trf: Similarity3) -> &mut Self {
+        let base = self.verts.len() as u32;
+        self.verts.extend(other.verts.iter().map(|v| MeshVertex {
+            position: trf.transform_vec(v.position),
+            normal: trf.rotation * v.normal,
+            uv: v.uv,
+        }));
+        self.idxs.extend(other.idxs.iter().map(|i| i + base));
+        self
+    }
+    pub fn vertices(&self) -> &[MeshVertex] {
+        &self.verts
+    }
+    pub fn indices(&self) -> &[u32] {
+        &self.idxs
+    }
+    pub fn is_empty(&self) -> bool {
+        self.idxs.is_empty()
+    }
+    pub fn textured_vertices(&self) -> Vec<textured::Vertex> {
+        self.verts
+            .iter()
+            .map(|v| textured::Vertex {
+                position: v.position.into(),
+                uv: v.uv.into(),
+                normal: v.normal.into(),
+            })
+            .collect()
+    }
+    pub fn flat_vertices(&self) -> Vec<flat::Vertex> {
+        self.verts
+            .iter()
+            .map(|v| flat::Vertex {
+                position: v.position.into(),
+                normal: v.normal.into(),
+                color: [1.0; 4],
+            })
+            .collect()
+    }
+
+    // A `size.x` by `size.y` rectangle on the XZ plane, facing up, with its
+    // texture repeated `uv_scale` times along each side
+    pub fn plane(size: Vec2, uv_scale: Vec2) -> Self {
+        let mut b = Self::new();
+        b.face(
+            Vec3::zero(),
+            Vec3::new(size.x / 2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -size.y / 2.0),
+            uv_scale,
+        );
+        b
+    }
+    // A box with the whole texture on each face
+    pub fn cuboid(size: Vec3) -> Self {
+        let h = size / 2.0;
+        let (x, y, z) = (Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z());
+        let mut b = Self::new();
+        // (which way the face points, its right, its up), with right x up = out
+        for (n, u, v) in [
+            (x, -z, y),
+            (-x, z, y),
+            (y, x, -z),
+            (-y, x, z),
+            (z, x, y),
+            (-z, -x, y),
+        ] {
+            b.face(n * h, u * h, v * h, Vec2::one());
+        }
+        b
+    }
+    // `rings` slices from pole to pole, each cut into `segments` around the Y axis
+    pub fn sphere(radius: f32, segments: u32, rings: u32) -> Self {
+        let rings = rings.max(2);
+        let profile: Vec<_> = (0..=rings)
+            .map(|i| {
+                let theta = PI * i as f32 / rings as f32;
+                let n = Vec2::new(theta.sin(), theta.cos());
+                (n * radius, n, i as f32 / rings as f32)
+            })
+            .collect();
+        let mut b = Self::new();
+        b.lathe(&profile, segments);
+        b
+    }
+    // Along the Y axis, with capped ends
+    pub fn cylinder(radius: f32, height: f32, segments: u32) -> Self {
+        let h = height / 2.0;
+        let mut b = Self::new();
+        b.lathe(
+            &[
+                (Vec2::new(radius, h), Vec2::unit_x(), 0.0),
+                (Vec2::new(radius, -h), Vec2::unit_x(), 1.0),
+            ],
+            segments,
+        );
+        b.disc(h, radius, segments, true);
+        b.disc(-h, radius, segments, false);
+        b
+    }
+    // A cylinder `height` long between the centers of its hemispherical ends,
+    // so `height + 2 * radius` long in all
+    pub fn capsule(radius: f32, height: f32, segments: u32, rings: u32) -> Self {
+        // an even number of rings, so there's one at the equator to stretch
+        let rings = (rings.max(2) + 1) / 2 * 2;
+        let h = height / 2.0;
+        let length = height + 2.0 * radius;
+        let mut profile = vec![];
+        for i in 0..=rings {
+            let theta = PI * i as f32 / rings as f32;
+            let n = Vec2::new(theta.sin(), theta.cos());
+            let p = n * radius;
+            // v runs down the whole length, from the top of the upper cap
+            if i <= rings / 2 {
+                profile.push((p + Vec2::new(0.0, h), n, (radius - p.y) / length));
+            }
+            if i >= rings / 2 {
+                profile.push((p - Vec2::new(0.0, h), n, (radius + height - p.y) / length));
+            }
+        }
+        let mut b = Self::new();
+        b.lathe(&profile, segments);
+        b
+    }
+
+    // A quad around `center` spanning +-`u` and +-`v`, facing along u x v
+    fn face(&mut self, center: Vec3, u: Vec3, v: Vec3, uv_scale: Vec2) {
+        let n = u.cross(v).normalized();
+        let a = self.vertex(center - u - v, n, Vec2::new(0.0, uv_scale.y));
+        let b = self.vertex(center + u - v, n, uv_scale);
+        let c = self.vertex(center + u + v, n, Vec2::new(uv_scale.x, 0.0));
+        let d = self.vertex(center - u + v, n, Vec2::zero());
+        self.quad(a, b, c, d);
+    }
+    // Sweeps a profile of (radius and height, normal in the same plane, v)
+    // from top to bottom once around the Y axis
+    fn lathe(&mut self, profile: &[(Vec2, Vec2, f32)], segments: u32) {
+        let segments = segments.max(3);
+        let base = self.verts.len() as u32;
+        for (p, n, v) in profile.iter() {
+            // the first column is repeated at the end so the texture can wrap
+            for j in 0..=segments {
+                let phi = 2.0 * PI * j as f32 / segments as f32;
+                let (s, c) = phi.sin_cos();
+                self.vertex(
+                    Vec3::new(p.x * c, p.y, -p.x * s),
+                    Vec3::new(n.x * c, n.y, -n.x * s),
+                    Vec2::new(j as f32 / segments as f32, *v),
+                );
+            }
+        }
+        let cols = segments + 1;
+        for row in 0..profile.len() as u32 - 1 {
+            for j in 0..segments {
+                let a = base + row * cols + j;
+                let b = a + cols;
+                self.quad(a, b, b + 1, a + 1);
+            }
+        }
+    }
+    // A flat cap at height `y`, facing up or down
+    fn disc(&mut self, y: f32, radius: f32, segments: u32, up: bool) {
+        let segments = segments.max(3);
+        let n = if up { Vec3::unit_y() } else { -Vec3::unit_y() };
+        let center = self.vertex(Vec3::new(0.0, y, 0.0), n, Vec2::new(0.5, 0.5));
+        for j in 0..=segments {
+            let phi = 2.0 * PI * j as f32 / segments as f32;
+            let (s, c) = phi.sin_cos();
+            self.vertex(
+                Vec3::new(radius * c, y, -radius * s),
+                n,
+                Vec2::new(0.5 + 0.5 * c, 0.5 + 0.5 * s),
+            );
+        }
+        for j in 0..segments {
+            let (a, b) = (center + 1 + j, center + 2 + j);
+            if up {
+                self.triangle(center, a, b);
+            } else {
+                self.triangle(center, b, a);
+            }
+        }
+    }
+}
@@ -1,13 +1,242 @@
+pub mod debug;
+pub mod dynamic;
 pub mod flat;
+pub mod lod;
+pub mod overlay;
+pub mod particles;
+pub mod pbr;
+pub mod post;
 pub mod skinned;
+pub mod skybox;
 pub mod sprites;
+pub mod terrain;
+pub mod text;
 pub mod textured;
 use crate::animation;
 use crate::assets;
 use crate::camera::Camera;
+use crate::font::TextStyle;
+use crate::sprite_sheet::SpriteAnimationState;
 use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
 use std::collections::HashMap;
-use std::rc::Rc;
+use vulkano::pipeline::graphics::color_blend::ColorBlendState;
+use vulkano::pipeline::graphics::depth_stencil::{CompareOp, DepthState, DepthStencilState};
+use vulkano::pipeline::graphics::rasterization::PolygonMode;
+use vulkano::pipeline::StateMode;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FogMode {
+    // ramps from nothing at `start` to full fog at `end`
+    Linear,
+    Exponential,
+    ExponentialSquared,
+}
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Fog {
+    pub mode: FogMode,
+    pub color: Vec4,
+    // used by the exponential modes
+    pub density: f32,
+    // used by the linear mode, in world units from the camera
+    pub start: f32,
+    pub end: f32,
+}
+impl Fog {
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        Self {
+            mode: other.mode,
+            color: self.color.lerp(other.color, r),
+            density: self.density.lerp(other.density, r),
+            start: self.start.lerp(other.start, r),
+            end: self.end.lerp(other.end, r),
+        }
+    }
+}
+
+// The scene's sun and sky light.  Only PBR models are lit; the other
+// renderers show their colors as they are.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Light {
+    // which way the light travels
+    pub direction: Vec3,
+    // can go past 1, since the scene is drawn in HDR
+    pub color: Vec3,
+    // reaches every surface equally, so shadowed sides aren't black
+    pub ambient: Vec3,
+}
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::new(-0.3, -1.0, -0.2).normalized(),
+            color: Vec3::broadcast(3.0),
+            ambient: Vec3::broadcast(0.3),
+        }
+    }
+}
+impl Light {
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        Self {
+            direction: self.direction.lerp(other.direction, r).normalized(),
+            color: self.color.lerp(other.color, r),
+            ambient: self.ambient.lerp(other.ambient, r),
+        }
+    }
+}
+
+// Per-instance extras for textured, flat, skinned and sprite instances
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InstanceStyle {
+    // multiplies the instance's color; alpha fades it out with a dither pattern
+    pub tint: Vec4,
+    // passed through to the fragment shaders for game-specific effects;
+    // the built-in shaders ignore it
+    pub params: Vec4,
+}
+impl Default for InstanceStyle {
+    fn default() -> Self {
+        Self {
+            tint: Vec4::one(),
+            params: Vec4::zero(),
+        }
+    }
+}
+impl InstanceStyle {
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        Self {
+            tint: self.tint.lerp(other.tint, r),
+            params: self.params.lerp(other.params, r),
+        }
+    }
+}
+
+// An inverted-hull outline around a textured, flat, skinned or PBR instance,
+// e.g. to highlight whatever can be picked up
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Outline {
+    pub color: Vec4,
+    // how far the hull sticks out past the model's surface, in world units
+    pub width: f32,
+}
+impl Outline {
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        Self {
+            color: self.color.lerp(other.color, r),
+            width: self.width + (other.width - self.width) * r,
+        }
+    }
+}
+
+// Debug views of everything in the scene; the sky is only drawn in Shaded.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum RenderMode {
+    #[default]
+    Shaded,
+    // triangle edges only, where the device supports it
+    Wireframe,
+    // world-space normals mapped from -1..1 to 0..1
+    Normals,
+    // a checkerboard over the meshes' texture coordinates
+    UvChecker,
+    // every layer of geometry adds heat, hidden or not
+    Overdraw,
+    // skinned vertices blend their bones' colors by weight; other meshes are grey
+    BoneWeights,
+}
+impl RenderMode {
+    pub const ALL: [RenderMode; 6] = [
+        Self::Shaded,
+        Self::Wireframe,
+        Self::Normals,
+        Self::UvChecker,
+        Self::Overdraw,
+        Self::BoneWeights,
+    ];
+    // The mode after this one, wrapping around, for cycling through them with a key
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|m| *m == self).unwrap();
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+    // The `mode` specialization constant of the debug fragment shaders
+    pub(crate) fn shader_mode(self) -> i32 {
+        match self {
+            Self::Shaded | Self::Wireframe => 0,
+            Self::Normals => 1,
+            Self::UvChecker => 2,
+            Self::Overdraw => 3,
+            Self::BoneWeights => 4,
+        }
+    }
+    pub(crate) fn polygon_mode(self, vulkan: &Vulkan) -> PolygonMode {
+        if self == Self::Wireframe && vulkan.device.enabled_features().fill_mode_non_solid {
+            PolygonMode::Line
+        } else {
+            PolygonMode::Fill
+        }
+    }
+    pub(crate) fn depth_stencil_state(self) -> DepthStencilState {
+        DepthStencilState {
+            // overdraw counts hidden layers too
+            depth: (self != Self::Overdraw).then(|| DepthState {
+                compare_op: StateMode::Fixed(CompareOp::Greater),
+                enable_dynamic: false,
+                write_enable: StateMode::Fixed(true),
+            }),
+            depth_bounds: None,
+            stencil: None,
+        }
+    }
+    pub(crate) fn color_blend_state(self) -> ColorBlendState {
+        if self == Self::Overdraw {
+            ColorBlendState::new(1).blend_additive()
+        } else {
+            ColorBlendState::new(1)
+        }
+    }
+}
+
+// Per-frame uniform data shared by the scene renderers (set 0, binding 0);
+// shaders/frame.glsl declares the matching block
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+pub(crate) struct FrameData {
+    viewproj: [f32; 4 * 4],
+    camera_pos: [f32; 4],
+    fog_color: [f32; 4],
+    // mode, density, start, end
+    fog_params: [f32; 4],
+    // for billboards to face the camera
+    view: [f32; 4 * 4],
+}
+impl FrameData {
+    pub(crate) fn new(camera: &Camera, fog: Option<Fog>) -> Self {
+        let eye = camera.transform.inversed().translation;
+        let (fog_color, fog_params) = match fog {
+            None => ([0.0; 4], [0.0; 4]),
+            Some(f) => (
+                f.color.into(),
+                [
+                    match f.mode {
+                        FogMode::Linear => 1.0,
+                        FogMode::Exponential => 2.0,
+                        FogMode::ExponentialSquared => 3.0,
+                    },
+                    f.density,
+                    f.start,
+                    f.end,
+                ],
+            ),
+        };
+        Self {
+            viewproj: *camera.as_matrix().as_array(),
+            camera_pos: [eye.x, eye.y, eye.z, 1.0],
+            fog_color,
+            fog_params,
+            view: *camera.transform.into_homogeneous_matrix().as_array(),
+        }
+    }
+}
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct RenderKey(usize);
 
@@ -16,7 +245,22 @@ pub struct RenderState {
     sprites: HashMap<RenderKey, sprites::SingleRenderState>,
     flats: HashMap<RenderKey, flat::SingleRenderState>,
     textured: HashMap<RenderKey, textured::SingleRenderState>,
+    pub(crate) pbr: HashMap<RenderKey, pbr::SingleRenderState>,
+    pub(crate) overlay: HashMap<RenderKey, overlay::SingleRenderState>,
+    pub(crate) texts: HashMap<RenderKey, text::SingleRenderState>,
+    pub(crate) particles: HashMap<RenderKey, particles::SingleRenderState>,
+    pub(crate) terrains: Vec<assets::TerrainRef>,
     pub(crate) camera: Camera,
+    pub(crate) sky: Option<skybox::Sky>,
+    pub(crate) clear_color: Vec4,
+    pub(crate) fog: Option<Fog>,
+    pub(crate) light: Light,
+    pub(crate) render_mode: RenderMode,
+    pub(crate) debug: debug::DebugLines,
+    // scenes drawn into render targets before this one
+    pub(crate) targets: HashMap<assets::RenderTargetRef, RenderState>,
+    // where to save this frame once it's shown
+    pub(crate) screenshot: Option<std::path::PathBuf>,
 }
 impl RenderState {
     pub fn new(cam: Camera) -> Self {
@@ -25,7 +269,20 @@ impl RenderState {
             sprites: HashMap::new(),
             flats: HashMap::new(),
             textured: HashMap::new(),
+            pbr: HashMap::new(),
+            overlay: HashMap::new(),
+            texts: HashMap::new(),
+            particles: HashMap::new(),
+            terrains: vec![],
             camera: cam,
+            sky: None,
+            clear_color: Vec4::zero(),
+            fog: None,
+            light: Light::default(),
+            render_mode: RenderMode::Shaded,
+            debug: debug::DebugLines::new(),
+            targets: HashMap::new(),
+            screenshot: None,
         }
     }
     pub fn camera_mut(&mut self) -> &mut Camera {
@@ -34,62 +291,190 @@ impl RenderState {
     pub fn set_camera(&mut self, c: Camera) {
         self.camera = c;
     }
+    pub fn set_sky(&mut self, sky: Option<skybox::Sky>) {
+        self.sky = sky;
+    }
+    pub fn set_clear_color(&mut self, c: Vec4) {
+        self.clear_color = c;
+    }
+    pub fn set_fog(&mut self, fog: Option<Fog>) {
+        self.fog = fog;
+    }
+    pub fn set_light(&mut self, light: Light) {
+        self.light = light;
+    }
+    // Draws the scene's meshes in one of the debug views, or normally
+    pub fn set_render_mode(&mut self, mode: RenderMode) {
+        self.render_mode = mode;
+    }
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+    // Saves this frame as a PNG at `path` once it's on screen
+    pub fn screenshot(&mut self, path: impl Into<std::path::PathBuf>) {
+        self.screenshot = Some(path.into());
+    }
     pub fn clear(&mut self) {
         self.skinned.clear();
         self.sprites.clear();
         self.flats.clear();
         self.textured.clear();
+        self.pbr.clear();
+        self.overlay.clear();
+        self.texts.clear();
+        self.particles.clear();
+        self.terrains.clear();
+        self.debug.clear();
+        self.targets.clear();
     }
     pub fn interpolate_from(&mut self, rs1: &Self, rs2: &Self, r: f32) {
+        self.skinned.retain(|k, _| rs2.skinned.contains_key(k));
         for (k, v1) in rs2.skinned.iter() {
             let v0 = rs1.skinned.get(k).unwrap_or(v1);
             self.skinned.insert(*k, v0.interpolate(v1, r));
         }
+        self.sprites.retain(|k, _| rs2.sprites.contains_key(k));
         for (k, v1) in rs2.sprites.iter() {
             let v0 = rs1.sprites.get(k).unwrap_or(v1);
             self.sprites.insert(*k, v0.interpolate(v1, r));
         }
+        self.flats.retain(|k, _| rs2.flats.contains_key(k));
         for (k, v1) in rs2.flats.iter() {
             let v0 = rs1.flats.get(k).unwrap_or(v1);
             self.flats.insert(*k, v0.interpolate(v1, r));
         }
+        self.textured.retain(|k, _| rs2.textured.contains_key(k));
         for (k, v1) in rs2.textured.iter() {
             let v0 = rs1.textured.get(k).unwrap_or(v1);
             self.textured.insert(*k, v0.interpolate(v1, r));
         }
+        self.pbr.retain(|k, _| rs2.pbr.contains_key(k));
+        for (k, v1) in rs2.pbr.iter() {
+            let v0 = rs1.pbr.get(k).unwrap_or(v1);
+            self.pbr.insert(*k, v0.interpolate(v1, r));
+        }
+        self.overlay.retain(|k, _| rs2.overlay.contains_key(k));
+        for (k, v1) in rs2.overlay.iter() {
+            let v0 = rs1.overlay.get(k).unwrap_or(v1);
+            self.overlay.insert(*k, v0.interpolate(v1, r));
+        }
+        self.texts.retain(|k, _| rs2.texts.contains_key(k));
+        for (k, v1) in rs2.texts.iter() {
+            let v0 = rs1.texts.get(k).unwrap_or(v1);
+            self.texts.insert(*k, v0.interpolate(v1, r));
+        }
+        self.particles.retain(|k, _| rs2.particles.contains_key(k));
+        for (k, v1) in rs2.particles.iter() {
+            let v0 = rs1.particles.get(k).unwrap_or(v1);
+            self.particles.insert(*k, v0.interpolate(v1, r));
+        }
+        // terrains don't move, so there's nothing to blend
+        self.terrains.clone_from(&rs2.terrains);
         self.camera = rs1.camera.interpolate(&rs2.camera, r);
+        self.sky = match (rs1.sky, rs2.sky) {
+            (Some(s1), Some(s2)) => Some(s1.interpolate(&s2, r)),
+            (_, s2) => s2,
+        };
+        self.clear_color = rs1.clear_color.lerp(rs2.clear_color, r);
+        self.fog = match (rs1.fog, rs2.fog) {
+            (Some(f1), Some(f2)) => Some(f1.interpolate(&f2, r)),
+            (_, f2) => f2,
+        };
+        self.light = rs1.light.interpolate(&rs2.light, r);
+        self.render_mode = rs2.render_mode;
+        // debug shapes are immediate-mode: just show the latest ones
+        self.debug.copy_from(&rs2.debug);
+        self.targets.retain(|k, _| rs2.targets.contains_key(k));
+        for (k, t1) in rs2.targets.iter() {
+            let t0 = rs1.targets.get(k).unwrap_or(t1);
+            let t = self
+                .targets
+                .entry(*k)
+                .or_insert_with(|| RenderState::new(t1.camera));
+            t.clear();
+            t.interpolate_from(t0, t1, r);
+        }
+    }
+    // Whether drawing this state would sample `tex`, to catch a render
+    // target's scene showing the target itself
+    pub(crate) fn samples_texture(&self, tex: assets::TextureRef, assets: &assets::Assets) -> bool {
+        use skybox::Sky;
+        self.textured.values().any(|t| t.uses_texture(tex))
+            || self.skinned.values().any(|s| s.uses_texture(tex))
+            || self.pbr.values().any(|p| p.uses_texture(tex, assets))
+            || self.sprites.values().any(|s| s.uses_texture(tex, assets))
+            || self.overlay.values().any(|o| o.uses_texture(tex, assets))
+            || self.texts.values().any(|t| t.uses_texture(tex, assets))
+            || self.particles.values().any(|p| p.uses_texture(tex))
+            || self.terrains.iter().any(|&t| {
+                let settings = assets.terrain(t).settings();
+                settings.splat == tex || settings.layers.iter().any(|l| l.texture == tex)
+            })
+            || matches!(self.sky, Some(Sky::Cubemap(t) | Sky::Equirectangular(t)) if t == tex)
+    }
+    // The scene to draw into `target` this frame, seen from `camera`.  It
+    // gets drawn before this one, so this scene can show the target's
+    // texture, but the target's own scene mustn't.
+    pub fn render_target(
+        &mut self,
+        target: assets::RenderTargetRef,
+        camera: Camera,
+    ) -> &mut RenderState {
+        let rs = self
+            .targets
+            .entry(target)
+            .or_insert_with(|| RenderState::new(camera));
+        rs.set_camera(camera);
+        // targets show the same debug view as the scene they're in
+        rs.render_mode = self.render_mode;
+        rs
     }
 
+    // `model` is an Rc of either a model or an lod::Lod of them.  These
+    // return the instance's state so it can be tweaked, e.g. tinted.
     pub fn render_skinned(
         &mut self,
-        model: Rc<skinned::Model>,
+        model: impl Into<lod::Detail<skinned::Model>>,
         animation: assets::AnimRef,
         state: animation::AnimationState,
         transform: Similarity3,
         key: usize,
-    ) {
-        assert!(self
-            .skinned
-            .insert(
-                RenderKey(key),
-                skinned::SingleRenderState::new(model, animation, state, transform),
-            )
-            .is_none());
+    ) -> &mut skinned::SingleRenderState {
+        Self::insert(
+            &mut self.skinned,
+            key,
+            skinned::SingleRenderState::new(model.into(), animation, state, transform),
+        )
     }
     pub fn render_textured(
         &mut self,
-        model: Rc<textured::Model>,
+        model: impl Into<lod::Detail<textured::Model>>,
         transform: Similarity3,
         key: usize,
-    ) {
-        assert!(self
-            .textured
-            .insert(
-                RenderKey(key),
-                textured::SingleRenderState::new(model, transform),
-            )
-            .is_none());
+    ) -> &mut textured::SingleRenderState {
+        Self::insert(
+            &mut self.textured,
+            key,
+            textured::SingleRenderState::new(model.into(), transform),
+        )
+    }
+    pub fn render_pbr(
+        &mut self,
+        model: impl Into<lod::Detail<pbr::Model>>,
+        transform: Similarity3,
+        key: usize,
+    ) -> &mut pbr::SingleRenderState {
+        Self::insert(
+            &mut self.pbr,
+            key,
+            pbr::SingleRenderState::new(model.into(), transform),
+        )
+    }
+    // Terrains are drawn where their settings put them, once per frame
+    pub fn render_terrain(&mut self, terrain: assets::TerrainRef) {
+        self.terrains.push(terrain);
     }
+    // Returns the sprite's state so it can be tweaked, e.g. made into a billboard
     pub fn render_sprite(
         &mut self,
         tex: assets::TextureRef,
@@ -97,22 +482,129 @@ impl RenderState {
         transform: Isometry3,
         size: Vec2,
         key: usize,
+    ) -> &mut sprites::SingleRenderState {
+        Self::insert(
+            &mut self.sprites,
+            key,
+            sprites::SingleRenderState::new(tex, region, transform, size),
+        )
+    }
+    fn insert<T>(map: &mut HashMap<RenderKey, T>, key: usize, state: T) -> &mut T {
+        use std::collections::hash_map::Entry;
+        match map.entry(RenderKey(key)) {
+            Entry::Vacant(v) => v.insert(state),
+            Entry::Occupied(_) => panic!("Render key {} rendered twice", key),
+        }
+    }
+    // A sprite showing the current frame of one of a sprite sheet's sequences
+    pub fn render_animated_sprite(
+        &mut self,
+        sheet: assets::SpriteSheetRef,
+        state: SpriteAnimationState,
+        transform: Isometry3,
+        size: Vec2,
+        key: usize,
+    ) -> &mut sprites::SingleRenderState {
+        Self::insert(
+            &mut self.sprites,
+            key,
+            sprites::SingleRenderState::animated(sheet, state, transform, size),
+        )
+    }
+    pub fn render_flat(
+        &mut self,
+        model: impl Into<lod::Detail<flat::Model>>,
+        transform: Similarity3,
+        key: usize,
+    ) -> &mut flat::SingleRenderState {
+        Self::insert(
+            &mut self.flats,
+            key,
+            flat::SingleRenderState::new(model.into(), transform),
+        )
+    }
+    // A screen-space quad drawn over the scene, in ascending `z` order
+    pub fn render_overlay(
+        &mut self,
+        tex: assets::TextureRef,
+        region: Rect,
+        placement: overlay::Placement,
+        z: i32,
+        key: usize,
+    ) {
+        Self::insert(
+            &mut self.overlay,
+            key,
+            overlay::SingleRenderState::new(tex, region, placement, z),
+        );
+    }
+    // Text on the screen overlay, sharing keys and z order with `render_overlay`.
+    // The laid out block is placed like an overlay quad, with `offset` in pixels.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_overlay_text(
+        &mut self,
+        font: assets::FontRef,
+        text: &str,
+        style: TextStyle,
+        anchor: overlay::Anchor,
+        offset: Vec2,
+        z: i32,
+        key: usize,
     ) {
-        assert!(self
-            .sprites
-            .insert(
-                RenderKey(key),
-                sprites::SingleRenderState::new(tex, region, transform, size),
-            )
-            .is_none());
-    }
-    pub fn render_flat(&mut self, model: Rc<flat::Model>, transform: Similarity3, key: usize) {
-        assert!(self
-            .flats
-            .insert(
-                RenderKey(key),
-                flat::SingleRenderState::new(model, transform),
-            )
-            .is_none());
+        Self::insert(
+            &mut self.overlay,
+            key,
+            overlay::SingleRenderState::text(
+                font,
+                text,
+                style,
+                overlay::Placement::pixels(anchor, offset, Vec2::zero()),
+                z,
+            ),
+        );
+    }
+    pub fn render_text(
+        &mut self,
+        font: assets::FontRef,
+        text: &str,
+        style: TextStyle,
+        transform: Similarity3,
+        key: usize,
+    ) {
+        Self::insert(
+            &mut self.texts,
+            key,
+            text::SingleRenderState::new(font, text, style, transform),
+        );
+    }
+    // Snapshots the emitter's live particles
+    pub fn render_particles(&mut self, emitter: &particles::Emitter, key: usize) {
+        Self::insert(
+            &mut self.particles,
+            key,
+            particles::SingleRenderState::new(emitter),
+        );
+    }
+    // Debug shapes drawn after this call are hidden behind scene geometry
+    // (the default) or drawn on top of everything
+    pub fn debug_depth_test(&mut self, enabled: bool) {
+        self.debug.depth_test = enabled;
+    }
+    pub fn debug_line(&mut self, from: Vec3, to: Vec3, color: Vec4) {
+        self.debug.line(from, to, color);
+    }
+    pub fn debug_aabb(&mut self, min: Vec3, max: Vec3, color: Vec4) {
+        self.debug.aabb(min, max, color);
+    }
+    pub fn debug_sphere(&mut self, center: Vec3, radius: f32, color: Vec4) {
+        self.debug.sphere(center, radius, color);
+    }
+    // Red, green and blue lines along the transform's x, y and z axes, scaled by its scale
+    pub fn debug_axes(&mut self, trf: Similarity3) {
+        self.debug.axes(trf);
+    }
+    // A square grid on the XZ plane
+    pub fn debug_grid(&mut self, center: Vec3, size: f32, divisions: u32, color: Vec4) {
+        self.debug.grid(center, size, divisions, color);
     }
 }
@@ -0,0 +1,312 @@
+use super::RenderState;
+use crate::assets;
+use crate::camera::Camera;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use std::sync::Arc;
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::TypedBufferAccess;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
+use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::ViewportState;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::Pipeline;
+use vulkano::render_pass::Subpass;
+
+#[repr(C)]
+#[derive(Default, Debug, Clone, Copy, Pod, Zeroable)]
+pub(crate) struct Vertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+vulkano::impl_vertex!(Vertex, position, color);
+
+// Line segments queued up for one frame, split by whether they're hidden behind geometry
+#[derive(Clone)]
+pub(crate) struct DebugLines {
+    depth_tested: Vec<Vertex>,
+    always_visible: Vec<Vertex>,
+    pub(crate) depth_test: bool,
+}
+impl DebugLines {
+    pub(crate) fn new() -> Self {
+        Self {
+            depth_tested: vec![],
+            always_visible: vec![],
+            depth_test: true,
+        }
+    }
+    pub(crate) fn clear(&mut self) {
+        self.depth_tested.clear();
+        self.always_visible.clear();
+    }
+    pub(crate) fn copy_from(&mut self, other: &Self) {
+        self.clear();
+        self.depth_tested.extend_from_slice(&other.depth_tested);
+        self.always_visible.extend_from_slice(&other.always_visible);
+    }
+    pub(crate) fn line(&mut self, from: Vec3, to: Vec3, color: Vec4) {
+        let verts = if self.depth_test {
+            &mut self.depth_tested
+        } else {
+            &mut self.always_visible
+        };
+        verts.push(Vertex {
+            position: from.into(),
+            color: color.into(),
+        });
+        verts.push(Vertex {
+            position: to.into(),
+            color: color.into(),
+        });
+    }
+    pub(crate) fn aabb(&mut self, min: Vec3, max: Vec3, color: Vec4) {
+        let corner = |i: usize| {
+            Vec3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        // each edge joins two corners that differ in exactly one axis
+        for i in 0..8 {
+            for axis in [1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corner(i), corner(i | axis), color);
+                }
+            }
+        }
+    }
+    pub(crate) fn circle(&mut self, center: Vec3, u: Vec3, v: Vec3, color: Vec4) {
+        const SEGMENTS: usize = 24;
+        let point = |i: usize| {
+            let theta = i as f32 / SEGMENTS as f32 * 2.0 * PI;
+            center + u * theta.cos() + v * theta.sin()
+        };
+        for i in 0..SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+    pub(crate) fn sphere(&mut self, center: Vec3, radius: f32, color: Vec4) {
+        let (x, y, z) = (
+            Vec3::unit_x() * radius,
+            Vec3::unit_y() * radius,
+            Vec3::unit_z() * radius,
+        );
+        self.circle(center, x, y, color);
+        self.circle(center, x, z, color);
+        self.circle(center, y, z, color);
+    }
+    pub(crate) fn axes(&mut self, trf: Similarity3) {
+        let origin = trf.translation;
+        self.line(
+            origin,
+            trf.transform_vec(Vec3::unit_x()),
+            Vec4::new(1.0, 0.0, 0.0, 1.0),
+        );
+        self.line(
+            origin,
+            trf.transform_vec(Vec3::unit_y()),
+            Vec4::new(0.0, 1.0, 0.0, 1.0),
+        );
+        self.line(
+            origin,
+            trf.transform_vec(Vec3::unit_z()),
+            Vec4::new(0.0, 0.0, 1.0, 1.0),
+        );
+    }
+    pub(crate) fn grid(&mut self, center: Vec3, size: f32, divisions: u32, color: Vec4) {
+        let half = size / 2.0;
+        let step = size / divisions.max(1) as f32;
+        for i in 0..=divisions.max(1) {
+            let offset = -half + step * i as f32;
+            self.line(
+                center + Vec3::new(offset, 0.0, -half),
+                center + Vec3::new(offset, 0.0, half),
+                color,
+            );
+            self.line(
+                center + Vec3::new(-half, 0.0, offset),
+                center + Vec3::new(half, 0.0, offset),
+                color,
+            );
+        }
+    }
+}
+
+pub struct Renderer {
+    depth_pipeline: Arc<GraphicsPipeline>,
+    overlay_pipeline: Arc<GraphicsPipeline>,
+    uniform_buffers: CpuBufferPool<super::FrameData>,
+    uniform_pds: SingleLayoutDescSetPool,
+    uniform_binding: Option<Arc<SingleLayoutDescSet>>,
+    vertex_pool: CpuBufferPool<Vertex, Arc<vulkano::memory::pool::StdMemoryPool>>,
+    depth_tested:
+        Option<Arc<CpuBufferPoolChunk<Vertex, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
+    always_visible:
+        Option<Arc<CpuBufferPoolChunk<Vertex, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+// vertex attributes
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 color;
+
+// outputs
+layout(location = 0) out vec4 out_color;
+
+// uniforms
+#include <frame.glsl>
+
+void main() {
+  gl_Position = viewproj * vec4(position.xyz, 1.0);
+  out_color = color;
+}
+                "
+            }
+        }
+
+        mod fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(location = 0) in vec4 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    f_color = color;
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let fs = fs::load(vulkan.device.clone()).unwrap();
+        use vulkano::pipeline::graphics::depth_stencil::*;
+        let depth_pipeline = Self::build_pipeline(
+            vulkan,
+            &vs,
+            &fs,
+            DepthStencilState {
+                depth: Some(DepthState {
+                    compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
+                    enable_dynamic: false,
+                    write_enable: vulkano::pipeline::StateMode::Fixed(true),
+                }),
+                depth_bounds: None,
+                stencil: None,
+            },
+        );
+        let overlay_pipeline =
+            Self::build_pipeline(vulkan, &vs, &fs, DepthStencilState::disabled());
+
+        let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let uniform_pds = SingleLayoutDescSetPool::new(
+            depth_pipeline
+                .layout()
+                .set_layouts()
+                .get(0)
+                .unwrap()
+                .clone(),
+        );
+        let vertex_pool = CpuBufferPool::vertex_buffer(vulkan.device.clone());
+
+        Self {
+            depth_pipeline,
+            overlay_pipeline,
+            uniform_buffers,
+            uniform_pds,
+            uniform_binding: None,
+            vertex_pool,
+            depth_tested: None,
+            always_visible: None,
+        }
+    }
+    fn build_pipeline(
+        vulkan: &Vulkan,
+        vs: &vulkano::shader::ShaderModule,
+        fs: &vulkano::shader::ShaderModule,
+        depth_stencil: vulkano::pipeline::graphics::depth_stencil::DepthStencilState,
+    ) -> Arc<GraphicsPipeline> {
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().vertex::<Vertex>())
+            .vertex_shader(vs.entry_point("main").unwrap(), ())
+            .input_assembly_state(
+                InputAssemblyState::new().topology(
+                    vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::LineList,
+                ),
+            )
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs.entry_point("main").unwrap(), ())
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(RasterizationState::new())
+            .depth_stencil_state(depth_stencil)
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    pub fn prepare(&mut self, rs: &RenderState, _assets: &assets::Assets, camera: &Camera) {
+        let buf = self
+            .uniform_buffers
+            .next(super::FrameData::new(camera, None))
+            .unwrap();
+        let uds = self
+            .uniform_pds
+            .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
+                0, buf,
+            )])
+            .unwrap();
+        self.uniform_binding = Some(uds);
+        self.depth_tested = self.upload(&rs.debug.depth_tested);
+        self.always_visible = self.upload(&rs.debug.always_visible);
+    }
+    #[allow(clippy::type_complexity)]
+    fn upload(
+        &self,
+        verts: &[Vertex],
+    ) -> Option<Arc<CpuBufferPoolChunk<Vertex, Arc<vulkano::memory::pool::StdMemoryPool>>>> {
+        if verts.is_empty() {
+            None
+        } else {
+            Some(self.vertex_pool.chunk(verts.iter().copied()).unwrap())
+        }
+    }
+    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        let uds = self.uniform_binding.clone().unwrap();
+        for (pipeline, verts) in [
+            (&self.depth_pipeline, self.depth_tested.take()),
+            (&self.overlay_pipeline, self.always_visible.take()),
+        ] {
+            if let Some(verts) = verts {
+                let count = verts.len() as u32;
+                builder
+                    .bind_pipeline_graphics(pipeline.clone())
+                    .bind_vertex_buffers(0, [verts])
+                    .bind_descriptor_sets(
+                        vulkano::pipeline::PipelineBindPoint::Graphics,
+                        pipeline.layout().clone(),
+                        0,
+                        uds.clone(),
+                    )
+                    .draw(count, 1, 0, 0)
+                    .unwrap();
+            }
+        }
+    }
+}
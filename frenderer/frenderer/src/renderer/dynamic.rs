@@ -0,0 +1,175 @@
+use crate::assets::MeshRef;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::Pod;
+use std::collections::HashMap;
+use std::sync::Arc;
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::ImmutableBuffer;
+use vulkano::buffer::TypedBufferAccess;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::memory::pool::StdMemoryPool;
+
+// Vertex types that can go in a dynamic mesh
+pub trait DynamicVertex: Pod + Send + Sync + 'static {
+    fn position(&self) -> Vec3;
+}
+
+// A mesh whose vertices and indices live on the CPU and can be replaced
+// at any time, e.g. for procedural geometry or trails.  It's uploaded again
+// whenever it's drawn after a change.
+pub struct DynamicMesh<V: DynamicVertex> {
+    verts: Vec<V>,
+    idxs: Vec<u32>,
+    bounds: Bounds,
+    // bumped on every change, so renderers know to upload it again
+    generation: u64,
+}
+impl<V: DynamicVertex> DynamicMesh<V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            verts: vec![],
+            idxs: vec![],
+            bounds: Bounds::from_points(&[]),
+            generation: 0,
+        }
+    }
+    // Replaces the whole mesh; `idxs` are triangles' corners, three at a time
+    pub fn set(&mut self, verts: Vec<V>, idxs: Vec<u32>) {
+        assert_eq!(idxs.len() % 3, 0, "Dynamic mesh indices aren't triangles");
+        assert!(
+            idxs.iter().all(|i| (*i as usize) < verts.len()),
+            "Dynamic mesh index out of range"
+        );
+        self.verts = verts;
+        self.idxs = idxs;
+        self.changed();
+    }
+    // Changes vertices in place, keeping the triangles as they are
+    pub fn modify_vertices(&mut self, f: impl FnOnce(&mut [V])) {
+        f(&mut self.verts);
+        self.changed();
+    }
+    pub fn clear(&mut self) {
+        self.set(vec![], vec![]);
+    }
+    pub fn vertices(&self) -> &[V] {
+        &self.verts
+    }
+    pub fn indices(&self) -> &[u32] {
+        &self.idxs
+    }
+    pub fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+    pub fn is_empty(&self) -> bool {
+        self.idxs.is_empty()
+    }
+    fn changed(&mut self) {
+        let points: Vec<Vec3> = self.verts.iter().map(|v| v.position()).collect();
+        self.bounds = Bounds::from_points(&points);
+        self.generation += 1;
+    }
+}
+
+// Where a batch's vertices and indices are: uploaded once, or uploaded
+// again after every change
+pub(crate) enum Geometry<V> {
+    Static(Arc<ImmutableBuffer<[V]>>, Arc<ImmutableBuffer<[u32]>>),
+    Dynamic(
+        Arc<CpuBufferPoolChunk<V, Arc<StdMemoryPool>>>,
+        Arc<CpuBufferPoolChunk<u32, Arc<StdMemoryPool>>>,
+    ),
+}
+impl<V> Clone for Geometry<V> {
+    fn clone(&self) -> Self {
+        match self {
+            Geometry::Static(v, i) => Geometry::Static(v.clone(), i.clone()),
+            Geometry::Dynamic(v, i) => Geometry::Dynamic(v.clone(), i.clone()),
+        }
+    }
+}
+impl<V: DynamicVertex> Geometry<V> {
+    // Binds the vertices to binding 0 and the indices, returning the index count
+    pub(crate) fn bind<P, L>(&self, builder: &mut AutoCommandBufferBuilder<P, L>) -> u32 {
+        match self {
+            Geometry::Static(v, i) => {
+                builder
+                    .bind_vertex_buffers(0, [v.clone()])
+                    .bind_index_buffer(i.clone());
+                i.len() as u32
+            }
+            Geometry::Dynamic(v, i) => {
+                builder
+                    .bind_vertex_buffers(0, [v.clone()])
+                    .bind_index_buffer(i.clone());
+                i.len() as u32
+            }
+        }
+    }
+}
+
+struct Upload<V> {
+    generation: u64,
+    geometry: Geometry<V>,
+    drawn: bool,
+}
+
+// A renderer's uploads of the dynamic meshes it draws.  The pools hand out
+// buffers round-robin and reuse them once the GPU is done with them.
+pub(crate) struct Uploads<V: DynamicVertex> {
+    vert_pool: CpuBufferPool<V, Arc<StdMemoryPool>>,
+    idx_pool: CpuBufferPool<u32, Arc<StdMemoryPool>>,
+    uploads: HashMap<MeshRef<DynamicMesh<V>>, Upload<V>>,
+}
+impl<V: DynamicVertex> Uploads<V> {
+    pub(crate) fn new(vulkan: &Vulkan) -> Self {
+        Self {
+            vert_pool: CpuBufferPool::vertex_buffer(vulkan.device.clone()),
+            idx_pool: CpuBufferPool::new(
+                vulkan.device.clone(),
+                vulkano::buffer::BufferUsage::index_buffer(),
+            ),
+            uploads: HashMap::new(),
+        }
+    }
+    // The mesh's current geometry, uploading it if it changed since last
+    // time.  Empty meshes can't be uploaded, so don't ask for them.
+    pub(crate) fn geometry(
+        &mut self,
+        key: MeshRef<DynamicMesh<V>>,
+        mesh: &DynamicMesh<V>,
+    ) -> Geometry<V> {
+        use std::collections::hash_map::Entry;
+        debug_assert!(!mesh.is_empty());
+        let upload = match self.uploads.entry(key) {
+            Entry::Occupied(o) if o.get().generation == mesh.generation => o.into_mut(),
+            e => {
+                let geometry = Geometry::Dynamic(
+                    self.vert_pool.chunk(mesh.verts.iter().copied()).unwrap(),
+                    self.idx_pool.chunk(mesh.idxs.iter().copied()).unwrap(),
+                );
+                let upload = Upload {
+                    generation: mesh.generation,
+                    geometry,
+                    drawn: false,
+                };
+                match e {
+                    Entry::Occupied(mut o) => {
+                        o.insert(upload);
+                        o.into_mut()
+                    }
+                    Entry::Vacant(v) => v.insert(upload),
+                }
+            }
+        };
+        upload.drawn = true;
+        upload.geometry.clone()
+    }
+    pub(crate) fn clear_frame(&mut self) {
+        // let go of meshes that didn't get drawn, so their buffers can be reused
+        self.uploads.retain(|_k, u| u.drawn);
+        self.uploads.values_mut().for_each(|u| u.drawn = false);
+    }
+}
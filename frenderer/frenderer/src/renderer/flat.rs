@@ -1,85 +1,176 @@
+use super::dynamic::{DynamicVertex, Geometry, Uploads};
+use super::lod::Detail;
+use super::{InstanceStyle, Outline, RenderMode};
 use crate::assets::{self, MaterialRef, MeshRef};
 use crate::camera::Camera;
 use crate::types::*;
 use crate::vulkan::Vulkan;
 use bytemuck::{Pod, Zeroable};
 use std::collections::HashMap;
-use std::rc::Rc;
 use std::sync::Arc;
 use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
 use vulkano::buffer::CpuBufferPool;
 use vulkano::buffer::ImmutableBuffer;
-use vulkano::buffer::TypedBufferAccess;
 use vulkano::command_buffer::AutoCommandBufferBuilder;
 use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
 use vulkano::descriptor_set::PersistentDescriptorSet;
 use vulkano::descriptor_set::SingleLayoutDescSetPool;
 use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
-use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::rasterization::{CullMode, RasterizationState};
 use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
 use vulkano::pipeline::graphics::viewport::ViewportState;
 use vulkano::pipeline::GraphicsPipeline;
 use vulkano::pipeline::Pipeline;
 use vulkano::render_pass::Subpass;
+use vulkano::shader::{EntryPoint, SpecializationConstants};
 
+// What the shaders see of a material
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+pub(crate) struct MaterialData {
+    pub(crate) color: [f32; 4],
+    // rgb, and 0 to keep the std140 layout
+    pub(crate) emissive: [f32; 4],
+}
 #[derive(Clone, Debug)]
 pub struct Material {
     color: Vec4,
-    buffer: Arc<ImmutableBuffer<[f32; 4]>>,
+    // added after shading, so it shows even in the dark and feeds bloom
+    emissive: Vec3,
+    buffer: Arc<ImmutableBuffer<MaterialData>>,
     name: String,
 }
 impl Material {
     pub fn name(&self) -> &str {
         &self.name
     }
+    // Multiplied with the mesh's vertex colors
     pub fn color(&self) -> Vec4 {
         self.color
     }
-    pub(crate) fn new(color: Vec4, name: String, buffer: Arc<ImmutableBuffer<[f32; 4]>>) -> Self {
+    pub fn emissive(&self) -> Vec3 {
+        self.emissive
+    }
+    pub(crate) fn new(
+        color: Vec4,
+        emissive: Vec3,
+        name: String,
+        buffer: Arc<ImmutableBuffer<MaterialData>>,
+    ) -> Self {
         Self {
             color,
+            emissive,
             name,
             buffer,
         }
     }
 }
+// A model's meshes can be loaded ones or dynamic ones
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ModelMesh {
+    Static(MeshRef<Mesh>),
+    Dynamic(MeshRef<DynamicMesh>),
+}
+impl ModelMesh {
+    fn bounds(&self, assets: &assets::Assets) -> Bounds {
+        match self {
+            ModelMesh::Static(m) => assets.flat_mesh(*m).bounds,
+            ModelMesh::Dynamic(m) => assets.dynamic_flat_mesh(*m).bounds(),
+        }
+    }
+}
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Model {
     materials: Vec<MaterialRef<Material>>,
-    meshes: Vec<MeshRef<Mesh>>,
+    meshes: Vec<ModelMesh>,
 }
 impl Model {
     pub fn new(meshes: Vec<MeshRef<Mesh>>, materials: Vec<MaterialRef<Material>>) -> Self {
-        Self { materials, meshes }
+        Self {
+            materials,
+            meshes: meshes.into_iter().map(ModelMesh::Static).collect(),
+        }
+    }
+    pub fn dynamic(mesh: MeshRef<DynamicMesh>, material: MaterialRef<Material>) -> Self {
+        Self {
+            materials: vec![material],
+            meshes: vec![ModelMesh::Dynamic(mesh)],
+        }
+    }
+    // Bounding sphere radius around the model's origin, for picking levels of detail
+    pub(crate) fn radius(&self, assets: &assets::Assets) -> f32 {
+        self.meshes
+            .iter()
+            .map(|m| {
+                let b = m.bounds(assets);
+                b.center.mag() + b.radius
+            })
+            .fold(0.0, f32::max)
     }
 }
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct ModelKey(assets::MeshRef<Mesh>, assets::MaterialRef<Material>);
+pub(crate) struct ModelKey(ModelMesh, assets::MaterialRef<Material>);
 
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod, Zeroable)]
 pub struct Vertex {
     pub position: [f32; 3],
+    pub normal: [f32; 3],
+    // linear rgba, white for meshes that aren't vertex painted
+    pub color: [f32; 4],
 }
-vulkano::impl_vertex!(Vertex, position);
+vulkano::impl_vertex!(Vertex, position, normal, color);
+impl DynamicVertex for Vertex {
+    fn position(&self) -> Vec3 {
+        self.position.into()
+    }
+}
+pub type DynamicMesh = super::dynamic::DynamicMesh<Vertex>;
 pub struct Mesh {
-    pub mesh: russimp::mesh::Mesh,
+    // None for meshes built in code
+    pub mesh: Option<russimp::mesh::Mesh>,
     pub verts: Arc<ImmutableBuffer<[Vertex]>>,
     pub idx: Arc<ImmutableBuffer<[u32]>>,
+    pub bounds: Bounds,
 }
 
 pub struct SingleRenderState {
-    model: Rc<Model>,
+    model: Detail<Model>,
     transform: Similarity3,
+    style: InstanceStyle,
+    outline: Option<Outline>,
 }
 impl SingleRenderState {
-    pub fn new(model: Rc<Model>, transform: Similarity3) -> Self {
-        Self { model, transform }
+    pub fn new(model: Detail<Model>, transform: Similarity3) -> Self {
+        Self {
+            model,
+            transform,
+            style: InstanceStyle::default(),
+            outline: None,
+        }
+    }
+    pub fn tint(&mut self, tint: Vec4) -> &mut Self {
+        self.style.tint = tint;
+        self
+    }
+    pub fn params(&mut self, params: Vec4) -> &mut Self {
+        self.style.params = params;
+        self
+    }
+    // Draws an outline around the instance, or stops drawing one
+    pub fn outline(&mut self, outline: Option<Outline>) -> &mut Self {
+        self.outline = outline;
+        self
     }
     pub fn interpolate(&self, other: &Self, r: f32) -> Self {
         Self {
             model: other.model.clone(),
             transform: self.transform.lerp(&other.transform, r),
+            style: self.style.interpolate(&other.style, r),
+            outline: match (self.outline, other.outline) {
+                (Some(o0), Some(o1)) => Some(o0.interpolate(&o1, r)),
+                (_, o1) => o1,
+            },
         }
     }
 }
@@ -87,27 +178,37 @@ impl SingleRenderState {
 #[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
 struct InstanceData {
     model: [f32; 4 * 4],
+    tint: [f32; 4],
+    params: [f32; 4],
+    // color, and width (0 for no outline)
+    outline: [f32; 4],
 }
-vulkano::impl_vertex!(InstanceData, model);
+vulkano::impl_vertex!(InstanceData, model, tint, params, outline);
 
 struct BatchData {
-    verts: Arc<ImmutableBuffer<[Vertex]>>,
-    idxs: Arc<ImmutableBuffer<[u32]>>,
+    geometry: Geometry<Vertex>,
     material_pds: Arc<vulkano::descriptor_set::PersistentDescriptorSet>,
     instance_data: Vec<InstanceData>,
+    // whether any instance this frame has an outline
+    outlined: bool,
     instance_buf:
         Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
 }
 
 pub struct Renderer {
     pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    outline_pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    // alternate pipelines for the debug render modes
+    mode_pipelines: HashMap<RenderMode, Arc<vulkano::pipeline::GraphicsPipeline>>,
+    mode: RenderMode,
     // we'll use one uniform buffer across all batches.
-    // it will be the projection-view transform.
-    uniform_buffers: CpuBufferPool<Mat4>,
+    // it will be the projection-view transform and fog settings.
+    uniform_buffers: CpuBufferPool<super::FrameData>,
     uniform_pds: SingleLayoutDescSetPool,
     uniform_binding: Option<Arc<SingleLayoutDescSet>>,
     instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
     batches: HashMap<ModelKey, BatchData>,
+    dynamic: Uploads<Vertex>,
 }
 
 impl Renderer {
@@ -115,19 +216,34 @@ impl Renderer {
         mod vs {
             vulkano_shaders::shader! {
                 ty: "vertex",
+                include: ["src/shaders"],
                 src: "
 #version 450
 
 // vertex attributes
 layout(location = 0) in vec3 position;
+layout(location = 9) in vec4 color;
 // instance data
 layout(location = 1) in mat4 model;
+layout(location = 5) in vec4 tint;
+layout(location = 6) in vec4 params;
+
+// outputs
+layout(location = 0) out vec3 out_world;
+layout(location = 1) flat out vec4 out_tint;
+layout(location = 2) flat out vec4 out_params;
+layout(location = 3) out vec4 out_color;
 
 // uniforms
-layout(set=0, binding=0) uniform BatchData { mat4 viewproj; };
+#include <frame.glsl>
 
 void main() {
-  gl_Position = viewproj * model * vec4(position.xyz, 1.0);
+  vec4 world = model * vec4(position.xyz, 1.0);
+  gl_Position = viewproj * world;
+  out_world = world.xyz;
+  out_tint = tint;
+  out_params = params;
+  out_color = color;
 }
                 "
             }
@@ -136,15 +252,164 @@ void main() {
         mod fs {
             vulkano_shaders::shader! {
                 ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform Material {
+                  vec4 color;
+                  vec4 emissive;
+                };
+                layout(location = 0) in vec3 world;
+                layout(location = 1) flat in vec4 tint;
+                layout(location = 2) flat in vec4 params;
+                layout(location = 3) in vec4 vertex_color;
+                layout(location = 0) out vec4 f_color;
+
+                // ordered dither, so faded instances need no sorting
+                float dither() {
+                    const float bayer[16] = float[16](
+                        0.0, 8.0, 2.0, 10.0, 12.0, 4.0, 14.0, 6.0,
+                        3.0, 11.0, 1.0, 9.0, 15.0, 7.0, 13.0, 5.0);
+                    ivec2 p = ivec2(gl_FragCoord.xy) % 4;
+                    return (bayer[p.y * 4 + p.x] + 0.5) / 16.0;
+                }
+
+                void main() {
+                    vec4 base = color * vertex_color;
+                    if (base.a < 0.1 || tint.a < dither()) { discard; }
+                    vec3 col = base.rgb * tint.rgb + emissive.rgb;
+                    f_color = vec4(mix(col, fog_color.rgb * base.a, fog_amount(world)), base.a);
+                }
+            "
+            }
+        }
+
+        mod outline_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 7) in vec3 normal;
+layout(location = 1) in mat4 model;
+layout(location = 8) in vec4 outline;
+
+layout(location = 0) out vec3 out_world;
+layout(location = 1) flat out vec3 out_color;
+
+#include <frame.glsl>
+
+void main() {
+  if (outline.w <= 0.0) {
+    // not outlined: put the whole instance outside the clip volume
+    gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+    return;
+  }
+  // push the hull out along the normal, in world units
+  vec3 n = mat3(model) * normal;
+  n /= max(length(n), 0.0001);
+  vec4 world = model * vec4(position, 1.0) + vec4(n * outline.w, 0.0);
+  gl_Position = viewproj * world;
+  out_world = world.xyz;
+  out_color = outline.rgb;
+}
+"
+            }
+        }
+
+        mod outline_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
                 src: "
                 #version 450
 
+                #include <frame.glsl>
                 layout(set = 1, binding = 0) uniform Material {vec4 color;};
+                layout(location = 0) in vec3 world;
+                layout(location = 1) flat in vec3 outline_color;
                 layout(location = 0) out vec4 f_color;
 
                 void main() {
+                    // neither do invisible materials
                     if (color.a < 0.1) { discard; }
-                    f_color = color;
+                    f_color = vec4(mix(outline_color, fog_color.rgb, fog_amount(world)), 1.0);
+                }
+            "
+            }
+        }
+
+        mod debug_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 7) in vec3 normal;
+layout(location = 1) in mat4 model;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec3 out_normal;
+layout(location = 3) out vec3 out_color;
+
+#include <frame.glsl>
+
+void main() {
+  vec4 world = model * vec4(position, 1.0);
+  gl_Position = viewproj * world;
+  // flat meshes have no texture coordinates, so they get a world-space checker
+  out_uv = world.xz * 0.125;
+  out_world = world.xyz;
+  out_normal = mat3(model) * normal;
+  // no bones to show
+  out_color = vec3(0.5);
+}
+"
+            }
+        }
+
+        mod debug_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                // which view, from RenderMode::shader_mode
+                layout(constant_id = 0) const int mode = 1;
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform Material {vec4 color;} material;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec3 normal;
+                layout(location = 3) in vec3 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    if (material.color.a < 0.1) { discard; }
+                    vec3 n = normal / max(length(normal), 0.0001);
+                    // a headlight, so shapes still read in the flat-colored views
+                    float light = 0.4 + 0.6 * abs(dot(n, normalize(camera_pos.xyz - world)));
+                    if (mode == 1) {
+                        f_color = vec4(n * 0.5 + 0.5, 1.0);
+                    } else if (mode == 2) {
+                        vec2 cell = floor(uv * 8.0);
+                        float check = mod(cell.x + cell.y, 2.0);
+                        f_color = vec4(mix(vec3(0.1), vec3(0.9), check) * light, 1.0);
+                    } else if (mode == 3) {
+                        // blended additively, once for every layer at this pixel
+                        f_color = vec4(0.1, 0.04, 0.01, 1.0);
+                    } else {
+                        f_color = vec4(color * light, 1.0);
+                    }
                 }
             "
             }
@@ -152,38 +417,56 @@ void main() {
 
         let vs = vs::load(vulkan.device.clone()).unwrap();
         let fs = fs::load(vulkan.device.clone()).unwrap();
-        use vulkano::pipeline::graphics::depth_stencil::*;
-        let pipeline = GraphicsPipeline::start()
-            .vertex_input_state(
-                BuffersDefinition::new()
-                    .vertex::<Vertex>()
-                    .instance::<InstanceData>(),
-            )
-            .vertex_shader(vs.entry_point("main").unwrap(), ())
-            .input_assembly_state(InputAssemblyState::new().topology(
-                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
-            ))
-            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
-            .fragment_shader(fs.entry_point("main").unwrap(), ())
-            .rasterization_state(
-                RasterizationState::new()
-                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
-                    .front_face(
-                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
-                    ),
-            )
-            .depth_stencil_state(DepthStencilState {
-                depth: Some(DepthState {
-                    compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
-                    enable_dynamic: false,
-                    write_enable: vulkano::pipeline::StateMode::Fixed(true),
-                }),
-                depth_bounds: None,
-                stencil: None,
+        let outline_vs = outline_vs::load(vulkan.device.clone()).unwrap();
+        let outline_fs = outline_fs::load(vulkan.device.clone()).unwrap();
+        let debug_vs = debug_vs::load(vulkan.device.clone()).unwrap();
+        let debug_fs = debug_fs::load(vulkan.device.clone()).unwrap();
+        let pipeline = Self::build_pipeline(
+            vulkan,
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+            (),
+            CullMode::Back,
+            RenderMode::Shaded,
+        );
+        // the hull's back faces, pushed out past the model, show around its edges
+        let outline_pipeline = Self::build_pipeline(
+            vulkan,
+            outline_vs.entry_point("main").unwrap(),
+            outline_fs.entry_point("main").unwrap(),
+            (),
+            CullMode::Front,
+            RenderMode::Shaded,
+        );
+        // wireframe keeps the usual shaders; the other views share the debug ones
+        let mode_pipelines = RenderMode::ALL
+            .into_iter()
+            .filter(|mode| *mode != RenderMode::Shaded)
+            .map(|mode| {
+                let pipeline = if mode == RenderMode::Wireframe {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        fs.entry_point("main").unwrap(),
+                        (),
+                        CullMode::Back,
+                        mode,
+                    )
+                } else {
+                    Self::build_pipeline(
+                        vulkan,
+                        debug_vs.entry_point("main").unwrap(),
+                        debug_fs.entry_point("main").unwrap(),
+                        debug_fs::SpecializationConstants {
+                            mode: mode.shader_mode(),
+                        },
+                        CullMode::Back,
+                        mode,
+                    )
+                };
+                (mode, pipeline)
             })
-            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
-            .build(vulkan.device.clone())
-            .unwrap();
+            .collect();
 
         let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
         let uniform_pds =
@@ -193,42 +476,94 @@ void main() {
 
         Self {
             pipeline,
+            outline_pipeline,
+            mode_pipelines,
+            mode: RenderMode::Shaded,
             uniform_buffers,
             uniform_pds,
             instance_pool,
             batches: HashMap::new(),
+            dynamic: Uploads::new(vulkan),
             uniform_binding: None,
         }
     }
+    fn build_pipeline<S: SpecializationConstants>(
+        vulkan: &Vulkan,
+        vs: EntryPoint,
+        fs: EntryPoint,
+        fs_constants: S,
+        cull_mode: CullMode,
+        mode: RenderMode,
+    ) -> Arc<GraphicsPipeline> {
+        GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<Vertex>()
+                    .instance::<InstanceData>(),
+            )
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, fs_constants)
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(
+                RasterizationState::new()
+                    .cull_mode(cull_mode)
+                    .front_face(
+                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                    )
+                    .polygon_mode(mode.polygon_mode(vulkan)),
+            )
+            .depth_stencil_state(mode.depth_stencil_state())
+            .color_blend_state(mode.color_blend_state())
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
     pub(crate) fn push_model(
         &mut self,
         key: ModelKey,
-        mesh: &Mesh,
+        geometry: Geometry<Vertex>,
         material: &Material,
         trf: Similarity3,
+        style: InstanceStyle,
+        outline: Option<Outline>,
     ) {
         use std::collections::hash_map::Entry;
         let inst = InstanceData {
             model: *trf.into_homogeneous_matrix().as_array(),
+            tint: style.tint.into(),
+            params: style.params.into(),
+            outline: match outline {
+                Some(o) => [o.color.x, o.color.y, o.color.z, o.width],
+                None => [0.0; 4],
+            },
         };
         match self.batches.entry(key) {
             Entry::Vacant(v) => {
-                let mut b = Self::create_batch(self.pipeline.clone(), mesh, material);
+                let mut b = Self::create_batch(self.pipeline.clone(), geometry, material);
                 b.push_instance(inst);
                 v.insert(b);
             }
-            Entry::Occupied(v) => v.into_mut().push_instance(inst),
+            Entry::Occupied(v) => {
+                let b = v.into_mut();
+                // dynamic meshes may have been uploaded again since last frame
+                b.geometry = geometry;
+                b.push_instance(inst);
+            }
         }
     }
     fn create_batch(
         pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
-        mesh: &Mesh,
+        geometry: Geometry<Vertex>,
         material: &Material,
     ) -> BatchData {
         BatchData {
-            verts: mesh.verts.clone(),
-            idxs: mesh.idx.clone(),
+            geometry,
             instance_data: vec![],
+            outlined: false,
             instance_buf: None,
             material_pds: PersistentDescriptorSet::new(
                 pipeline.layout().set_layouts().get(1).unwrap().clone(),
@@ -241,17 +576,43 @@ void main() {
         }
     }
     pub fn prepare(&mut self, rs: &super::RenderState, assets: &assets::Assets, camera: &Camera) {
+        let frustum = camera.frustum();
         for v in rs.flats.values() {
-            for (meshr, matr) in v.model.meshes.iter().zip(v.model.materials.iter()) {
-                let mesh = assets.flat_mesh(*meshr);
+            let (model, _) = v.model.select(camera, v.transform, |m| m.radius(assets));
+            for (meshr, matr) in model.meshes.iter().zip(model.materials.iter()) {
+                let (center, radius) = meshr.bounds(assets).sphere(v.transform);
+                if !frustum.contains_sphere(center, radius) {
+                    continue;
+                }
+                let geometry = match meshr {
+                    ModelMesh::Static(m) => {
+                        let mesh = assets.flat_mesh(*m);
+                        Geometry::Static(mesh.verts.clone(), mesh.idx.clone())
+                    }
+                    ModelMesh::Dynamic(m) => {
+                        let mesh = assets.dynamic_flat_mesh(*m);
+                        if mesh.is_empty() {
+                            continue;
+                        }
+                        self.dynamic.geometry(*m, mesh)
+                    }
+                };
                 let mat = assets.material(*matr);
-                self.push_model(ModelKey(*meshr, *matr), mesh, mat, v.transform);
+                self.push_model(
+                    ModelKey(*meshr, *matr),
+                    geometry,
+                    mat,
+                    v.transform,
+                    v.style,
+                    v.outline,
+                );
             }
         }
-        self.prepare_draw(camera);
+        self.mode = rs.render_mode;
+        self.prepare_draw(super::FrameData::new(camera, rs.fog));
     }
-    fn prepare_draw(&mut self, camera: &Camera) {
-        let buf = self.uniform_buffers.next(camera.as_matrix()).unwrap();
+    fn prepare_draw(&mut self, frame: super::FrameData) {
+        let buf = self.uniform_buffers.next(frame).unwrap();
         let uds = self
             .uniform_pds
             .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
@@ -259,17 +620,30 @@ void main() {
             )])
             .unwrap();
         self.uniform_binding = Some(uds);
-        for (_k, b) in self.batches.iter_mut() {
+        // batches whose instances were all culled this frame have nothing to upload
+        for (_k, b) in self.batches.iter_mut().filter(|(_k, b)| !b.is_empty()) {
             b.prepare_draw(&self.instance_pool);
         }
     }
     pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
         let uds = self.uniform_binding.clone().unwrap();
 
-        builder.bind_pipeline_graphics(self.pipeline.clone());
+        let pipeline = self
+            .mode_pipelines
+            .get(&self.mode)
+            .unwrap_or(&self.pipeline)
+            .clone();
+        builder.bind_pipeline_graphics(pipeline.clone());
 
-        for (_b, dat) in self.batches.iter() {
-            dat.draw(self.pipeline.clone(), uds.clone(), builder);
+        for (_b, dat) in self.batches.iter().filter(|(_b, dat)| !dat.is_empty()) {
+            dat.draw(pipeline.clone(), uds.clone(), builder);
+        }
+        // outlined batches get drawn again, with instances that aren't outlined clipped away
+        if self.mode == RenderMode::Shaded && self.batches.values().any(|b| b.outlined) {
+            builder.bind_pipeline_graphics(self.outline_pipeline.clone());
+            for dat in self.batches.values().filter(|dat| dat.outlined) {
+                dat.draw(self.outline_pipeline.clone(), uds.clone(), builder);
+            }
         }
         self.clear_frame();
     }
@@ -279,6 +653,7 @@ void main() {
         self.batches.retain(|_k, v| !v.is_empty());
         // delete instance data from each batch, but don't throw away the vecs' allocations
         self.batches.iter_mut().for_each(|(_k, v)| v.clear_frame());
+        self.dynamic.clear_frame();
     }
 }
 
@@ -299,10 +674,9 @@ impl BatchData {
         unis: Arc<vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet>,
         builder: &mut AutoCommandBufferBuilder<P, L>,
     ) {
+        let index_count = self.geometry.bind(builder);
         builder
-            .bind_vertex_buffers(0, [self.verts.clone()])
             .bind_vertex_buffers(1, [self.instance_buf.clone().unwrap()])
-            .bind_index_buffer(self.idxs.clone())
             .bind_descriptor_sets(
                 vulkano::pipeline::PipelineBindPoint::Graphics,
                 (*pipeline).layout().clone(),
@@ -315,22 +689,18 @@ impl BatchData {
                 1,
                 self.material_pds.clone(),
             )
-            .draw_indexed(
-                self.idxs.len() as u32,
-                self.instance_data.len() as u32,
-                0,
-                0,
-                0,
-            )
+            .draw_indexed(index_count, self.instance_data.len() as u32, 0, 0, 0)
             .unwrap();
     }
     fn clear_frame(&mut self) {
         self.instance_data.clear();
+        self.outlined = false;
     }
     fn is_empty(&self) -> bool {
         self.instance_data.is_empty()
     }
     fn push_instance(&mut self, inst: InstanceData) {
+        self.outlined |= inst.outline[3] > 0.0;
         self.instance_data.push(inst);
     }
 }
@@ -0,0 +1,115 @@
+use crate::camera::Camera;
+use crate::types::*;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LodMetric {
+    // world units from the camera to the instance's origin
+    Distance,
+    // fraction of the screen's height the instance's bounding sphere covers
+    ScreenSize,
+}
+
+pub struct LodLevel<M> {
+    pub model: Rc<M>,
+    // For Distance, this level is used out to `threshold` units away; for
+    // ScreenSize, it's used while the instance covers at least `threshold`
+    pub threshold: f32,
+    // Only for skinned models: poses sampled per second of animation, or
+    // None to follow the animation smoothly
+    pub anim_rate: Option<f32>,
+}
+
+// One model at several levels of detail, most detailed first.  Instances
+// past every level's threshold use the last one.
+pub struct Lod<M> {
+    pub metric: LodMetric,
+    pub levels: Vec<LodLevel<M>>,
+}
+impl<M> Lod<M> {
+    pub fn new(metric: LodMetric) -> Self {
+        Self {
+            metric,
+            levels: vec![],
+        }
+    }
+    pub fn level(self, model: Rc<M>, threshold: f32) -> Self {
+        self.level_with_anim_rate(model, threshold, None)
+    }
+    pub fn level_with_anim_rate(
+        mut self,
+        model: Rc<M>,
+        threshold: f32,
+        anim_rate: Option<f32>,
+    ) -> Self {
+        self.levels.push(LodLevel {
+            model,
+            threshold,
+            anim_rate,
+        });
+        self
+    }
+    // `radius` measures a model's bounding sphere around its origin; only
+    // the most detailed model gets measured
+    pub fn select(
+        &self,
+        camera: &Camera,
+        trf: Similarity3,
+        radius: impl FnOnce(&M) -> f32,
+    ) -> &LodLevel<M> {
+        assert!(!self.levels.is_empty(), "Lod has no levels");
+        let eye = camera.transform.inversed().translation;
+        let dist = (trf.translation - eye).mag();
+        let which = match self.metric {
+            LodMetric::Distance => self.levels.iter().position(|l| dist <= l.threshold),
+            LodMetric::ScreenSize => {
+                let r = radius(&self.levels[0].model) * trf.scale.abs();
+                let size = r / (dist.max(0.001) * (camera.fov / 2.0).tan());
+                self.levels.iter().position(|l| size >= l.threshold)
+            }
+        };
+        &self.levels[which.unwrap_or(self.levels.len() - 1)]
+    }
+}
+
+// What an instance draws: always the same model, or whichever level of
+// detail suits where it is this frame
+#[derive(Clone)]
+pub enum Detail<M> {
+    Model(Rc<M>),
+    Lod(Rc<Lod<M>>),
+}
+impl<M> Detail<M> {
+    // The model to draw and, for skinned models, how often to sample poses
+    pub(crate) fn select(
+        &self,
+        camera: &Camera,
+        trf: Similarity3,
+        radius: impl FnOnce(&M) -> f32,
+    ) -> (&M, Option<f32>) {
+        match self {
+            Detail::Model(m) => (m, None),
+            Detail::Lod(lod) => {
+                let level = lod.select(camera, trf, radius);
+                (&level.model, level.anim_rate)
+            }
+        }
+    }
+    // Every model this might draw, wherever the camera ends up
+    pub(crate) fn models(&self) -> Vec<&M> {
+        match self {
+            Detail::Model(m) => vec![m.as_ref()],
+            Detail::Lod(lod) => lod.levels.iter().map(|l| l.model.as_ref()).collect(),
+        }
+    }
+}
+impl<M> From<Rc<M>> for Detail<M> {
+    fn from(m: Rc<M>) -> Self {
+        Detail::Model(m)
+    }
+}
+impl<M> From<Rc<Lod<M>>> for Detail<M> {
+    fn from(lod: Rc<Lod<M>>) -> Self {
+        Detail::Lod(lod)
+    }
+}
@@ -0,0 +1,423 @@
+use super::RenderState;
+use crate::assets;
+use crate::font::TextStyle;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use std::collections::HashMap;
+use std::sync::Arc;
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::graphics::color_blend::{
+    AttachmentBlend, BlendFactor, BlendOp, ColorBlendState,
+};
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::ViewportState;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::Pipeline;
+use vulkano::render_pass::Subpass;
+
+// Which point of the screen (and of the quad) an overlay element is pinned to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+impl Anchor {
+    // 0.0, 0.5 or 1.0 along each axis, with y pointing down
+    fn factors(&self) -> Vec2 {
+        match self {
+            Anchor::TopLeft => Vec2::new(0.0, 0.0),
+            Anchor::Top => Vec2::new(0.5, 0.0),
+            Anchor::TopRight => Vec2::new(1.0, 0.0),
+            Anchor::Left => Vec2::new(0.0, 0.5),
+            Anchor::Center => Vec2::new(0.5, 0.5),
+            Anchor::Right => Vec2::new(1.0, 0.5),
+            Anchor::BottomLeft => Vec2::new(0.0, 1.0),
+            Anchor::Bottom => Vec2::new(0.5, 1.0),
+            Anchor::BottomRight => Vec2::new(1.0, 1.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Units {
+    Pixels,
+    // fractions of the screen's width and height
+    Normalized,
+}
+
+// Where an overlay element goes on screen.  The quad's anchor point sits on
+// the screen's anchor point, pushed inwards from the anchored edges by
+// `offset` (or right/down for centered axes).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Placement {
+    pub anchor: Anchor,
+    pub units: Units,
+    pub offset: Vec2,
+    pub size: Vec2,
+}
+impl Placement {
+    pub fn pixels(anchor: Anchor, offset: Vec2, size: Vec2) -> Self {
+        Self {
+            anchor,
+            units: Units::Pixels,
+            offset,
+            size,
+        }
+    }
+    pub fn normalized(anchor: Anchor, offset: Vec2, size: Vec2) -> Self {
+        Self {
+            anchor,
+            units: Units::Normalized,
+            offset,
+            size,
+        }
+    }
+    // Top-left corner and size in pixels, y down
+    pub fn screen_rect(&self, screen: Vec2) -> Rect {
+        let scale = match self.units {
+            Units::Pixels => Vec2::one(),
+            Units::Normalized => screen,
+        };
+        let size = self.size * scale;
+        let f = self.anchor.factors();
+        let inwards = Vec2::new(
+            if f.x == 1.0 { -1.0 } else { 1.0 },
+            if f.y == 1.0 { -1.0 } else { 1.0 },
+        );
+        let pos = screen * f - size * f + self.offset * scale * inwards;
+        Rect { pos, sz: size }
+    }
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        if self.anchor != other.anchor || self.units != other.units {
+            return *other;
+        }
+        Self {
+            offset: self.offset.lerp(other.offset, r),
+            size: self.size.lerp(other.size, r),
+            ..*other
+        }
+    }
+}
+
+enum Content {
+    Image {
+        texture: assets::TextureRef,
+        // same convention as sprite regions
+        region: Rect,
+    },
+    // the placement's size is filled in from the laid out text
+    Text {
+        font: assets::FontRef,
+        text: String,
+        style: TextStyle,
+    },
+}
+
+pub struct SingleRenderState {
+    content: Content,
+    placement: Placement,
+    z: i32,
+}
+impl SingleRenderState {
+    pub(crate) fn new(
+        texture: assets::TextureRef,
+        region: Rect,
+        placement: Placement,
+        z: i32,
+    ) -> Self {
+        Self {
+            content: Content::Image { texture, region },
+            placement,
+            z,
+        }
+    }
+    pub(crate) fn text(
+        font: assets::FontRef,
+        text: &str,
+        style: TextStyle,
+        placement: Placement,
+        z: i32,
+    ) -> Self {
+        Self {
+            content: Content::Text {
+                font,
+                text: text.to_string(),
+                style,
+            },
+            placement,
+            z,
+        }
+    }
+    pub(crate) fn uses_texture(&self, tex: assets::TextureRef, assets: &assets::Assets) -> bool {
+        match &self.content {
+            Content::Image { texture, .. } => *texture == tex,
+            Content::Text { font, .. } => assets.font(*font).texture == tex,
+        }
+    }
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        let content = match (&self.content, &other.content) {
+            (
+                Content::Image { region: r0, .. },
+                Content::Image {
+                    texture,
+                    region: r1,
+                },
+            ) => Content::Image {
+                texture: *texture,
+                region: r0.lerp(r1, r),
+            },
+            (Content::Text { style: s0, .. }, Content::Text { font, text, style }) => {
+                Content::Text {
+                    font: *font,
+                    text: text.clone(),
+                    style: s0.interpolate(style, r),
+                }
+            }
+            (_, Content::Image { texture, region }) => Content::Image {
+                texture: *texture,
+                region: *region,
+            },
+            (_, Content::Text { font, text, style }) => Content::Text {
+                font: *font,
+                text: text.clone(),
+                style: *style,
+            },
+        };
+        Self {
+            content,
+            placement: self.placement.interpolate(&other.placement, r),
+            z: other.z,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+struct InstanceData {
+    // top-left corner and size in normalized device coordinates
+    rect: [f32; 4],
+    size_uv: [f32; 4],
+    // straight alpha, multiplied with the texture
+    color: [f32; 4],
+}
+vulkano::impl_vertex!(InstanceData, rect, size_uv, color);
+
+// A run of consecutive instances (in z order) sharing one texture
+struct Run {
+    texture: assets::TextureRef,
+    start: u32,
+    count: u32,
+}
+
+pub struct Renderer {
+    pipeline: Arc<GraphicsPipeline>,
+    instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
+    instance_buf:
+        Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
+    texture_pds: HashMap<assets::TextureRef, Arc<PersistentDescriptorSet>>,
+    runs: Vec<Run>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                src: "
+#version 450
+
+// instance data
+layout(location = 0) in vec4 rect;
+layout(location = 1) in vec4 size_uv;
+layout(location = 2) in vec4 color;
+
+// outputs
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec4 out_color;
+
+void main() {
+  // two triangles, corners as fractions of the quad with y down
+  vec2 corners[] = {
+    vec2(0.0, 0.0),
+    vec2(0.0, 1.0),
+    vec2(1.0, 1.0),
+    vec2(0.0, 0.0),
+    vec2(1.0, 1.0),
+    vec2(1.0, 0.0),
+  };
+  vec2 c = corners[gl_VertexIndex];
+  gl_Position = vec4(rect.xy + rect.zw * c, 0.0, 1.0);
+  out_uv = vec2(size_uv.z, 1.0 - size_uv.w) + size_uv.xy * c;
+  out_color = vec4(color.rgb * color.a, color.a);
+}
+"
+            }
+        }
+
+        mod fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec4 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    f_color = texture(tex, uv) * color;
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let fs = fs::load(vulkan.device.clone()).unwrap();
+        use vulkano::pipeline::graphics::depth_stencil::*;
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().instance::<InstanceData>())
+            .vertex_shader(vs.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs.entry_point("main").unwrap(), ())
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(RasterizationState::new())
+            // textures are stored with premultiplied alpha
+            .color_blend_state(ColorBlendState::new(1).blend(AttachmentBlend {
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::One,
+                color_destination: BlendFactor::OneMinusSrcAlpha,
+                alpha_op: BlendOp::Add,
+                alpha_source: BlendFactor::One,
+                alpha_destination: BlendFactor::OneMinusSrcAlpha,
+            }))
+            .depth_stencil_state(DepthStencilState::disabled())
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap();
+
+        let instance_pool = CpuBufferPool::vertex_buffer(vulkan.device.clone());
+
+        Self {
+            pipeline,
+            instance_pool,
+            instance_buf: None,
+            texture_pds: HashMap::new(),
+            runs: vec![],
+        }
+    }
+    // `screen` is the viewport size in pixels
+    pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, screen: Vec2) {
+        self.runs.clear();
+        // back to front; ties are broken by key so the order is stable between frames
+        let mut elements: Vec<_> = rs.overlay.iter().collect();
+        elements.sort_by_key(|(k, v)| (v.z, **k));
+        let mut instances = Vec::with_capacity(elements.len());
+        for (_k, v) in elements {
+            match &v.content {
+                Content::Image { texture, region } => {
+                    let r = v.placement.screen_rect(screen);
+                    instances.push(Self::instance(screen, r.pos, r.sz, *region, Vec4::one()));
+                    self.push_run(*texture, assets, instances.len());
+                }
+                Content::Text { font, text, style } => {
+                    let f = assets.font(*font);
+                    let layout = f.layout(text, style);
+                    let placement = Placement {
+                        size: layout.size,
+                        ..v.placement
+                    };
+                    // snap to whole pixels to keep small text crisp
+                    let origin = placement.screen_rect(screen).pos;
+                    let origin = Vec2::new(origin.x.round(), origin.y.round());
+                    for g in layout.glyphs {
+                        instances.push(Self::instance(
+                            screen,
+                            origin + g.pos,
+                            g.size,
+                            g.region,
+                            style.color,
+                        ));
+                        self.push_run(f.texture, assets, instances.len());
+                    }
+                }
+            }
+        }
+        self.instance_buf = if instances.is_empty() {
+            None
+        } else {
+            Some(self.instance_pool.chunk(instances).unwrap())
+        };
+    }
+    fn instance(screen: Vec2, pos: Vec2, size: Vec2, region: Rect, color: Vec4) -> InstanceData {
+        let pos = pos / screen * 2.0 - Vec2::one();
+        let sz = size / screen * 2.0;
+        InstanceData {
+            rect: [pos.x, pos.y, sz.x, sz.y],
+            size_uv: [region.sz.x, region.sz.y, region.pos.x, region.pos.y],
+            color: color.into(),
+        }
+    }
+    // Extends the last run with the newest of `count` instances, or starts a new one
+    fn push_run(&mut self, texture: assets::TextureRef, assets: &assets::Assets, count: usize) {
+        match self.runs.last_mut() {
+            Some(run) if run.texture == texture => run.count += 1,
+            _ => self.runs.push(Run {
+                texture,
+                start: count as u32 - 1,
+                count: 1,
+            }),
+        }
+        if !self.texture_pds.contains_key(&texture) {
+            let tex = assets.texture(texture);
+            let pds = PersistentDescriptorSet::new(
+                self.pipeline.layout().set_layouts().get(0).unwrap().clone(),
+                [
+                    vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
+                        0,
+                        tex.view.clone(),
+                        tex.sampler.clone(),
+                    ),
+                ],
+            )
+            .unwrap();
+            self.texture_pds.insert(texture, pds);
+        }
+    }
+    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        let instance_buf = match self.instance_buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        builder
+            .bind_pipeline_graphics(self.pipeline.clone())
+            .bind_vertex_buffers(0, [instance_buf]);
+        for run in self.runs.iter() {
+            builder
+                .bind_descriptor_sets(
+                    vulkano::pipeline::PipelineBindPoint::Graphics,
+                    self.pipeline.layout().clone(),
+                    0,
+                    self.texture_pds[&run.texture].clone(),
+                )
+                .draw(6, run.count, 0, run.start)
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use super::{RenderMode, RenderState};
+use crate::assets;
+use crate::camera::Camera;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::pipeline::graphics::color_blend::{
+    AttachmentBlend, BlendFactor, BlendOp, ColorBlendState,
+};
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::ViewportState;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::Pipeline;
+use vulkano::render_pass::Subpass;
+use vulkano::shader::{EntryPoint, SpecializationConstants};
+
+// Keyframes over a particle's life, from 0.0 (born) to 1.0 (dead)
+#[derive(Clone, Debug)]
+pub struct Curve<T> {
+    keys: Vec<(f32, T)>,
+}
+impl<T: Lerp<f32> + Copy> Curve<T> {
+    pub fn constant(v: T) -> Self {
+        Self {
+            keys: vec![(0.0, v)],
+        }
+    }
+    pub fn linear(from: T, to: T) -> Self {
+        Self {
+            keys: vec![(0.0, from), (1.0, to)],
+        }
+    }
+    // `keys` must be sorted by time and non-empty
+    pub fn new(keys: Vec<(f32, T)>) -> Self {
+        assert!(!keys.is_empty());
+        Self { keys }
+    }
+    pub fn sample(&self, t: f32) -> T {
+        let next = self.keys.iter().position(|(kt, _)| *kt > t);
+        match next {
+            Some(0) => self.keys[0].1,
+            None => self.keys.last().unwrap().1,
+            Some(i) => {
+                let (t0, v0) = self.keys[i - 1];
+                let (t1, v1) = self.keys[i];
+                v0.lerp(v1, (t - t0) / (t1 - t0))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmitterSettings {
+    pub texture: assets::TextureRef,
+    // atlas cels, same convention as sprite regions; empty means the whole texture
+    pub frames: Vec<Rect>,
+    // pick one frame per particle at random rather than flipping through them over its life
+    pub random_frame: bool,
+    // particles per second while the emitter is active
+    pub spawn_rate: f32,
+    pub max_particles: usize,
+    // seconds, picked uniformly in this range
+    pub lifetime: (f32, f32),
+    // spawn within this distance of the emitter along each axis
+    pub spawn_extent: Vec3,
+    pub velocity: Vec3,
+    // added to `velocity`, scaled by a random amount in -1..1 per axis
+    pub velocity_spread: Vec3,
+    pub gravity: Vec3,
+    // fraction of velocity lost per second
+    pub drag: f32,
+    pub color: Curve<Vec4>,
+    pub size: Curve<f32>,
+    // glow instead of covering what's behind
+    pub additive: bool,
+}
+impl EmitterSettings {
+    pub fn new(texture: assets::TextureRef) -> Self {
+        Self {
+            texture,
+            frames: vec![Rect::new(0.0, 1.0, 1.0, 1.0)],
+            random_frame: false,
+            spawn_rate: 10.0,
+            max_particles: 256,
+            lifetime: (1.0, 1.0),
+            spawn_extent: Vec3::zero(),
+            velocity: Vec3::unit_y(),
+            velocity_spread: Vec3::zero(),
+            gravity: Vec3::zero(),
+            drag: 0.0,
+            color: Curve::constant(Vec4::one()),
+            size: Curve::constant(1.0),
+            additive: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Particle {
+    pos: Vec3,
+    vel: Vec3,
+    age: f32,
+    life: f32,
+    frame: usize,
+}
+
+// Simulated on the CPU in `tick`; hand it to `RenderState::render_particles` to draw it
+pub struct Emitter {
+    pub settings: EmitterSettings,
+    pub position: Vec3,
+    // stop spawning, but let the live particles finish
+    pub active: bool,
+    particles: Vec<Particle>,
+    spawn_acc: f32,
+}
+impl Emitter {
+    pub fn new(settings: EmitterSettings, position: Vec3) -> Self {
+        Self {
+            settings,
+            position,
+            active: true,
+            particles: vec![],
+            spawn_acc: 0.0,
+        }
+    }
+    pub fn burst(&mut self, count: usize) {
+        let mut rng = rand::thread_rng();
+        for _ in 0..count {
+            if self.particles.len() >= self.settings.max_particles {
+                break;
+            }
+            let s = &self.settings;
+            let mut spread = |v: Vec3| {
+                Vec3::new(
+                    v.x * rng.gen_range(-1.0_f32..=1.0),
+                    v.y * rng.gen_range(-1.0_f32..=1.0),
+                    v.z * rng.gen_range(-1.0_f32..=1.0),
+                )
+            };
+            let pos = self.position + spread(s.spawn_extent);
+            let vel = s.velocity + spread(s.velocity_spread);
+            let (lo, hi) = s.lifetime;
+            let life = if hi > lo { rng.gen_range(lo..hi) } else { lo };
+            let frame = if s.random_frame && !s.frames.is_empty() {
+                rng.gen_range(0..s.frames.len())
+            } else {
+                0
+            };
+            self.particles.push(Particle {
+                pos,
+                vel,
+                age: 0.0,
+                life,
+                frame,
+            });
+        }
+    }
+    pub fn tick(&mut self, dt: f64) {
+        let dt = dt as f32;
+        let s = &self.settings;
+        for p in self.particles.iter_mut() {
+            p.age += dt;
+            p.vel += s.gravity * dt;
+            p.vel *= (1.0 - s.drag * dt).max(0.0);
+            p.pos += p.vel * dt;
+        }
+        self.particles.retain(|p| p.age < p.life);
+        if self.active {
+            self.spawn_acc += self.settings.spawn_rate * dt;
+            let count = self.spawn_acc.floor();
+            self.spawn_acc -= count;
+            self.burst(count as usize);
+        }
+    }
+    // No live particles and nothing more coming
+    pub fn is_finished(&self) -> bool {
+        !self.active && self.particles.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.particles.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.particles.is_empty()
+    }
+}
+
+// A snapshot of one emitter's live particles
+pub struct SingleRenderState {
+    texture: assets::TextureRef,
+    instances: Vec<InstanceData>,
+}
+impl SingleRenderState {
+    pub(crate) fn new(emitter: &Emitter) -> Self {
+        let s = &emitter.settings;
+        let instances = emitter
+            .particles
+            .iter()
+            .map(|p| {
+                let t = (p.age / p.life).clamp(0.0, 1.0);
+                let frame = if s.random_frame {
+                    p.frame
+                } else {
+                    ((t * s.frames.len() as f32) as usize).min(s.frames.len().saturating_sub(1))
+                };
+                let region = s
+                    .frames
+                    .get(frame)
+                    .copied()
+                    .unwrap_or_else(|| Rect::new(0.0, 1.0, 1.0, 1.0));
+                let mut color = s.color.sample(t);
+                // premultiply here; zero alpha then adds onto the scene
+                color = Vec4::new(
+                    color.x * color.w,
+                    color.y * color.w,
+                    color.z * color.w,
+                    color.w,
+                );
+                if s.additive {
+                    color.w = 0.0;
+                }
+                InstanceData {
+                    pos_size: [p.pos.x, p.pos.y, p.pos.z, s.size.sample(t)],
+                    size_uv: [region.sz.x, region.sz.y, region.pos.x, region.pos.y],
+                    color: color.into(),
+                }
+            })
+            .collect();
+        Self {
+            texture: s.texture,
+            instances,
+        }
+    }
+    pub(crate) fn uses_texture(&self, tex: assets::TextureRef) -> bool {
+        self.texture == tex
+    }
+    pub fn interpolate(&self, other: &Self, _r: f32) -> Self {
+        // particles come and go every tick, so just show the latest ones
+        Self {
+            texture: other.texture,
+            instances: other.instances.clone(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+struct InstanceData {
+    pos_size: [f32; 4],
+    size_uv: [f32; 4],
+    // premultiplied
+    color: [f32; 4],
+}
+vulkano::impl_vertex!(InstanceData, pos_size, size_uv, color);
+struct BatchData {
+    material_pds: Arc<PersistentDescriptorSet>,
+    instance_data: Vec<InstanceData>,
+    instance_buf:
+        Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
+}
+
+pub struct Renderer {
+    pipeline: Arc<GraphicsPipeline>,
+    // alternate pipelines for the debug render modes
+    mode_pipelines: HashMap<RenderMode, Arc<GraphicsPipeline>>,
+    mode: RenderMode,
+    uniform_buffers: CpuBufferPool<super::FrameData>,
+    uniform_pds: SingleLayoutDescSetPool,
+    uniform_binding: Option<Arc<SingleLayoutDescSet>>,
+    instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
+    batches: HashMap<assets::TextureRef, BatchData>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+// instance data
+layout(location = 0) in vec4 pos_size;
+layout(location = 1) in vec4 size_uv;
+layout(location = 2) in vec4 color;
+
+// outputs
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec4 out_color;
+
+// uniforms
+#include <frame.glsl>
+
+void main() {
+  // 0: TL, 1: BL, 2: BR, 3: TL, 4: BR, 5: TR
+  vec2 posns[] = {
+    vec2(-0.5, 0.5),
+    vec2(-0.5, -0.5),
+    vec2(0.5, -0.5),
+    vec2(-0.5, 0.5),
+    vec2(0.5, -0.5),
+    vec2(0.5, 0.5),
+  };
+  vec2 pos = posns[gl_VertexIndex];
+  // always face the screen, using the camera's axes from the view rotation
+  vec3 right = vec3(view[0][0], view[1][0], view[2][0]);
+  vec3 up = vec3(view[0][1], view[1][1], view[2][1]);
+  vec3 world = pos_size.xyz + (right * pos.x + up * pos.y) * pos_size.w;
+  gl_Position = viewproj * vec4(world, 1.0);
+  out_uv = vec2(size_uv.z, 1.0 - size_uv.w) + size_uv.xy * vec2(pos.x + 0.5, 0.5 - pos.y);
+  out_world = world;
+  out_color = color;
+}
+"
+            }
+        }
+
+        mod fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec4 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    vec4 col = texture(tex, uv) * color;
+                    // particles fade out into the fog rather than taking on its color
+                    f_color = col * (1.0 - fog_amount(world));
+                }
+            "
+            }
+        }
+
+        mod debug_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                // which view, from RenderMode::shader_mode
+                layout(constant_id = 0) const int mode = 1;
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec4 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // skip what the shaded particle would leave clear, except when
+                    // counting overdraw, which costs the same either way
+                    vec4 col = texture(tex, uv) * color;
+                    if (mode != 3 && max(col.a, max(col.r, max(col.g, col.b))) < 0.1) {
+                        discard;
+                    }
+                    // quads carry no normals, so take the face's from the world position
+                    vec3 n = normalize(cross(dFdx(world), dFdy(world)));
+                    vec3 to_eye = normalize(camera_pos.xyz - world);
+                    if (dot(n, to_eye) < 0.0) { n = -n; }
+                    // a headlight, so shapes still read in the flat-colored views
+                    float light = 0.4 + 0.6 * abs(dot(n, to_eye));
+                    if (mode == 1) {
+                        f_color = vec4(n * 0.5 + 0.5, 1.0);
+                    } else if (mode == 2) {
+                        vec2 cell = floor(uv * 8.0);
+                        float check = mod(cell.x + cell.y, 2.0);
+                        f_color = vec4(mix(vec3(0.1), vec3(0.9), check) * light, 1.0);
+                    } else if (mode == 3) {
+                        // blended additively, once for every layer at this pixel
+                        f_color = vec4(0.1, 0.04, 0.01, 1.0);
+                    } else {
+                        // no bones to show
+                        f_color = vec4(vec3(0.5) * light, 1.0);
+                    }
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let fs = fs::load(vulkan.device.clone()).unwrap();
+        let debug_fs = debug_fs::load(vulkan.device.clone()).unwrap();
+        let pipeline = Self::build_pipeline(
+            vulkan,
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+            (),
+            RenderMode::Shaded,
+        );
+        // wireframe keeps the usual shaders; the other views share the debug one
+        let mode_pipelines = RenderMode::ALL
+            .into_iter()
+            .filter(|mode| *mode != RenderMode::Shaded)
+            .map(|mode| {
+                let pipeline = if mode == RenderMode::Wireframe {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        fs.entry_point("main").unwrap(),
+                        (),
+                        mode,
+                    )
+                } else {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        debug_fs.entry_point("main").unwrap(),
+                        debug_fs::SpecializationConstants {
+                            mode: mode.shader_mode(),
+                        },
+                        mode,
+                    )
+                };
+                (mode, pipeline)
+            })
+            .collect();
+
+        let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let uniform_pds =
+            SingleLayoutDescSetPool::new(pipeline.layout().set_layouts().get(0).unwrap().clone());
+        let instance_pool = CpuBufferPool::vertex_buffer(vulkan.device.clone());
+
+        Self {
+            pipeline,
+            mode_pipelines,
+            mode: RenderMode::Shaded,
+            uniform_buffers,
+            uniform_pds,
+            uniform_binding: None,
+            instance_pool,
+            batches: HashMap::new(),
+        }
+    }
+    fn build_pipeline<S: SpecializationConstants>(
+        vulkan: &Vulkan,
+        vs: EntryPoint,
+        fs: EntryPoint,
+        fs_constants: S,
+        mode: RenderMode,
+    ) -> Arc<GraphicsPipeline> {
+        use vulkano::pipeline::graphics::depth_stencil::*;
+        // overdraw counts every layer, so it needs its own blending and no depth test
+        let (color_blend, depth_stencil) = if mode == RenderMode::Overdraw {
+            (mode.color_blend_state(), mode.depth_stencil_state())
+        } else {
+            (
+                // premultiplied alpha; particles with zero alpha are purely additive
+                ColorBlendState::new(1).blend(AttachmentBlend {
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::One,
+                    color_destination: BlendFactor::OneMinusSrcAlpha,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::One,
+                    alpha_destination: BlendFactor::OneMinusSrcAlpha,
+                }),
+                // hidden by the scene, but particles don't hide each other
+                DepthStencilState {
+                    depth: Some(DepthState {
+                        compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
+                        enable_dynamic: false,
+                        write_enable: vulkano::pipeline::StateMode::Fixed(false),
+                    }),
+                    depth_bounds: None,
+                    stencil: None,
+                },
+            )
+        };
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().instance::<InstanceData>())
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, fs_constants)
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(RasterizationState::new().polygon_mode(mode.polygon_mode(vulkan)))
+            .color_blend_state(color_blend)
+            .depth_stencil_state(depth_stencil)
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        use std::collections::hash_map::Entry;
+        for v in rs.particles.values() {
+            if v.instances.is_empty() {
+                continue;
+            }
+            let batch = match self.batches.entry(v.texture) {
+                Entry::Occupied(o) => o.into_mut(),
+                Entry::Vacant(e) => {
+                    let texture = assets.texture(v.texture);
+                    e.insert(BatchData {
+                        material_pds: PersistentDescriptorSet::new(
+                            self.pipeline.layout().set_layouts().get(1).unwrap().clone(),
+                            [
+                                vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
+                                    0,
+                                    texture.view.clone(),
+                                    texture.sampler.clone(),
+                                ),
+                            ],
+                        )
+                        .unwrap(),
+                        instance_data: vec![],
+                        instance_buf: None,
+                    })
+                }
+            };
+            batch.instance_data.extend_from_slice(&v.instances);
+        }
+        let buf = self
+            .uniform_buffers
+            .next(super::FrameData::new(camera, rs.fog))
+            .unwrap();
+        let uds = self
+            .uniform_pds
+            .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
+                0, buf,
+            )])
+            .unwrap();
+        self.uniform_binding = Some(uds);
+        self.mode = rs.render_mode;
+        for b in self.batches.values_mut() {
+            b.instance_buf = if b.instance_data.is_empty() {
+                None
+            } else {
+                Some(
+                    self.instance_pool
+                        .chunk(b.instance_data.iter().copied())
+                        .unwrap(),
+                )
+            };
+        }
+    }
+    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        let uds = self.uniform_binding.clone().unwrap();
+        let pipeline = self
+            .mode_pipelines
+            .get(&self.mode)
+            .unwrap_or(&self.pipeline)
+            .clone();
+        builder.bind_pipeline_graphics(pipeline.clone());
+        for b in self.batches.values() {
+            if let Some(buf) = b.instance_buf.clone() {
+                builder
+                    .bind_vertex_buffers(0, [buf])
+                    .bind_descriptor_sets(
+                        vulkano::pipeline::PipelineBindPoint::Graphics,
+                        pipeline.layout().clone(),
+                        0,
+                        uds.clone(),
+                    )
+                    .bind_descriptor_sets(
+                        vulkano::pipeline::PipelineBindPoint::Graphics,
+                        pipeline.layout().clone(),
+                        1,
+                        b.material_pds.clone(),
+                    )
+                    .draw(6, b.instance_data.len() as u32, 0, 0)
+                    .unwrap();
+            }
+        }
+        // delete batches for textures that didn't get drawn this frame, and
+        // clear the rest without throwing away their allocations
+        self.batches.retain(|_k, b| !b.instance_data.is_empty());
+        self.batches
+            .values_mut()
+            .for_each(|b| b.instance_data.clear());
+    }
+}
@@ -0,0 +1,885 @@
+use super::lod::Detail;
+use super::RenderState;
+use super::{InstanceStyle, Outline, RenderMode};
+use crate::assets::{self, MaterialRef, MeshRef};
+use crate::camera::Camera;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use std::collections::HashMap;
+use std::sync::Arc;
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::ImmutableBuffer;
+use vulkano::buffer::TypedBufferAccess;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::descriptor_set::WriteDescriptorSet;
+use vulkano::image::immutable::ImmutableImage;
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::{CullMode, RasterizationState};
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::ViewportState;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::Pipeline;
+use vulkano::render_pass::Subpass;
+use vulkano::sampler::Sampler;
+use vulkano::shader::{EntryPoint, SpecializationConstants};
+
+#[repr(C)]
+#[derive(Default, Debug, Clone, Copy, Pod, Zeroable)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub uv: [f32; 2],
+    pub normal: [f32; 3],
+    // xyz along +u, w the handedness of the bitangent; zero if the mesh has none
+    pub tangent: [f32; 4],
+}
+vulkano::impl_vertex!(Vertex, position, uv, normal, tangent);
+
+pub struct Mesh {
+    pub mesh: Option<russimp::mesh::Mesh>,
+    pub verts: Arc<ImmutableBuffer<[Vertex]>>,
+    pub idx: Arc<ImmutableBuffer<[u32]>>,
+    pub bounds: Bounds,
+}
+
+// What the shaders see of a material
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+pub(crate) struct MaterialData {
+    pub(crate) base_color: [f32; 4],
+    // rgb, and 0 to keep the std140 layout
+    pub(crate) emissive: [f32; 4],
+    // metallic, roughness, normal scale, occlusion strength
+    pub(crate) factors: [f32; 4],
+}
+
+// A glTF-style metallic-roughness material.  Each factor multiplies its
+// texture; missing textures count as white, or as a flat normal map.
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub(crate) name: String,
+    pub(crate) base_color: Vec4,
+    pub(crate) metallic: f32,
+    pub(crate) roughness: f32,
+    pub(crate) emissive: Vec3,
+    pub(crate) normal_scale: f32,
+    pub(crate) occlusion_strength: f32,
+    // sRGB
+    pub(crate) base_color_texture: Option<assets::TextureRef>,
+    // linear: roughness in green, metalness in blue
+    pub(crate) metallic_roughness_texture: Option<assets::TextureRef>,
+    // linear, in tangent space
+    pub(crate) normal_texture: Option<assets::TextureRef>,
+    // linear, in red
+    pub(crate) occlusion_texture: Option<assets::TextureRef>,
+    // sRGB
+    pub(crate) emissive_texture: Option<assets::TextureRef>,
+    pub(crate) buffer: Arc<ImmutableBuffer<MaterialData>>,
+}
+impl Material {
+    pub(crate) fn textures(&self) -> impl Iterator<Item = assets::TextureRef> {
+        [
+            self.base_color_texture,
+            self.metallic_roughness_texture,
+            self.normal_texture,
+            self.occlusion_texture,
+            self.emissive_texture,
+        ]
+        .into_iter()
+        .flatten()
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn base_color(&self) -> Vec4 {
+        self.base_color
+    }
+    pub fn metallic(&self) -> f32 {
+        self.metallic
+    }
+    pub fn roughness(&self) -> f32 {
+        self.roughness
+    }
+    pub fn emissive(&self) -> Vec3 {
+        self.emissive
+    }
+    pub fn base_color_texture(&self) -> Option<assets::TextureRef> {
+        self.base_color_texture
+    }
+}
+
+#[derive(Clone)]
+pub struct Model {
+    meshes: Vec<MeshRef<Mesh>>,
+    materials: Vec<MaterialRef<Material>>,
+}
+impl Model {
+    pub(crate) fn new(meshes: Vec<MeshRef<Mesh>>, materials: Vec<MaterialRef<Material>>) -> Self {
+        Self { meshes, materials }
+    }
+    pub fn meshes(&self) -> &[MeshRef<Mesh>] {
+        &self.meshes
+    }
+    pub fn materials(&self) -> &[MaterialRef<Material>] {
+        &self.materials
+    }
+    // Bounding sphere radius around the model's origin, for picking levels of detail
+    pub(crate) fn radius(&self, assets: &assets::Assets) -> f32 {
+        self.meshes
+            .iter()
+            .map(|m| {
+                let b = assets.pbr_mesh(*m).bounds;
+                b.center.mag() + b.radius
+            })
+            .fold(0.0, f32::max)
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct ModelKey(MeshRef<Mesh>, MaterialRef<Material>);
+
+pub struct SingleRenderState {
+    model: Detail<Model>,
+    transform: Similarity3,
+    style: InstanceStyle,
+    outline: Option<Outline>,
+}
+impl SingleRenderState {
+    pub(crate) fn new(model: Detail<Model>, transform: Similarity3) -> Self {
+        Self {
+            model,
+            transform,
+            style: InstanceStyle::default(),
+            outline: None,
+        }
+    }
+    pub fn tint(&mut self, tint: Vec4) -> &mut Self {
+        self.style.tint = tint;
+        self
+    }
+    pub fn params(&mut self, params: Vec4) -> &mut Self {
+        self.style.params = params;
+        self
+    }
+    // Draws an outline around the instance, or stops drawing one
+    pub fn outline(&mut self, outline: Option<Outline>) -> &mut Self {
+        self.outline = outline;
+        self
+    }
+    pub(crate) fn uses_texture(&self, tex: assets::TextureRef, assets: &assets::Assets) -> bool {
+        self.model
+            .models()
+            .iter()
+            .flat_map(|m| m.materials.iter())
+            .any(|&mat| assets.pbr_material(mat).textures().any(|t| t == tex))
+    }
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        Self {
+            model: other.model.clone(),
+            transform: self.transform.lerp(&other.transform, r),
+            style: self.style.interpolate(&other.style, r),
+            outline: match (self.outline, other.outline) {
+                (Some(o0), Some(o1)) => Some(o0.interpolate(&o1, r)),
+                (_, o1) => o1,
+            },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+struct InstanceData {
+    model: [f32; 4 * 4],
+    tint: [f32; 4],
+    params: [f32; 4],
+    // color, and width (0 for no outline)
+    outline: [f32; 4],
+}
+vulkano::impl_vertex!(InstanceData, model, tint, params, outline);
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+struct LightData {
+    // toward the light
+    to_light: [f32; 4],
+    color: [f32; 4],
+    ambient: [f32; 4],
+}
+
+struct BatchData {
+    verts: Arc<ImmutableBuffer<[Vertex]>>,
+    idxs: Arc<ImmutableBuffer<[u32]>>,
+    material_pds: Arc<PersistentDescriptorSet>,
+    // just the base color, for outlines and debug views to cut out holes with
+    cutout_pds: Arc<PersistentDescriptorSet>,
+    instance_data: Vec<InstanceData>,
+    // whether any instance this frame has an outline
+    outlined: bool,
+    instance_buf:
+        Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
+}
+
+pub struct Renderer {
+    pipeline: Arc<GraphicsPipeline>,
+    outline_pipeline: Arc<GraphicsPipeline>,
+    // alternate pipelines for the debug render modes
+    mode_pipelines: HashMap<RenderMode, Arc<GraphicsPipeline>>,
+    mode: RenderMode,
+    uniform_buffers: CpuBufferPool<super::FrameData>,
+    light_buffers: CpuBufferPool<LightData>,
+    uniform_pds: SingleLayoutDescSetPool,
+    uniform_binding: Option<Arc<SingleLayoutDescSet>>,
+    // outlines and debug views go without the light
+    frame_pds: SingleLayoutDescSetPool,
+    frame_binding: Option<Arc<SingleLayoutDescSet>>,
+    instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
+    batches: HashMap<ModelKey, BatchData>,
+    // stand-ins for textures a material doesn't have
+    white: Arc<dyn ImageViewAbstract>,
+    flat_normal: Arc<dyn ImageViewAbstract>,
+    default_sampler: Arc<Sampler>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+// vertex attributes
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec3 normal;
+layout(location = 3) in vec4 tangent;
+// instance data
+layout(location = 4) in mat4 model;
+layout(location = 8) in vec4 tint;
+layout(location = 9) in vec4 params;
+
+// outputs
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec3 out_normal;
+layout(location = 3) out vec4 out_tangent;
+layout(location = 4) flat out vec4 out_tint;
+layout(location = 5) flat out vec4 out_params;
+
+// uniforms
+#include <frame.glsl>
+
+void main() {
+  vec4 world = model * vec4(position, 1.0);
+  gl_Position = viewproj * world;
+  out_uv = uv;
+  out_world = world.xyz;
+  // fine for the uniform scales of a Similarity3
+  out_normal = mat3(model) * normal;
+  out_tangent = vec4(mat3(model) * tangent.xyz, tangent.w);
+  out_tint = tint;
+  out_params = params;
+}
+                "
+            }
+        }
+
+        mod fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                #include <frame.glsl>
+                layout(set = 0, binding = 1) uniform LightData {
+                  vec4 to_light;
+                  vec4 light_color;
+                  vec4 ambient;
+                };
+                layout(set = 1, binding = 0) uniform MaterialData {
+                  vec4 base_color;
+                  vec4 emissive;
+                  // metallic, roughness, normal scale, occlusion strength
+                  vec4 factors;
+                };
+                layout(set = 1, binding = 1) uniform sampler2D base_color_tex;
+                layout(set = 1, binding = 2) uniform sampler2D metallic_roughness_tex;
+                layout(set = 1, binding = 3) uniform sampler2D normal_tex;
+                layout(set = 1, binding = 4) uniform sampler2D occlusion_tex;
+                layout(set = 1, binding = 5) uniform sampler2D emissive_tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec3 normal;
+                layout(location = 3) in vec4 tangent;
+                layout(location = 4) flat in vec4 tint;
+                layout(location = 5) flat in vec4 params;
+                layout(location = 0) out vec4 f_color;
+
+                const float PI = 3.14159265359;
+
+                // ordered dither, so faded instances need no sorting
+                float dither() {
+                    const float bayer[16] = float[16](
+                        0.0, 8.0, 2.0, 10.0, 12.0, 4.0, 14.0, 6.0,
+                        3.0, 11.0, 1.0, 9.0, 15.0, 7.0, 13.0, 5.0);
+                    ivec2 p = ivec2(gl_FragCoord.xy) % 4;
+                    return (bayer[p.y * 4 + p.x] + 0.5) / 16.0;
+                }
+
+                void main() {
+                    vec4 base = base_color * texture(base_color_tex, uv);
+                    if (base.a < 0.1 || tint.a < dither()) { discard; }
+                    base.rgb *= tint.rgb;
+                    vec4 mr = texture(metallic_roughness_tex, uv);
+                    float metallic = clamp(factors.x * mr.b, 0.0, 1.0);
+                    float roughness = clamp(factors.y * mr.g, 0.04, 1.0);
+
+                    vec3 n = normalize(normal);
+                    if (dot(tangent.xyz, tangent.xyz) > 0.000001) {
+                        vec3 t = normalize(tangent.xyz - n * dot(n, tangent.xyz));
+                        vec3 b = cross(n, t) * tangent.w;
+                        vec3 tn = texture(normal_tex, uv).xyz * 2.0 - 1.0;
+                        tn.xy *= factors.z;
+                        n = normalize(mat3(t, b, n) * tn);
+                    }
+                    float ao = mix(1.0, texture(occlusion_tex, uv).r, factors.w);
+
+                    // Cook-Torrance: GGX distribution, Smith-Schlick geometry, Schlick Fresnel
+                    vec3 v = normalize(camera_pos.xyz - world);
+                    vec3 l = normalize(to_light.xyz);
+                    vec3 h = normalize(v + l);
+                    float ndl = max(dot(n, l), 0.0);
+                    float ndv = max(dot(n, v), 0.0001);
+                    float ndh = max(dot(n, h), 0.0);
+                    float vdh = max(dot(v, h), 0.0);
+                    vec3 f0 = mix(vec3(0.04), base.rgb, metallic);
+                    float a = roughness * roughness;
+                    float a2 = a * a;
+                    float dd = ndh * ndh * (a2 - 1.0) + 1.0;
+                    float d = a2 / (PI * dd * dd);
+                    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+                    float g = ndl / (ndl * (1.0 - k) + k) * ndv / (ndv * (1.0 - k) + k);
+                    vec3 f = f0 + (1.0 - f0) * pow(1.0 - vdh, 5.0);
+                    vec3 specular = d * g * f / max(4.0 * ndl * ndv, 0.0001);
+                    vec3 diffuse = (1.0 - f) * (1.0 - metallic) * base.rgb / PI;
+
+                    vec3 col = (diffuse + specular) * light_color.rgb * ndl
+                        + ambient.rgb * base.rgb * ao
+                        + emissive.rgb * texture(emissive_tex, uv).rgb;
+                    f_color = vec4(mix(col, fog_color.rgb * base.a, fog_amount(world)), base.a);
+                }
+            "
+            }
+        }
+
+        mod outline_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec3 normal;
+layout(location = 4) in mat4 model;
+layout(location = 10) in vec4 outline;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) flat out vec3 out_color;
+
+#include <frame.glsl>
+
+void main() {
+  if (outline.w <= 0.0) {
+    // not outlined: put the whole instance outside the clip volume
+    gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+    return;
+  }
+  // push the hull out along the normal, in world units
+  vec3 n = mat3(model) * normal;
+  n /= max(length(n), 0.0001);
+  vec4 world = model * vec4(position, 1.0) + vec4(n * outline.w, 0.0);
+  gl_Position = viewproj * world;
+  out_uv = uv;
+  out_world = world.xyz;
+  out_color = outline.rgb;
+}
+"
+            }
+        }
+
+        mod outline_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform MaterialData {
+                  vec4 base_color;
+                  vec4 emissive;
+                  vec4 factors;
+                };
+                layout(set = 1, binding = 1) uniform sampler2D base_color_tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) flat in vec3 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // cut-out parts of the material don't get outlined
+                    if ((base_color * texture(base_color_tex, uv)).a < 0.1) { discard; }
+                    f_color = vec4(mix(color, fog_color.rgb, fog_amount(world)), 1.0);
+                }
+            "
+            }
+        }
+
+        mod debug_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec3 normal;
+layout(location = 4) in mat4 model;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec3 out_normal;
+layout(location = 3) out vec3 out_color;
+
+#include <frame.glsl>
+
+void main() {
+  vec4 world = model * vec4(position, 1.0);
+  gl_Position = viewproj * world;
+  out_uv = uv;
+  out_world = world.xyz;
+  out_normal = mat3(model) * normal;
+  // no bones to show
+  out_color = vec3(0.5);
+}
+"
+            }
+        }
+
+        mod debug_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                // which view, from RenderMode::shader_mode
+                layout(constant_id = 0) const int mode = 1;
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform MaterialData {
+                  vec4 base_color;
+                  vec4 emissive;
+                  vec4 factors;
+                };
+                layout(set = 1, binding = 1) uniform sampler2D base_color_tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec3 normal;
+                layout(location = 3) in vec3 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    if ((base_color * texture(base_color_tex, uv)).a < 0.1) { discard; }
+                    vec3 n = normal / max(length(normal), 0.0001);
+                    // a headlight, so shapes still read in the flat-colored views
+                    float light = 0.4 + 0.6 * abs(dot(n, normalize(camera_pos.xyz - world)));
+                    if (mode == 1) {
+                        f_color = vec4(n * 0.5 + 0.5, 1.0);
+                    } else if (mode == 2) {
+                        vec2 cell = floor(uv * 8.0);
+                        float check = mod(cell.x + cell.y, 2.0);
+                        f_color = vec4(mix(vec3(0.1), vec3(0.9), check) * light, 1.0);
+                    } else if (mode == 3) {
+                        // blended additively, once for every layer at this pixel
+                        f_color = vec4(0.1, 0.04, 0.01, 1.0);
+                    } else {
+                        f_color = vec4(color * light, 1.0);
+                    }
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let fs = fs::load(vulkan.device.clone()).unwrap();
+        let outline_vs = outline_vs::load(vulkan.device.clone()).unwrap();
+        let outline_fs = outline_fs::load(vulkan.device.clone()).unwrap();
+        let debug_vs = debug_vs::load(vulkan.device.clone()).unwrap();
+        let debug_fs = debug_fs::load(vulkan.device.clone()).unwrap();
+        let pipeline = Self::build_pipeline(
+            vulkan,
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+            (),
+            CullMode::Back,
+            RenderMode::Shaded,
+        );
+        // the hull's back faces, pushed out past the model, show around its edges
+        let outline_pipeline = Self::build_pipeline(
+            vulkan,
+            outline_vs.entry_point("main").unwrap(),
+            outline_fs.entry_point("main").unwrap(),
+            (),
+            CullMode::Front,
+            RenderMode::Shaded,
+        );
+        // wireframe keeps the usual shaders; the other views share the debug ones
+        let mode_pipelines = RenderMode::ALL
+            .into_iter()
+            .filter(|mode| *mode != RenderMode::Shaded)
+            .map(|mode| {
+                let pipeline = if mode == RenderMode::Wireframe {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        fs.entry_point("main").unwrap(),
+                        (),
+                        CullMode::Back,
+                        mode,
+                    )
+                } else {
+                    Self::build_pipeline(
+                        vulkan,
+                        debug_vs.entry_point("main").unwrap(),
+                        debug_fs.entry_point("main").unwrap(),
+                        debug_fs::SpecializationConstants {
+                            mode: mode.shader_mode(),
+                        },
+                        CullMode::Back,
+                        mode,
+                    )
+                };
+                (mode, pipeline)
+            })
+            .collect();
+
+        let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let light_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let uniform_pds =
+            SingleLayoutDescSetPool::new(pipeline.layout().set_layouts().get(0).unwrap().clone());
+        let frame_pds = SingleLayoutDescSetPool::new(
+            outline_pipeline
+                .layout()
+                .set_layouts()
+                .get(0)
+                .unwrap()
+                .clone(),
+        );
+        let instance_pool = CpuBufferPool::vertex_buffer(vulkan.device.clone());
+        let white = Self::solid_texture(vulkan, [255, 255, 255, 255]);
+        let flat_normal = Self::solid_texture(vulkan, [128, 128, 255, 255]);
+        let default_sampler = assets::SamplerSettings::default()
+            .create_sampler(vulkan)
+            .unwrap();
+
+        Self {
+            pipeline,
+            outline_pipeline,
+            mode_pipelines,
+            mode: RenderMode::Shaded,
+            uniform_buffers,
+            light_buffers,
+            uniform_pds,
+            uniform_binding: None,
+            frame_pds,
+            frame_binding: None,
+            instance_pool,
+            batches: HashMap::new(),
+            white,
+            flat_normal,
+            default_sampler,
+        }
+    }
+    fn build_pipeline<S: SpecializationConstants>(
+        vulkan: &Vulkan,
+        vs: EntryPoint,
+        fs: EntryPoint,
+        fs_constants: S,
+        cull_mode: CullMode,
+        mode: RenderMode,
+    ) -> Arc<GraphicsPipeline> {
+        GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<Vertex>()
+                    .instance::<InstanceData>(),
+            )
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, fs_constants)
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(
+                RasterizationState::new()
+                    .cull_mode(cull_mode)
+                    .front_face(
+                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                    )
+                    .polygon_mode(mode.polygon_mode(vulkan)),
+            )
+            .depth_stencil_state(mode.depth_stencil_state())
+            .color_blend_state(mode.color_blend_state())
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    // A 1x1 linear texture
+    fn solid_texture(vulkan: &mut Vulkan, color: [u8; 4]) -> Arc<dyn ImageViewAbstract> {
+        let (img, fut) = ImmutableImage::from_iter(
+            [color].into_iter(),
+            vulkano::image::ImageDimensions::Dim2d {
+                width: 1,
+                height: 1,
+                array_layers: 1,
+            },
+            vulkano::image::MipmapsCount::One,
+            vulkano::format::Format::R8G8B8A8_UNORM,
+            vulkan.queue.clone(),
+        )
+        .unwrap();
+        vulkan.wait_for(Box::new(fut));
+        ImageView::new_default(img).unwrap()
+    }
+    fn create_batch(&self, mesh: &Mesh, material: &Material, assets: &assets::Assets) -> BatchData {
+        let tex =
+            |binding: u32, t: Option<assets::TextureRef>, fallback: &Arc<dyn ImageViewAbstract>| {
+                match t {
+                    Some(t) => {
+                        let t = assets.texture(t);
+                        WriteDescriptorSet::image_view_sampler(
+                            binding,
+                            t.view.clone(),
+                            t.sampler.clone(),
+                        )
+                    }
+                    None => WriteDescriptorSet::image_view_sampler(
+                        binding,
+                        fallback.clone(),
+                        self.default_sampler.clone(),
+                    ),
+                }
+            };
+        BatchData {
+            verts: mesh.verts.clone(),
+            idxs: mesh.idx.clone(),
+            instance_data: vec![],
+            outlined: false,
+            instance_buf: None,
+            cutout_pds: PersistentDescriptorSet::new(
+                self.outline_pipeline
+                    .layout()
+                    .set_layouts()
+                    .get(1)
+                    .unwrap()
+                    .clone(),
+                [
+                    WriteDescriptorSet::buffer(0, material.buffer.clone()),
+                    tex(1, material.base_color_texture, &self.white),
+                ],
+            )
+            .unwrap(),
+            material_pds: PersistentDescriptorSet::new(
+                self.pipeline.layout().set_layouts().get(1).unwrap().clone(),
+                [
+                    WriteDescriptorSet::buffer(0, material.buffer.clone()),
+                    tex(1, material.base_color_texture, &self.white),
+                    tex(2, material.metallic_roughness_texture, &self.white),
+                    tex(3, material.normal_texture, &self.flat_normal),
+                    tex(4, material.occlusion_texture, &self.white),
+                    tex(5, material.emissive_texture, &self.white),
+                ],
+            )
+            .unwrap(),
+        }
+    }
+    pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        let frustum = camera.frustum();
+        for v in rs.pbr.values() {
+            let (model, _) = v.model.select(camera, v.transform, |m| m.radius(assets));
+            for (meshr, matr) in model.meshes.iter().zip(model.materials.iter()) {
+                let mesh = assets.pbr_mesh(*meshr);
+                let (center, radius) = mesh.bounds.sphere(v.transform);
+                if !frustum.contains_sphere(center, radius) {
+                    continue;
+                }
+                let inst = InstanceData {
+                    model: *v.transform.into_homogeneous_matrix().as_array(),
+                    tint: v.style.tint.into(),
+                    params: v.style.params.into(),
+                    outline: match v.outline {
+                        Some(o) => [o.color.x, o.color.y, o.color.z, o.width],
+                        None => [0.0; 4],
+                    },
+                };
+                let key = ModelKey(*meshr, *matr);
+                if !self.batches.contains_key(&key) {
+                    let b = self.create_batch(mesh, assets.pbr_material(*matr), assets);
+                    self.batches.insert(key, b);
+                }
+                self.batches.get_mut(&key).unwrap().push_instance(inst);
+            }
+        }
+        let frame = self
+            .uniform_buffers
+            .next(super::FrameData::new(camera, rs.fog))
+            .unwrap();
+        let light = self
+            .light_buffers
+            .next(LightData {
+                to_light: (-rs.light.direction.normalized())
+                    .into_homogeneous_vector()
+                    .into(),
+                color: rs.light.color.into_homogeneous_vector().into(),
+                ambient: rs.light.ambient.into_homogeneous_vector().into(),
+            })
+            .unwrap();
+        let uds = self
+            .uniform_pds
+            .next(vec![
+                WriteDescriptorSet::buffer(0, frame.clone()),
+                WriteDescriptorSet::buffer(1, light),
+            ])
+            .unwrap();
+        self.uniform_binding = Some(uds);
+        let fds = self
+            .frame_pds
+            .next(vec![WriteDescriptorSet::buffer(0, frame)])
+            .unwrap();
+        self.frame_binding = Some(fds);
+        self.mode = rs.render_mode;
+        // batches whose instances were all culled this frame have nothing to upload
+        for b in self.batches.values_mut().filter(|b| !b.is_empty()) {
+            b.prepare_draw(&self.instance_pool);
+        }
+    }
+    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        if self.batches.values().all(|b| b.is_empty()) {
+            self.clear_frame();
+            return;
+        }
+        let uds = self.uniform_binding.clone().unwrap();
+        let fds = self.frame_binding.clone().unwrap();
+        let pipeline = self
+            .mode_pipelines
+            .get(&self.mode)
+            .unwrap_or(&self.pipeline)
+            .clone();
+        // wireframe still shades the material; the other views only cut out holes
+        let shaded = matches!(self.mode, RenderMode::Shaded | RenderMode::Wireframe);
+        builder.bind_pipeline_graphics(pipeline.clone());
+        for dat in self.batches.values().filter(|dat| !dat.is_empty()) {
+            if shaded {
+                dat.draw(
+                    pipeline.clone(),
+                    uds.clone(),
+                    dat.material_pds.clone(),
+                    builder,
+                );
+            } else {
+                dat.draw(
+                    pipeline.clone(),
+                    fds.clone(),
+                    dat.cutout_pds.clone(),
+                    builder,
+                );
+            }
+        }
+        // outlined batches get drawn again, with instances that aren't outlined clipped away
+        if self.mode == RenderMode::Shaded && self.batches.values().any(|b| b.outlined) {
+            builder.bind_pipeline_graphics(self.outline_pipeline.clone());
+            for dat in self.batches.values().filter(|dat| dat.outlined) {
+                dat.draw(
+                    self.outline_pipeline.clone(),
+                    fds.clone(),
+                    dat.cutout_pds.clone(),
+                    builder,
+                );
+            }
+        }
+        self.clear_frame();
+    }
+    fn clear_frame(&mut self) {
+        // delete batch data for objects that didn't get rendered this frame
+        self.batches.retain(|_k, v| !v.is_empty());
+        // delete instance data from each batch, but don't throw away the vecs' allocations
+        self.batches.values_mut().for_each(|v| v.clear_frame());
+    }
+}
+
+impl BatchData {
+    fn prepare_draw(
+        &mut self,
+        instance_pool: &CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
+    ) {
+        self.instance_buf = Some(
+            instance_pool
+                .chunk(self.instance_data.iter().copied())
+                .unwrap(),
+        );
+    }
+    fn draw<P, L>(
+        &self,
+        pipeline: Arc<GraphicsPipeline>,
+        unis: Arc<SingleLayoutDescSet>,
+        material: Arc<PersistentDescriptorSet>,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+    ) {
+        builder
+            .bind_vertex_buffers(0, (self.verts.clone(), self.instance_buf.clone().unwrap()))
+            .bind_index_buffer(self.idxs.clone())
+            .bind_descriptor_sets(
+                vulkano::pipeline::PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                0,
+                unis,
+            )
+            .bind_descriptor_sets(
+                vulkano::pipeline::PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                1,
+                material,
+            )
+            .draw_indexed(
+                self.idxs.len() as u32,
+                self.instance_data.len() as u32,
+                0,
+                0,
+                0,
+            )
+            .unwrap();
+    }
+    fn clear_frame(&mut self) {
+        self.instance_data.clear();
+        self.outlined = false;
+    }
+    fn is_empty(&self) -> bool {
+        self.instance_data.is_empty()
+    }
+    fn push_instance(&mut self, inst: InstanceData) {
+        self.outlined |= inst.outline[3] > 0.0;
+        self.instance_data.push(inst);
+    }
+}
@@ -0,0 +1,564 @@
+use crate::assets;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use std::sync::Arc;
+use vulkano::command_buffer::{
+    AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, SubpassContents,
+};
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::format::ClearValue;
+use vulkano::image::view::ImageViewAbstract;
+use vulkano::image::ImageAccess;
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::{Viewport, ViewportState};
+use vulkano::pipeline::{GraphicsPipeline, Pipeline, PipelineBindPoint};
+use vulkano::render_pass::{Framebuffer, RenderPass, Subpass};
+use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo};
+use vulkano::shader::EntryPoint;
+
+// Full-screen passes run over the finished scene, in the order given.
+// The HUD is part of the scene, so they apply to it too.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PostEffect {
+    // Scales linear color by `exposure` and maps it into 0..1 with a filmic (ACES) curve
+    Tonemap {
+        exposure: f32,
+    },
+    // Blurs whatever is brighter than `threshold` and adds it back, scaled by `intensity`.
+    // Goes before Tonemap, while colors can still be brighter than 1.
+    Bloom {
+        threshold: f32,
+        intensity: f32,
+    },
+    // Remaps colors through a LUT image of N slices of N by N, side by side
+    // with blue increasing to the right, as loaded by `load_texture`.
+    // `strength` blends from the original colors (0) to the graded ones (1).
+    ColorGrade {
+        lut: assets::TextureRef,
+        strength: f32,
+    },
+    // Darkens by up to `strength` towards the corners, starting `radius` of
+    // the way out from the center
+    Vignette {
+        strength: f32,
+        radius: f32,
+    },
+    // Smooths jagged edges; goes last, after Tonemap
+    Fxaa,
+}
+
+// Bloom is blurred at half resolution this many times each way
+const BLOOM_BLURS: usize = 3;
+
+// `params` means something different to each pass; `texel` is the size
+// of one texel of the first input in UV units
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod, Debug, PartialEq)]
+struct Params {
+    params: [f32; 4],
+    texel: [f32; 4],
+}
+
+// Intermediate images, recreated when the window changes size
+struct Targets {
+    size: [u32; 2],
+    // effects ping-pong between these
+    full: [(Arc<dyn ImageViewAbstract>, Arc<Framebuffer>); 2],
+    // bloom's bright parts get blurred back and forth between these
+    half: [(Arc<dyn ImageViewAbstract>, Arc<Framebuffer>); 2],
+}
+
+pub struct Renderer {
+    output_pipeline: Arc<GraphicsPipeline>,
+    tonemap_pipeline: Arc<GraphicsPipeline>,
+    bright_pipeline: Arc<GraphicsPipeline>,
+    blur_pipeline: Arc<GraphicsPipeline>,
+    combine_pipeline: Arc<GraphicsPipeline>,
+    grade_pipeline: Arc<GraphicsPipeline>,
+    vignette_pipeline: Arc<GraphicsPipeline>,
+    fxaa_pipeline: Arc<GraphicsPipeline>,
+    sampler: Arc<Sampler>,
+    targets: Option<Targets>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                src: "
+#version 450
+
+// no vertex attributes; one triangle covers the screen
+layout(location = 0) out vec2 out_uv;
+
+void main() {
+  out_uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+  gl_Position = vec4(out_uv * 2.0 - 1.0, 0.0, 1.0);
+}
+"
+            }
+        }
+
+        mod output_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // without a Tonemap effect, anything too bright just clips
+                    f_color = clamp(texture(src, uv), 0.0, 1.0);
+                }
+            "
+            }
+        }
+
+        mod tonemap_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(push_constant) uniform Params {
+                    vec4 params;
+                    vec4 texel;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                // Narkowicz's fit of the ACES filmic curve
+                vec3 aces(vec3 x) {
+                    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+                }
+
+                void main() {
+                    vec4 c = texture(src, uv);
+                    f_color = vec4(aces(c.rgb * params.x), c.a);
+                }
+            "
+            }
+        }
+
+        mod bright_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(push_constant) uniform Params {
+                    vec4 params;
+                    vec4 texel;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // drawn at half size, so each lookup averages four texels
+                    vec3 c = texture(src, uv).rgb;
+                    float brightest = max(c.r, max(c.g, c.b));
+                    float over = max(brightest - params.x, 0.0);
+                    f_color = vec4(c * over / max(brightest, 0.0001), 1.0);
+                }
+            "
+            }
+        }
+
+        mod blur_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(push_constant) uniform Params {
+                    vec4 params;
+                    vec4 texel;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // a 9-tap gaussian along params.xy, in five linearly filtered lookups
+                    vec2 d = params.xy * texel.xy;
+                    vec3 c = texture(src, uv).rgb * 0.2270270270;
+                    c += texture(src, uv + d * 1.3846153846).rgb * 0.3162162162;
+                    c += texture(src, uv - d * 1.3846153846).rgb * 0.3162162162;
+                    c += texture(src, uv + d * 3.2307692308).rgb * 0.0702702703;
+                    c += texture(src, uv - d * 3.2307692308).rgb * 0.0702702703;
+                    f_color = vec4(c, 1.0);
+                }
+            "
+            }
+        }
+
+        mod combine_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(set = 0, binding = 1) uniform sampler2D bloom;
+                layout(push_constant) uniform Params {
+                    vec4 params;
+                    vec4 texel;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    vec4 c = texture(src, uv);
+                    f_color = vec4(c.rgb + texture(bloom, uv).rgb * params.x, c.a);
+                }
+            "
+            }
+        }
+
+        mod grade_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(set = 0, binding = 1) uniform sampler2D lut;
+                layout(push_constant) uniform Params {
+                    vec4 params;
+                    vec4 texel;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                vec3 to_srgb(vec3 c) {
+                    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, c));
+                }
+
+                void main() {
+                    vec4 c = texture(src, uv);
+                    // LUTs are indexed by the colors people see, i.e. sRGB;
+                    // the texture is sRGB too, so lookups come back linear
+                    vec3 s = to_srgb(clamp(c.rgb, 0.0, 1.0));
+                    float n = params.y;
+                    float b = s.b * (n - 1.0);
+                    float b0 = floor(b);
+                    float b1 = min(b0 + 1.0, n - 1.0);
+                    // stay on texel centers so neighboring slices don't bleed in
+                    vec2 rg = (s.rg * (n - 1.0) + 0.5) / vec2(n * n, n);
+                    vec3 g0 = textureLod(lut, rg + vec2(b0 / n, 0.0), 0.0).rgb;
+                    vec3 g1 = textureLod(lut, rg + vec2(b1 / n, 0.0), 0.0).rgb;
+                    vec3 graded = mix(g0, g1, b - b0);
+                    f_color = vec4(mix(c.rgb, graded, params.x), c.a);
+                }
+            "
+            }
+        }
+
+        mod vignette_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(push_constant) uniform Params {
+                    vec4 params;
+                    vec4 texel;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    vec4 c = texture(src, uv);
+                    // round rather than stretched to the screen's shape,
+                    // with 0 at the center and 1 in the corners
+                    vec2 aspect = vec2(texel.y / texel.x, 1.0);
+                    float dist = length((uv - 0.5) * aspect) / length(0.5 * aspect);
+                    float dark = params.x * smoothstep(params.y, 1.0, dist);
+                    f_color = vec4(c.rgb * (1.0 - dark), c.a);
+                }
+            "
+            }
+        }
+
+        mod fxaa_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 0, binding = 0) uniform sampler2D src;
+                layout(push_constant) uniform Params {
+                    vec4 params;
+                    vec4 texel;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 0) out vec4 f_color;
+
+                const float SPAN_MAX = 8.0;
+                const float REDUCE_MUL = 1.0 / 8.0;
+                const float REDUCE_MIN = 1.0 / 128.0;
+
+                // on roughly gamma-encoded values, which edges are judged by
+                float luma(vec3 c) {
+                    return dot(sqrt(clamp(c, 0.0, 1.0)), vec3(0.299, 0.587, 0.114));
+                }
+
+                void main() {
+                    vec2 t = texel.xy;
+                    vec4 m = texture(src, uv);
+                    float nw = luma(texture(src, uv + vec2(-1.0, -1.0) * t).rgb);
+                    float ne = luma(texture(src, uv + vec2(1.0, -1.0) * t).rgb);
+                    float sw = luma(texture(src, uv + vec2(-1.0, 1.0) * t).rgb);
+                    float se = luma(texture(src, uv + vec2(1.0, 1.0) * t).rgb);
+                    float lm = luma(m.rgb);
+                    float lmin = min(lm, min(min(nw, ne), min(sw, se)));
+                    float lmax = max(lm, max(max(nw, ne), max(sw, se)));
+                    // blur along the edge, i.e. across the luma gradient's normal
+                    vec2 dir = vec2(-((nw + ne) - (sw + se)), (nw + sw) - (ne + se));
+                    float reduce = max((nw + ne + sw + se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+                    float rcp_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + reduce);
+                    dir = clamp(dir * rcp_min, vec2(-SPAN_MAX), vec2(SPAN_MAX)) * t;
+                    vec3 a = 0.5 * (texture(src, uv + dir * (1.0 / 3.0 - 0.5)).rgb
+                                    + texture(src, uv + dir * (2.0 / 3.0 - 0.5)).rgb);
+                    vec3 b = a * 0.5 + 0.25 * (texture(src, uv - dir * 0.5).rgb
+                                               + texture(src, uv + dir * 0.5).rgb);
+                    float lb = luma(b);
+                    // the wider blur went past the edge; use the narrow one
+                    f_color = vec4((lb < lmin || lb > lmax) ? a : b, m.a);
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let vs = vs.entry_point("main").unwrap();
+        let output_fs = output_fs::load(vulkan.device.clone()).unwrap();
+        let tonemap_fs = tonemap_fs::load(vulkan.device.clone()).unwrap();
+        let bright_fs = bright_fs::load(vulkan.device.clone()).unwrap();
+        let blur_fs = blur_fs::load(vulkan.device.clone()).unwrap();
+        let combine_fs = combine_fs::load(vulkan.device.clone()).unwrap();
+        let grade_fs = grade_fs::load(vulkan.device.clone()).unwrap();
+        let vignette_fs = vignette_fs::load(vulkan.device.clone()).unwrap();
+        let fxaa_fs = fxaa_fs::load(vulkan.device.clone()).unwrap();
+        let post = |fs: EntryPoint| {
+            Self::build_pipeline(vulkan, vulkan.post_render_pass.clone(), vs.clone(), fs)
+        };
+        let tonemap_pipeline = post(tonemap_fs.entry_point("main").unwrap());
+        let bright_pipeline = post(bright_fs.entry_point("main").unwrap());
+        let blur_pipeline = post(blur_fs.entry_point("main").unwrap());
+        let combine_pipeline = post(combine_fs.entry_point("main").unwrap());
+        let grade_pipeline = post(grade_fs.entry_point("main").unwrap());
+        let vignette_pipeline = post(vignette_fs.entry_point("main").unwrap());
+        let fxaa_pipeline = post(fxaa_fs.entry_point("main").unwrap());
+        let output_pipeline = Self::build_pipeline(
+            vulkan,
+            vulkan.output_render_pass.clone(),
+            vs,
+            output_fs.entry_point("main").unwrap(),
+        );
+        let sampler = Sampler::new(
+            vulkan.device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        Self {
+            output_pipeline,
+            tonemap_pipeline,
+            bright_pipeline,
+            blur_pipeline,
+            combine_pipeline,
+            grade_pipeline,
+            vignette_pipeline,
+            fxaa_pipeline,
+            sampler,
+            targets: None,
+        }
+    }
+    fn build_pipeline(
+        vulkan: &Vulkan,
+        render_pass: Arc<RenderPass>,
+        vs: EntryPoint,
+        fs: EntryPoint,
+    ) -> Arc<GraphicsPipeline> {
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new())
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, ())
+            .rasterization_state(RasterizationState::new())
+            .render_pass(Subpass::from(render_pass, 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    fn prepare_targets(&mut self, vulkan: &Vulkan) {
+        let size = vulkan.scene.image().dimensions().width_height();
+        if self.targets.as_ref().map(|t| t.size) == Some(size) {
+            return;
+        }
+        let half = [(size[0] / 2).max(1), (size[1] / 2).max(1)];
+        self.targets = Some(Targets {
+            size,
+            full: [
+                vulkan.create_post_target(size),
+                vulkan.create_post_target(size),
+            ],
+            half: [
+                vulkan.create_post_target(half),
+                vulkan.create_post_target(half),
+            ],
+        });
+    }
+    // Runs `effects` over the scene and writes the result into output framebuffer `image_num`
+    pub fn draw(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        vulkan: &Vulkan,
+        assets: &assets::Assets,
+        effects: &[PostEffect],
+        image_num: usize,
+    ) {
+        self.prepare_targets(vulkan);
+        let targets = self.targets.as_ref().unwrap();
+        let mut src = vulkan.scene.clone();
+        for (i, effect) in effects.iter().enumerate() {
+            let (dst, dst_fb) = targets.full[i % 2].clone();
+            match *effect {
+                PostEffect::Tonemap { exposure } => self.pass(
+                    builder,
+                    &self.tonemap_pipeline,
+                    dst_fb,
+                    [src],
+                    [exposure, 0.0],
+                ),
+                PostEffect::Bloom {
+                    threshold,
+                    intensity,
+                } => {
+                    let [(a, a_fb), (b, b_fb)] = targets.half.clone();
+                    self.pass(
+                        builder,
+                        &self.bright_pipeline,
+                        a_fb.clone(),
+                        [src.clone()],
+                        [threshold, 0.0],
+                    );
+                    for _ in 0..BLOOM_BLURS {
+                        self.pass(
+                            builder,
+                            &self.blur_pipeline,
+                            b_fb.clone(),
+                            [a.clone()],
+                            [1.0, 0.0],
+                        );
+                        self.pass(
+                            builder,
+                            &self.blur_pipeline,
+                            a_fb.clone(),
+                            [b.clone()],
+                            [0.0, 1.0],
+                        );
+                    }
+                    self.pass(
+                        builder,
+                        &self.combine_pipeline,
+                        dst_fb,
+                        [src, a],
+                        [intensity, 0.0],
+                    );
+                }
+                PostEffect::ColorGrade { lut, strength } => {
+                    let lut = assets.texture(lut);
+                    self.pass(
+                        builder,
+                        &self.grade_pipeline,
+                        dst_fb,
+                        [src, lut.view.clone()],
+                        [strength, lut.size.1 as f32],
+                    );
+                }
+                PostEffect::Vignette { strength, radius } => self.pass(
+                    builder,
+                    &self.vignette_pipeline,
+                    dst_fb,
+                    [src],
+                    [strength, radius],
+                ),
+                PostEffect::Fxaa => {
+                    self.pass(builder, &self.fxaa_pipeline, dst_fb, [src], [0.0, 0.0])
+                }
+            }
+            src = dst;
+        }
+        self.pass(
+            builder,
+            &self.output_pipeline,
+            vulkan.framebuffers[image_num].clone(),
+            [src],
+            [0.0, 0.0],
+        );
+    }
+    // Draws one full-screen triangle into `framebuffer`, sampling `inputs`
+    // at bindings 0, 1, ...
+    fn pass<const N: usize>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: &Arc<GraphicsPipeline>,
+        framebuffer: Arc<Framebuffer>,
+        inputs: [Arc<dyn ImageViewAbstract>; N],
+        params: [f32; 2],
+    ) {
+        let [w, h] = framebuffer.extent();
+        let [sw, sh] = inputs[0].image().dimensions().width_height();
+        let layout = pipeline.layout().clone();
+        // inputs change whenever the effects do, so these aren't kept around
+        let set = PersistentDescriptorSet::new(
+            layout.set_layouts().get(0).unwrap().clone(),
+            inputs.into_iter().enumerate().map(|(i, view)| {
+                WriteDescriptorSet::image_view_sampler(i as u32, view, self.sampler.clone())
+            }),
+        )
+        .unwrap();
+        builder
+            .begin_render_pass(framebuffer, SubpassContents::Inline, [ClearValue::None])
+            .unwrap()
+            .set_viewport(
+                0,
+                [Viewport {
+                    origin: [0.0, 0.0],
+                    dimensions: [w as f32, h as f32],
+                    depth_range: 0.0..1.0,
+                }],
+            )
+            .bind_pipeline_graphics(pipeline.clone())
+            .bind_descriptor_sets(PipelineBindPoint::Graphics, layout.clone(), 0, set);
+        // the output pass doesn't read any parameters
+        if !layout.push_constant_ranges().is_empty() {
+            builder.push_constants(
+                layout,
+                0,
+                Params {
+                    params: [params[0], params[1], 0.0, 0.0],
+                    texel: [1.0 / sw as f32, 1.0 / sh as f32, 0.0, 0.0],
+                },
+            );
+        }
+        builder.draw(3, 1, 0, 0).unwrap();
+        builder.end_render_pass().unwrap();
+    }
+}
@@ -1,4 +1,6 @@
+use super::lod::Detail;
 use super::RenderState;
+use super::{InstanceStyle, Outline, RenderMode};
 use crate::animation;
 use crate::assets;
 use crate::assets::Texture;
@@ -7,7 +9,6 @@ use crate::types::*;
 use crate::vulkan::Vulkan;
 use bytemuck::{Pod, Zeroable};
 use std::collections::HashMap;
-use std::rc::Rc;
 use std::sync::Arc;
 use vulkano::buffer::cpu_pool::{CpuBufferPoolChunk, CpuBufferPoolSubbuffer};
 use vulkano::buffer::CpuBufferPool;
@@ -18,13 +19,13 @@ use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
 use vulkano::descriptor_set::PersistentDescriptorSet;
 use vulkano::descriptor_set::SingleLayoutDescSetPool;
 use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
-use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::rasterization::{CullMode, RasterizationState};
 use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
 use vulkano::pipeline::graphics::viewport::ViewportState;
 use vulkano::pipeline::GraphicsPipeline;
 use vulkano::pipeline::Pipeline;
 use vulkano::render_pass::Subpass;
-use vulkano::sampler::Sampler;
+use vulkano::shader::{EntryPoint, SpecializationConstants};
 
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod, Zeroable)]
@@ -34,19 +35,25 @@ pub struct Vertex {
     // Really, four u8 values
     pub bone_ids: u32,
     pub bone_weights: [f32; 4],
+    pub normal: [f32; 3],
 }
-vulkano::impl_vertex!(Vertex, position, uv, bone_ids, bone_weights);
+vulkano::impl_vertex!(Vertex, position, uv, bone_ids, bone_weights, normal);
 pub struct Mesh {
     pub mesh: russimp::mesh::Mesh,
     pub rig: animation::Rig,
     pub verts: Arc<ImmutableBuffer<[Vertex]>>,
     pub idx: Arc<ImmutableBuffer<[u32]>>,
+    pub bounds: Bounds,
 }
 impl Mesh {
     pub fn bone_count(&self) -> usize {
         self.rig.joints.len()
     }
 }
+// Mesh bounds come from the bind pose, and animation can carry limbs
+// past them, so skinned bounding spheres are grown by this much.
+const POSE_BOUNDS_SLACK: f32 = 1.5;
+
 #[derive(Clone)]
 pub struct Model {
     meshes: Vec<assets::MeshRef<Mesh>>,
@@ -59,19 +66,31 @@ impl Model {
     ) -> Self {
         Self { meshes, textures }
     }
+    // Bounding sphere radius around the model's origin, for picking levels of detail
+    pub(crate) fn radius(&self, assets: &assets::Assets) -> f32 {
+        self.meshes
+            .iter()
+            .map(|m| {
+                let b = assets.skinned_mesh(*m).bounds;
+                b.center.mag() + b.radius
+            })
+            .fold(0.0, f32::max)
+    }
 }
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) struct ModelKey(assets::MeshRef<Mesh>, assets::TextureRef);
 
 pub struct SingleRenderState {
-    model: Rc<Model>,
+    model: Detail<Model>,
     transform: Similarity3,
     animation: assets::AnimRef,
     state: animation::AnimationState,
+    style: InstanceStyle,
+    outline: Option<Outline>,
 }
 impl SingleRenderState {
     pub(crate) fn new(
-        model: Rc<Model>,
+        model: Detail<Model>,
         animation: assets::AnimRef,
         state: animation::AnimationState,
         transform: Similarity3,
@@ -81,14 +100,40 @@ impl SingleRenderState {
             animation,
             state,
             transform,
+            style: InstanceStyle::default(),
+            outline: None,
         }
     }
+    pub fn tint(&mut self, tint: Vec4) -> &mut Self {
+        self.style.tint = tint;
+        self
+    }
+    pub fn params(&mut self, params: Vec4) -> &mut Self {
+        self.style.params = params;
+        self
+    }
+    // Draws an outline around the instance, or stops drawing one
+    pub fn outline(&mut self, outline: Option<Outline>) -> &mut Self {
+        self.outline = outline;
+        self
+    }
+    pub(crate) fn uses_texture(&self, tex: assets::TextureRef) -> bool {
+        self.model
+            .models()
+            .iter()
+            .any(|m| m.textures.contains(&tex))
+    }
     pub fn interpolate(&self, other: &Self, r: f32) -> Self {
         Self {
             model: other.model.clone(),
             transform: self.transform.lerp(&other.transform, r),
             animation: other.animation,
             state: self.state.interpolate(&other.state, r),
+            style: self.style.interpolate(&other.style, r),
+            outline: match (self.outline, other.outline) {
+                (Some(o0), Some(o1)) => Some(o0.interpolate(&o1, r)),
+                (_, o1) => o1,
+            },
         }
     }
 }
@@ -97,8 +142,12 @@ impl SingleRenderState {
 #[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
 struct InstanceData {
     model: [f32; 4 * 4],
+    tint: [f32; 4],
+    params: [f32; 4],
+    // color, and width (0 for no outline)
+    outline: [f32; 4],
 }
-vulkano::impl_vertex!(InstanceData, model);
+vulkano::impl_vertex!(InstanceData, model, tint, params, outline);
 
 struct BatchData {
     verts: Arc<ImmutableBuffer<[Vertex]>>,
@@ -106,6 +155,8 @@ struct BatchData {
     material_pds: Arc<vulkano::descriptor_set::PersistentDescriptorSet>,
     bone_ds: Option<Arc<vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet>>,
     instance_data: Vec<InstanceData>,
+    // whether any instance this frame has an outline
+    outlined: bool,
     bones: Vec<animation::Bone>,
     instance_buf:
         Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
@@ -116,10 +167,13 @@ struct BatchData {
 
 pub struct Renderer {
     pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
-    sampler: Arc<Sampler>,
+    outline_pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    // alternate pipelines for the debug render modes
+    mode_pipelines: HashMap<RenderMode, Arc<vulkano::pipeline::GraphicsPipeline>>,
+    mode: RenderMode,
     // we'll use one uniform buffer across all batches.
-    // it will be the projection-view transform.
-    uniform_buffers: CpuBufferPool<Mat4>,
+    // it will be the projection-view transform and fog settings.
+    uniform_buffers: CpuBufferPool<super::FrameData>,
     uniform_pds: SingleLayoutDescSetPool,
     bone_count_buffers: CpuBufferPool<u32>,
     storage_buffers: CpuBufferPool<animation::Bone>,
@@ -134,6 +188,7 @@ impl Renderer {
         mod vs {
             vulkano_shaders::shader! {
                 ty: "vertex",
+                include: ["src/shaders"],
                 src: "
 #version 450
 
@@ -144,12 +199,17 @@ layout(location = 2) in uint bone_ids;
 layout(location = 3) in vec4 bone_weights;
 // instance data
 layout(location = 4) in mat4 model;
+layout(location = 8) in vec4 tint;
+layout(location = 9) in vec4 params;
 
 // outputs
 layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) flat out vec4 out_tint;
+layout(location = 3) flat out vec4 out_params;
 
 // uniforms
-layout(set=0, binding=0) uniform BatchData { mat4 viewproj; };
+#include <frame.glsl>
 struct Bone {
   vec4 pos;
   vec4 rot;
@@ -177,9 +237,13 @@ void main() {
     vec3 disp = bone_dat.pos.xyz;
     new_vertex += (quat_rot(rot, position)*scale + disp)*weight;
   }
-  gl_Position = viewproj * model * vec4(new_vertex.xyz, 1.0);
+  vec4 world = model * vec4(new_vertex.xyz, 1.0);
+  gl_Position = viewproj * world;
   //gl_Position = viewproj * model * vec4(position.xyz, 1.0);
   out_uv = uv;
+  out_world = world.xyz;
+  out_tint = tint;
+  out_params = params;
 }
                 "
             }
@@ -188,18 +252,218 @@ void main() {
         mod fs {
             vulkano_shaders::shader! {
                 ty: "fragment",
+                include: ["src/shaders"],
                 src: "
                 #version 450
 
+                #include <frame.glsl>
                 layout(set = 2, binding = 0) uniform sampler2D tex;
                 layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) flat in vec4 tint;
+                layout(location = 3) flat in vec4 params;
                 layout(location = 0) out vec4 f_color;
 
+                // ordered dither, so faded instances need no sorting
+                float dither() {
+                    const float bayer[16] = float[16](
+                        0.0, 8.0, 2.0, 10.0, 12.0, 4.0, 14.0, 6.0,
+                        3.0, 11.0, 1.0, 9.0, 15.0, 7.0, 13.0, 5.0);
+                    ivec2 p = ivec2(gl_FragCoord.xy) % 4;
+                    return (bayer[p.y * 4 + p.x] + 0.5) / 16.0;
+                }
+
                 void main() {
                     vec4 col = texture(tex, uv);
                     //col = vec4(1.0, 1.0, 0.0, 1.0);
-                    if (col.a < 0.1) { discard; }
-                    f_color = col;
+                    if (col.a < 0.1 || tint.a < dither()) { discard; }
+                    col.rgb *= tint.rgb;
+                    f_color = vec4(mix(col.rgb, fog_color.rgb * col.a, fog_amount(world)), col.a);
+                }
+            "
+            }
+        }
+
+        mod outline_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in uint bone_ids;
+layout(location = 3) in vec4 bone_weights;
+layout(location = 10) in vec3 normal;
+layout(location = 4) in mat4 model;
+layout(location = 11) in vec4 outline;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) flat out vec3 out_color;
+
+#include <frame.glsl>
+struct Bone {
+  vec4 pos;
+  vec4 rot;
+};
+layout(set = 1, binding = 0) uniform BoneCount { uint bone_count; };
+layout(std430, set = 1, binding = 1) buffer Bones { Bone bones[]; };
+
+// rotate a vector by a quaternion.
+vec3 quat_rot(vec4 q, vec3 v) {
+  return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+void main() {
+  if (outline.w <= 0.0) {
+    // not outlined: put the whole instance outside the clip volume
+    gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+    return;
+  }
+  uint first_bone = gl_InstanceIndex * bone_count;
+  vec3 new_vertex = vec3(0,0,0);
+  vec3 new_normal = vec3(0,0,0);
+  // pose the normal along with the vertex, same as the main shader
+  for (int idx=0; idx < 4; idx++) {
+    int bone = int(bone_ids >> (8*(3-idx)) & 0x000000FF);
+    float weight = bone_weights[idx];
+    Bone bone_dat = bones[first_bone+bone];
+    new_vertex += (quat_rot(bone_dat.rot, position)*bone_dat.pos.w + bone_dat.pos.xyz)*weight;
+    new_normal += quat_rot(bone_dat.rot, normal)*weight;
+  }
+  // push the hull out along the normal, in world units
+  vec3 n = mat3(model) * new_normal;
+  n /= max(length(n), 0.0001);
+  vec4 world = model * vec4(new_vertex, 1.0) + vec4(n * outline.w, 0.0);
+  gl_Position = viewproj * world;
+  out_uv = uv;
+  out_world = world.xyz;
+  out_color = outline.rgb;
+}
+"
+            }
+        }
+
+        mod outline_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                #include <frame.glsl>
+                layout(set = 2, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) flat in vec3 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // cut-out parts of the texture don't get outlined
+                    if (texture(tex, uv).a < 0.1) { discard; }
+                    f_color = vec4(mix(color, fog_color.rgb, fog_amount(world)), 1.0);
+                }
+            "
+            }
+        }
+
+        mod debug_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in uint bone_ids;
+layout(location = 3) in vec4 bone_weights;
+layout(location = 10) in vec3 normal;
+layout(location = 4) in mat4 model;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec3 out_normal;
+layout(location = 3) out vec3 out_color;
+
+#include <frame.glsl>
+struct Bone {
+  vec4 pos;
+  vec4 rot;
+};
+layout(set = 1, binding = 0) uniform BoneCount { uint bone_count; };
+layout(std430, set = 1, binding = 1) buffer Bones { Bone bones[]; };
+
+// rotate a vector by a quaternion.
+vec3 quat_rot(vec4 q, vec3 v) {
+  return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+// an arbitrary but stable color for each bone
+vec3 bone_color(int bone) {
+  return fract(vec3(0.13, 0.47, 0.71) + float(bone) * vec3(0.618, 0.382, 0.236));
+}
+
+void main() {
+  uint first_bone = gl_InstanceIndex * bone_count;
+  vec3 new_vertex = vec3(0,0,0);
+  vec3 new_normal = vec3(0,0,0);
+  out_color = vec3(0,0,0);
+  for (int idx=0; idx < 4; idx++) {
+    int bone = int(bone_ids >> (8*(3-idx)) & 0x000000FF);
+    float weight = bone_weights[idx];
+    Bone bone_dat = bones[first_bone+bone];
+    new_vertex += (quat_rot(bone_dat.rot, position)*bone_dat.pos.w + bone_dat.pos.xyz)*weight;
+    new_normal += quat_rot(bone_dat.rot, normal)*weight;
+    out_color += bone_color(bone)*weight;
+  }
+  vec4 world = model * vec4(new_vertex, 1.0);
+  gl_Position = viewproj * world;
+  out_uv = uv;
+  out_world = world.xyz;
+  out_normal = mat3(model) * new_normal;
+}
+"
+            }
+        }
+
+        mod debug_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                // which view, from RenderMode::shader_mode
+                layout(constant_id = 0) const int mode = 1;
+
+                #include <frame.glsl>
+                layout(set = 2, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec3 normal;
+                layout(location = 3) in vec3 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    if (texture(tex, uv).a < 0.1) { discard; }
+                    vec3 n = normal / max(length(normal), 0.0001);
+                    // a headlight, so shapes still read in the flat-colored views
+                    float light = 0.4 + 0.6 * abs(dot(n, normalize(camera_pos.xyz - world)));
+                    if (mode == 1) {
+                        f_color = vec4(n * 0.5 + 0.5, 1.0);
+                    } else if (mode == 2) {
+                        vec2 cell = floor(uv * 8.0);
+                        float check = mod(cell.x + cell.y, 2.0);
+                        f_color = vec4(mix(vec3(0.1), vec3(0.9), check) * light, 1.0);
+                    } else if (mode == 3) {
+                        // blended additively, once for every layer at this pixel
+                        f_color = vec4(0.1, 0.04, 0.01, 1.0);
+                    } else {
+                        f_color = vec4(color * light, 1.0);
+                    }
                 }
             "
             }
@@ -207,40 +471,56 @@ void main() {
 
         let vs = vs::load(vulkan.device.clone()).unwrap();
         let fs = fs::load(vulkan.device.clone()).unwrap();
-        use vulkano::sampler::SamplerCreateInfo;
-        let sampler = Sampler::new(vulkan.device.clone(), SamplerCreateInfo::default()).unwrap();
-        use vulkano::pipeline::graphics::depth_stencil::*;
-        let pipeline = GraphicsPipeline::start()
-            .vertex_input_state(
-                BuffersDefinition::new()
-                    .vertex::<Vertex>()
-                    .instance::<InstanceData>(),
-            )
-            .vertex_shader(vs.entry_point("main").unwrap(), ())
-            .input_assembly_state(InputAssemblyState::new().topology(
-                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
-            ))
-            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
-            .fragment_shader(fs.entry_point("main").unwrap(), ())
-            .rasterization_state(
-                RasterizationState::new()
-                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
-                    .front_face(
-                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
-                    ),
-            )
-            .depth_stencil_state(DepthStencilState {
-                depth: Some(DepthState {
-                    compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
-                    enable_dynamic: false,
-                    write_enable: vulkano::pipeline::StateMode::Fixed(true),
-                }),
-                depth_bounds: None,
-                stencil: None,
+        let outline_vs = outline_vs::load(vulkan.device.clone()).unwrap();
+        let outline_fs = outline_fs::load(vulkan.device.clone()).unwrap();
+        let debug_vs = debug_vs::load(vulkan.device.clone()).unwrap();
+        let debug_fs = debug_fs::load(vulkan.device.clone()).unwrap();
+        let pipeline = Self::build_pipeline(
+            vulkan,
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+            (),
+            CullMode::Back,
+            RenderMode::Shaded,
+        );
+        // the hull's back faces, pushed out past the model, show around its edges
+        let outline_pipeline = Self::build_pipeline(
+            vulkan,
+            outline_vs.entry_point("main").unwrap(),
+            outline_fs.entry_point("main").unwrap(),
+            (),
+            CullMode::Front,
+            RenderMode::Shaded,
+        );
+        // wireframe keeps the usual shaders; the other views share the debug ones
+        let mode_pipelines = RenderMode::ALL
+            .into_iter()
+            .filter(|mode| *mode != RenderMode::Shaded)
+            .map(|mode| {
+                let pipeline = if mode == RenderMode::Wireframe {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        fs.entry_point("main").unwrap(),
+                        (),
+                        CullMode::Back,
+                        mode,
+                    )
+                } else {
+                    Self::build_pipeline(
+                        vulkan,
+                        debug_vs.entry_point("main").unwrap(),
+                        debug_fs.entry_point("main").unwrap(),
+                        debug_fs::SpecializationConstants {
+                            mode: mode.shader_mode(),
+                        },
+                        CullMode::Back,
+                        mode,
+                    )
+                };
+                (mode, pipeline)
             })
-            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
-            .build(vulkan.device.clone())
-            .unwrap();
+            .collect();
 
         let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
         let uniform_pds =
@@ -261,8 +541,10 @@ void main() {
         let instance_pool = CpuBufferPool::vertex_buffer(vulkan.device.clone());
 
         Self {
-            sampler,
             pipeline,
+            outline_pipeline,
+            mode_pipelines,
+            mode: RenderMode::Shaded,
             uniform_buffers,
             uniform_pds,
             bone_count_buffers,
@@ -273,24 +555,67 @@ void main() {
             uniform_binding: None,
         }
     }
+    fn build_pipeline<S: SpecializationConstants>(
+        vulkan: &Vulkan,
+        vs: EntryPoint,
+        fs: EntryPoint,
+        fs_constants: S,
+        cull_mode: CullMode,
+        mode: RenderMode,
+    ) -> Arc<GraphicsPipeline> {
+        GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<Vertex>()
+                    .instance::<InstanceData>(),
+            )
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, fs_constants)
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(
+                RasterizationState::new()
+                    .cull_mode(cull_mode)
+                    .front_face(
+                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                    )
+                    .polygon_mode(mode.polygon_mode(vulkan)),
+            )
+            .depth_stencil_state(mode.depth_stencil_state())
+            .color_blend_state(mode.color_blend_state())
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn push_model(
         &mut self,
         key: ModelKey,
         mesh: &Mesh,
         texture: &Texture,
         trf: Similarity3,
+        style: InstanceStyle,
+        outline: Option<Outline>,
         anim: &animation::Animation,
         state: &animation::AnimationState,
     ) {
         use std::collections::hash_map::Entry;
         let inst = InstanceData {
             model: *trf.into_homogeneous_matrix().as_array(),
+            tint: style.tint.into(),
+            params: style.params.into(),
+            outline: match outline {
+                Some(o) => [o.color.x, o.color.y, o.color.z, o.width],
+                None => [0.0; 4],
+            },
         };
         match self.batches.entry(key) {
             Entry::Vacant(v) => {
                 let mut b = Self::create_batch(
                     self.pipeline.clone(),
-                    self.sampler.clone(),
                     &self.bone_count_buffers,
                     mesh,
                     texture,
@@ -304,7 +629,6 @@ void main() {
     }
     fn create_batch(
         pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
-        sampler: Arc<Sampler>,
         bone_count_buffers: &CpuBufferPool<u32>,
         mesh: &Mesh,
         texture: &Texture,
@@ -315,6 +639,7 @@ void main() {
             verts: mesh.verts.clone(),
             idxs: mesh.idx.clone(),
             instance_data: vec![],
+            outlined: false,
             bones: vec![],
             instance_buf: None,
             bone_ds: None,
@@ -324,9 +649,8 @@ void main() {
                 [
                     vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
                         0,
-                        vulkano::image::view::ImageView::new_default(texture.texture.clone())
-                            .unwrap(),
-                        sampler,
+                        texture.view.clone(),
+                        texture.sampler.clone(),
                     ),
                 ],
             )
@@ -334,9 +658,23 @@ void main() {
         }
     }
     pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        let frustum = camera.frustum();
         for v in rs.skinned.values() {
-            for (meshr, texr) in v.model.meshes.iter().zip(v.model.textures.iter()) {
+            let (model, anim_rate) = v.model.select(camera, v.transform, |m| m.radius(assets));
+            // far away instances can step through their poses at a lower rate
+            let state = match anim_rate {
+                Some(rate) if rate > 0.0 => animation::AnimationState {
+                    t: (v.state.t * rate).floor() / rate,
+                },
+                _ => v.state,
+            };
+            for (meshr, texr) in model.meshes.iter().zip(model.textures.iter()) {
                 let mesh = assets.skinned_mesh(*meshr);
+                // culled instances don't get their bones sampled either
+                let (center, radius) = mesh.bounds.sphere(v.transform);
+                if !frustum.contains_sphere(center, radius * POSE_BOUNDS_SLACK) {
+                    continue;
+                }
                 let tex = assets.texture(*texr);
                 let anim = assets.animation(v.animation);
                 self.push_model(
@@ -344,15 +682,18 @@ void main() {
                     mesh,
                     tex,
                     v.transform,
+                    v.style,
+                    v.outline,
                     anim,
-                    &v.state,
+                    &state,
                 );
             }
         }
-        self.prepare_draw(camera);
+        self.mode = rs.render_mode;
+        self.prepare_draw(super::FrameData::new(camera, rs.fog));
     }
-    fn prepare_draw(&mut self, camera: &Camera) {
-        let buf = self.uniform_buffers.next(camera.as_matrix()).unwrap();
+    fn prepare_draw(&mut self, frame: super::FrameData) {
+        let buf = self.uniform_buffers.next(frame).unwrap();
         let uds = self
             .uniform_pds
             .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
@@ -360,7 +701,8 @@ void main() {
             )])
             .unwrap();
         self.uniform_binding = Some(uds);
-        for (_k, b) in self.batches.iter_mut() {
+        // batches whose instances were all culled this frame have nothing to upload
+        for (_k, b) in self.batches.iter_mut().filter(|(_k, b)| !b.is_empty()) {
             b.prepare_draw(
                 &self.storage_buffers,
                 &mut self.bone_pds,
@@ -371,10 +713,22 @@ void main() {
     pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
         let uds = self.uniform_binding.clone().unwrap();
 
-        builder.bind_pipeline_graphics(self.pipeline.clone());
+        let pipeline = self
+            .mode_pipelines
+            .get(&self.mode)
+            .unwrap_or(&self.pipeline)
+            .clone();
+        builder.bind_pipeline_graphics(pipeline.clone());
 
-        for (_b, dat) in self.batches.iter() {
-            dat.draw(self.pipeline.clone(), uds.clone(), builder);
+        for (_b, dat) in self.batches.iter().filter(|(_b, dat)| !dat.is_empty()) {
+            dat.draw(pipeline.clone(), uds.clone(), builder);
+        }
+        // outlined batches get drawn again, with instances that aren't outlined clipped away
+        if self.mode == RenderMode::Shaded && self.batches.values().any(|b| b.outlined) {
+            builder.bind_pipeline_graphics(self.outline_pipeline.clone());
+            for dat in self.batches.values().filter(|dat| dat.outlined) {
+                dat.draw(self.outline_pipeline.clone(), uds.clone(), builder);
+            }
         }
         self.clear_frame();
     }
@@ -454,6 +808,7 @@ impl BatchData {
     }
     fn clear_frame(&mut self) {
         self.instance_data.clear();
+        self.outlined = false;
         self.bones.clear();
     }
     fn is_empty(&self) -> bool {
@@ -466,6 +821,7 @@ impl BatchData {
         anim: &animation::Animation,
         state: &animation::AnimationState,
     ) {
+        self.outlined |= inst.outline[3] > 0.0;
         self.instance_data.push(inst);
         // animation sampling here
         mesh.rig.write_bones(&mut self.bones, anim, state);
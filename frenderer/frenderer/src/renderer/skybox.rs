@@ -0,0 +1,352 @@
+use super::RenderState;
+use crate::assets;
+use crate::camera::Camera;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use std::sync::Arc;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::ViewportState;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::Pipeline;
+use vulkano::render_pass::Subpass;
+use vulkano::shader::EntryPoint;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sky {
+    // colors blend from the horizon up to the zenith and down to the nadir
+    Gradient {
+        zenith: Vec4,
+        horizon: Vec4,
+        nadir: Vec4,
+    },
+    // a cube texture from `Assets::load_cubemap`
+    Cubemap(assets::TextureRef),
+    // a single panorama texture mapped by longitude and latitude
+    Equirectangular(assets::TextureRef),
+}
+impl Default for Sky {
+    fn default() -> Self {
+        Sky::Gradient {
+            zenith: Vec4::new(0.15, 0.35, 0.75, 1.0),
+            horizon: Vec4::new(0.75, 0.85, 0.95, 1.0),
+            nadir: Vec4::new(0.3, 0.3, 0.3, 1.0),
+        }
+    }
+}
+impl Sky {
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        match (self, other) {
+            (
+                Sky::Gradient {
+                    zenith: z0,
+                    horizon: h0,
+                    nadir: n0,
+                },
+                Sky::Gradient {
+                    zenith: z1,
+                    horizon: h1,
+                    nadir: n1,
+                },
+            ) => Sky::Gradient {
+                zenith: z0.lerp(*z1, r),
+                horizon: h0.lerp(*h1, r),
+                nadir: n0.lerp(*n1, r),
+            },
+            _ => *other,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+struct SkyData {
+    inv_viewproj: [f32; 4 * 4],
+    zenith: [f32; 4],
+    horizon: [f32; 4],
+    nadir: [f32; 4],
+}
+
+pub struct Renderer {
+    gradient_pipeline: Arc<GraphicsPipeline>,
+    cubemap_pipeline: Arc<GraphicsPipeline>,
+    equirect_pipeline: Arc<GraphicsPipeline>,
+    uniform_buffers: CpuBufferPool<SkyData>,
+    // only the gradient reads the sky data in its fragment shader, so each
+    // pipeline's set 0 layout differs and needs its own pool
+    gradient_pds: SingleLayoutDescSetPool,
+    cubemap_pds: SingleLayoutDescSetPool,
+    equirect_pds: SingleLayoutDescSetPool,
+    uniform_binding: Option<Arc<SingleLayoutDescSet>>,
+    // the texture descriptor set only changes when the sky does; cubemap
+    // and panorama skies have different layouts, so the kind matters too
+    texture_pds: Option<(Sky, Arc<PersistentDescriptorSet>)>,
+    sky: Option<Sky>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                src: "
+#version 450
+
+// no vertex attributes; one triangle covers the screen
+layout(location = 0) out vec3 out_dir;
+
+layout(set=0, binding=0) uniform SkyData {
+  mat4 inv_viewproj;
+  vec4 zenith;
+  vec4 horizon;
+  vec4 nadir;
+};
+
+void main() {
+  vec2 pos = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+  // depth 0 is infinitely far away with a reversed-Z projection
+  gl_Position = vec4(pos, 0.0, 1.0);
+  // unproject a point on the near plane to find the view ray in world space
+  vec4 near = inv_viewproj * vec4(pos, 1.0, 1.0);
+  out_dir = near.xyz / near.w;
+}
+"
+            }
+        }
+
+        mod gradient_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set=0, binding=0) uniform SkyData {
+                  mat4 inv_viewproj;
+                  vec4 zenith;
+                  vec4 horizon;
+                  vec4 nadir;
+                };
+                layout(location = 0) in vec3 dir;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    float y = normalize(dir).y;
+                    if (y > 0.0) {
+                        f_color = mix(horizon, zenith, sqrt(y));
+                    } else {
+                        f_color = mix(horizon, nadir, sqrt(-y));
+                    }
+                }
+            "
+            }
+        }
+
+        mod cubemap_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 1, binding = 0) uniform samplerCube tex;
+                layout(location = 0) in vec3 dir;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    f_color = texture(tex, dir);
+                }
+            "
+            }
+        }
+
+        mod equirect_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                layout(set = 1, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec3 dir;
+                layout(location = 0) out vec4 f_color;
+
+                const float PI = 3.14159265359;
+
+                void main() {
+                    vec3 d = normalize(dir);
+                    vec2 uv = vec2(atan(d.z, d.x) / (2.0 * PI) + 0.5, acos(d.y) / PI);
+                    // u jumps from 1 back to 0 where the panorama wraps, which
+                    // would pick the smallest mip along that seam.  The same u
+                    // shifted half a turn is smooth there, so take whichever
+                    // has the smaller derivative.
+                    float u2 = fract(uv.x + 0.5);
+                    float dudx = abs(dFdx(uv.x)) < abs(dFdx(u2)) ? dFdx(uv.x) : dFdx(u2);
+                    float dudy = abs(dFdy(uv.x)) < abs(dFdy(u2)) ? dFdy(uv.x) : dFdy(u2);
+                    f_color = textureGrad(tex, uv, vec2(dudx, dFdx(uv.y)), vec2(dudy, dFdy(uv.y)));
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let gradient_fs = gradient_fs::load(vulkan.device.clone()).unwrap();
+        let cubemap_fs = cubemap_fs::load(vulkan.device.clone()).unwrap();
+        let equirect_fs = equirect_fs::load(vulkan.device.clone()).unwrap();
+        let vs_main = vs.entry_point("main").unwrap();
+        let gradient_pipeline = Self::build_pipeline(
+            vulkan,
+            vs_main.clone(),
+            gradient_fs.entry_point("main").unwrap(),
+        );
+        let cubemap_pipeline = Self::build_pipeline(
+            vulkan,
+            vs_main.clone(),
+            cubemap_fs.entry_point("main").unwrap(),
+        );
+        let equirect_pipeline =
+            Self::build_pipeline(vulkan, vs_main, equirect_fs.entry_point("main").unwrap());
+
+        let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let uniform_pds = |pipeline: &Arc<GraphicsPipeline>| {
+            SingleLayoutDescSetPool::new(pipeline.layout().set_layouts().get(0).unwrap().clone())
+        };
+        let gradient_pds = uniform_pds(&gradient_pipeline);
+        let cubemap_pds = uniform_pds(&cubemap_pipeline);
+        let equirect_pds = uniform_pds(&equirect_pipeline);
+
+        Self {
+            gradient_pipeline,
+            cubemap_pipeline,
+            equirect_pipeline,
+            uniform_buffers,
+            gradient_pds,
+            cubemap_pds,
+            equirect_pds,
+            uniform_binding: None,
+            texture_pds: None,
+            sky: None,
+        }
+    }
+    fn build_pipeline(vulkan: &Vulkan, vs: EntryPoint, fs: EntryPoint) -> Arc<GraphicsPipeline> {
+        use vulkano::pipeline::graphics::depth_stencil::*;
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new())
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, ())
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(
+                RasterizationState::new()
+                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::None),
+            )
+            // drawn after the scene: only fills pixels nothing else has written depth to
+            .depth_stencil_state(DepthStencilState {
+                depth: Some(DepthState {
+                    compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::GreaterOrEqual),
+                    enable_dynamic: false,
+                    write_enable: vulkano::pipeline::StateMode::Fixed(false),
+                }),
+                depth_bounds: None,
+                stencil: None,
+            })
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        // overdraw writes no depth, so the sky would cover it up; debug
+        // views all show against the plain clear color instead
+        self.sky = rs
+            .sky
+            .filter(|_| rs.render_mode == super::RenderMode::Shaded);
+        let sky = match self.sky {
+            Some(sky) => sky,
+            None => return,
+        };
+        let (zenith, horizon, nadir) = match sky {
+            Sky::Gradient {
+                zenith,
+                horizon,
+                nadir,
+            } => (zenith, horizon, nadir),
+            Sky::Cubemap(tex) | Sky::Equirectangular(tex) => {
+                if self.texture_pds.as_ref().map(|(s, _)| *s) != Some(sky) {
+                    let pipeline = self.pipeline(sky);
+                    let texture = assets.texture(tex);
+                    let pds = PersistentDescriptorSet::new(
+                        pipeline.layout().set_layouts().get(1).unwrap().clone(),
+                        [
+                            vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
+                                0,
+                                texture.view.clone(),
+                                texture.sampler.clone(),
+                            ),
+                        ],
+                    )
+                    .unwrap();
+                    self.texture_pds = Some((sky, pds));
+                }
+                (Vec4::zero(), Vec4::zero(), Vec4::zero())
+            }
+        };
+        // leave out the camera's translation so the sky stays infinitely far away
+        let view_rot = camera.transform.rotation.into_matrix().into_homogeneous();
+        let data = SkyData {
+            inv_viewproj: *(camera.projection() * view_rot).inversed().as_array(),
+            zenith: zenith.into(),
+            horizon: horizon.into(),
+            nadir: nadir.into(),
+        };
+        let buf = self.uniform_buffers.next(data).unwrap();
+        let uniform_pds = match sky {
+            Sky::Gradient { .. } => &mut self.gradient_pds,
+            Sky::Cubemap(_) => &mut self.cubemap_pds,
+            Sky::Equirectangular(_) => &mut self.equirect_pds,
+        };
+        let uds = uniform_pds
+            .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
+                0, buf,
+            )])
+            .unwrap();
+        self.uniform_binding = Some(uds);
+    }
+    fn pipeline(&self, sky: Sky) -> Arc<GraphicsPipeline> {
+        match sky {
+            Sky::Gradient { .. } => self.gradient_pipeline.clone(),
+            Sky::Cubemap(_) => self.cubemap_pipeline.clone(),
+            Sky::Equirectangular(_) => self.equirect_pipeline.clone(),
+        }
+    }
+    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        let sky = match self.sky {
+            Some(sky) => sky,
+            None => return,
+        };
+        let pipeline = self.pipeline(sky);
+        builder
+            .bind_pipeline_graphics(pipeline.clone())
+            .bind_descriptor_sets(
+                vulkano::pipeline::PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                0,
+                self.uniform_binding.clone().unwrap(),
+            );
+        if !matches!(sky, Sky::Gradient { .. }) {
+            builder.bind_descriptor_sets(
+                vulkano::pipeline::PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                1,
+                self.texture_pds.as_ref().unwrap().1.clone(),
+            );
+        }
+        builder.draw(3, 1, 0, 0).unwrap();
+    }
+}
@@ -1,7 +1,9 @@
 use super::RenderState;
+use super::{InstanceStyle, RenderMode};
 use crate::assets;
 use crate::assets::Texture;
 use crate::camera::Camera;
+use crate::sprite_sheet::SpriteAnimationState;
 use crate::types::*;
 use crate::vulkan::Vulkan;
 use bytemuck::{Pod, Zeroable};
@@ -20,13 +22,43 @@ use vulkano::pipeline::graphics::viewport::ViewportState;
 use vulkano::pipeline::GraphicsPipeline;
 use vulkano::pipeline::Pipeline;
 use vulkano::render_pass::Subpass;
-use vulkano::sampler::Sampler;
+use vulkano::shader::{EntryPoint, SpecializationConstants};
+
+// What part of which texture a sprite shows
+#[derive(Clone, Copy, PartialEq)]
+enum Cel {
+    Region(assets::TextureRef, Rect),
+    // resolved to a frame when the sprite is drawn
+    Sheet(assets::SpriteSheetRef, SpriteAnimationState),
+}
+impl Cel {
+    // Frames snap from one to the next; only playback time is interpolated
+    fn interpolate(&self, other: &Self, r: f32) -> Self {
+        match (self, other) {
+            (Cel::Sheet(s0, a0), Cel::Sheet(s1, a1)) if s0 == s1 => {
+                Cel::Sheet(*s1, a0.interpolate(a1, r))
+            }
+            _ => *other,
+        }
+    }
+}
+
+// Turns a sprite to face the camera, ignoring its own rotation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Billboard {
+    None,
+    // parallel to the screen
+    Spherical,
+    // spins around the world's Y axis only, staying upright
+    Cylindrical,
+}
 
 pub struct SingleRenderState {
-    texture: assets::TextureRef,
-    region: Rect,
+    cel: Cel,
     transform: Isometry3,
     size: Vec2,
+    billboard: Billboard,
+    style: InstanceStyle,
 }
 impl SingleRenderState {
     pub(crate) fn new(
@@ -36,18 +68,52 @@ impl SingleRenderState {
         size: Vec2,
     ) -> Self {
         Self {
-            texture,
-            region,
+            cel: Cel::Region(texture, region),
+            transform,
+            size,
+            billboard: Billboard::None,
+            style: InstanceStyle::default(),
+        }
+    }
+    pub(crate) fn animated(
+        sheet: assets::SpriteSheetRef,
+        state: SpriteAnimationState,
+        transform: Isometry3,
+        size: Vec2,
+    ) -> Self {
+        Self {
+            cel: Cel::Sheet(sheet, state),
             transform,
             size,
+            billboard: Billboard::None,
+            style: InstanceStyle::default(),
+        }
+    }
+    pub fn billboard(&mut self, mode: Billboard) -> &mut Self {
+        self.billboard = mode;
+        self
+    }
+    pub fn tint(&mut self, tint: Vec4) -> &mut Self {
+        self.style.tint = tint;
+        self
+    }
+    pub fn params(&mut self, params: Vec4) -> &mut Self {
+        self.style.params = params;
+        self
+    }
+    pub(crate) fn uses_texture(&self, tex: assets::TextureRef, assets: &assets::Assets) -> bool {
+        match &self.cel {
+            Cel::Region(t, _) => *t == tex,
+            Cel::Sheet(sheet, _) => assets.sprite_sheet(*sheet).texture == tex,
         }
     }
     pub fn interpolate(&self, other: &Self, r: f32) -> Self {
         Self {
-            texture: other.texture,
+            cel: self.cel.interpolate(&other.cel, r),
             transform: self.transform.lerp(&other.transform, r),
             size: self.size.lerp(other.size, r),
-            region: self.region.lerp(&other.region, r),
+            billboard: other.billboard,
+            style: self.style.interpolate(&other.style, r),
         }
     }
 }
@@ -57,8 +123,12 @@ impl SingleRenderState {
 struct InstanceData {
     model: [f32; 4 * 4],
     size_uv: [f32; 4],
+    // 0 none, 1 spherical, 2 cylindrical
+    billboard: f32,
+    tint: [f32; 4],
+    params: [f32; 4],
 }
-vulkano::impl_vertex!(InstanceData, model, size_uv);
+vulkano::impl_vertex!(InstanceData, model, size_uv, billboard, tint, params);
 struct BatchData {
     material_pds: Arc<vulkano::descriptor_set::PersistentDescriptorSet>,
     instance_data: Vec<InstanceData>,
@@ -69,10 +139,12 @@ struct BatchData {
 
 pub struct Renderer {
     pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
-    sampler: Arc<Sampler>,
+    // alternate pipelines for the debug render modes
+    mode_pipelines: HashMap<RenderMode, Arc<vulkano::pipeline::GraphicsPipeline>>,
+    mode: RenderMode,
     // we'll use one uniform buffer across all batches.
-    // it will be the projection-view transform.
-    uniform_buffers: CpuBufferPool<Mat4>,
+    // it will be the projection-view transform and fog settings.
+    uniform_buffers: CpuBufferPool<super::FrameData>,
     uniform_pds: SingleLayoutDescSetPool,
     uniform_binding: Option<Arc<SingleLayoutDescSet>>,
     index_buf: Arc<ImmutableBuffer<[u16]>>,
@@ -85,6 +157,7 @@ impl Renderer {
         mod vs {
             vulkano_shaders::shader! {
                 ty: "vertex",
+                include: ["src/shaders"],
                 src: "
 #version 450
 
@@ -92,12 +165,18 @@ impl Renderer {
 // instance data
 layout(location = 0) in mat4 model;
 layout(location = 4) in vec4 size_uv;
+layout(location = 5) in float billboard;
+layout(location = 6) in vec4 tint;
+layout(location = 7) in vec4 params;
 
 // outputs
 layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) flat out vec4 out_tint;
+layout(location = 3) flat out vec4 out_params;
 
 // uniforms
-layout(set=0, binding=0) uniform BatchData { mat4 viewproj; };
+#include <frame.glsl>
 
 void main() {
   float w = size_uv.x;
@@ -110,8 +189,24 @@ void main() {
     vec2(0.5, 0.5),
   };
   vec2 pos = posns[gl_VertexIndex].xy;
-  gl_Position = viewproj * model * vec4(pos.xy, 0.0, 1.0);
+  vec4 world = model * vec4(pos.xy, 0.0, 1.0);
+  if (billboard > 0.5) {
+    // keep the sprite's position and size but use the camera's axes,
+    // which are the rows of the view rotation
+    vec3 right = vec3(view[0][0], view[1][0], view[2][0]);
+    vec3 up = vec3(view[0][1], view[1][1], view[2][1]);
+    if (billboard > 1.5) {
+      right = normalize(vec3(right.x, 0.0, right.z));
+      up = vec3(0.0, 1.0, 0.0);
+    }
+    vec2 scale = vec2(length(model[0].xyz), length(model[1].xyz));
+    world = vec4(model[3].xyz + right * pos.x * scale.x + up * pos.y * scale.y, 1.0);
+  }
+  gl_Position = viewproj * world;
   out_uv = vec2(size_uv.z,1.0-size_uv.w) + vec2(size_uv.x*(pos.x+0.5),size_uv.y*(1.0-(pos.y+0.5)));
+  out_world = world.xyz;
+  out_tint = tint;
+  out_params = params;
 }
 "
             }
@@ -120,18 +215,76 @@ void main() {
         mod fs {
             vulkano_shaders::shader! {
                 ty: "fragment",
+                include: ["src/shaders"],
                 src: "
                 #version 450
 
+                #include <frame.glsl>
                 layout(set = 1, binding = 0) uniform sampler2D tex;
                 layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) flat in vec4 tint;
+                layout(location = 3) flat in vec4 params;
                 layout(location = 0) out vec4 f_color;
 
+                // ordered dither, so faded instances need no sorting
+                float dither() {
+                    const float bayer[16] = float[16](
+                        0.0, 8.0, 2.0, 10.0, 12.0, 4.0, 14.0, 6.0,
+                        3.0, 11.0, 1.0, 9.0, 15.0, 7.0, 13.0, 5.0);
+                    ivec2 p = ivec2(gl_FragCoord.xy) % 4;
+                    return (bayer[p.y * 4 + p.x] + 0.5) / 16.0;
+                }
+
                 void main() {
                     vec4 col = texture(tex, uv);
                     //col = vec4(1.0, 1.0, 0.0, 1.0);
-                    if (col.a < 0.1) { discard; }
-                    f_color = col;
+                    if (col.a < 0.1 || tint.a < dither()) { discard; }
+                    col.rgb *= tint.rgb;
+                    f_color = vec4(mix(col.rgb, fog_color.rgb * col.a, fog_amount(world)), col.a);
+                }
+            "
+            }
+        }
+
+        mod debug_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                // which view, from RenderMode::shader_mode
+                layout(constant_id = 0) const int mode = 1;
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // the same cutout as the shaded sprite
+                    if (texture(tex, uv).a < 0.1) { discard; }
+                    // quads carry no normals, so take the face's from the world position
+                    vec3 n = normalize(cross(dFdx(world), dFdy(world)));
+                    vec3 to_eye = normalize(camera_pos.xyz - world);
+                    if (dot(n, to_eye) < 0.0) { n = -n; }
+                    // a headlight, so shapes still read in the flat-colored views
+                    float light = 0.4 + 0.6 * abs(dot(n, to_eye));
+                    if (mode == 1) {
+                        f_color = vec4(n * 0.5 + 0.5, 1.0);
+                    } else if (mode == 2) {
+                        vec2 cell = floor(uv * 8.0);
+                        float check = mod(cell.x + cell.y, 2.0);
+                        f_color = vec4(mix(vec3(0.1), vec3(0.9), check) * light, 1.0);
+                    } else if (mode == 3) {
+                        // blended additively, once for every layer at this pixel
+                        f_color = vec4(0.1, 0.04, 0.01, 1.0);
+                    } else {
+                        // no bones to show
+                        f_color = vec4(vec3(0.5) * light, 1.0);
+                    }
                 }
             "
             }
@@ -139,36 +292,41 @@ void main() {
 
         let vs = vs::load(vulkan.device.clone()).unwrap();
         let fs = fs::load(vulkan.device.clone()).unwrap();
-        use vulkano::sampler::SamplerCreateInfo;
-        let sampler = Sampler::new(vulkan.device.clone(), SamplerCreateInfo::default()).unwrap();
-        use vulkano::pipeline::graphics::depth_stencil::*;
-        let pipeline = GraphicsPipeline::start()
-            .vertex_input_state(BuffersDefinition::new().instance::<InstanceData>())
-            .vertex_shader(vs.entry_point("main").unwrap(), ())
-            .input_assembly_state(InputAssemblyState::new().topology(
-                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
-            ))
-            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
-            .fragment_shader(fs.entry_point("main").unwrap(), ())
-            .rasterization_state(
-                RasterizationState::new()
-                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
-                    .front_face(
-                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
-                    ),
-            )
-            .depth_stencil_state(DepthStencilState {
-                depth: Some(DepthState {
-                    compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
-                    enable_dynamic: false,
-                    write_enable: vulkano::pipeline::StateMode::Fixed(true),
-                }),
-                depth_bounds: None,
-                stencil: None,
+        let debug_fs = debug_fs::load(vulkan.device.clone()).unwrap();
+        let pipeline = Self::build_pipeline(
+            vulkan,
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+            (),
+            RenderMode::Shaded,
+        );
+        // wireframe keeps the usual shaders; the other views share the debug one
+        let mode_pipelines = RenderMode::ALL
+            .into_iter()
+            .filter(|mode| *mode != RenderMode::Shaded)
+            .map(|mode| {
+                let pipeline = if mode == RenderMode::Wireframe {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        fs.entry_point("main").unwrap(),
+                        (),
+                        mode,
+                    )
+                } else {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        debug_fs.entry_point("main").unwrap(),
+                        debug_fs::SpecializationConstants {
+                            mode: mode.shader_mode(),
+                        },
+                        mode,
+                    )
+                };
+                (mode, pipeline)
             })
-            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
-            .build(vulkan.device.clone())
-            .unwrap();
+            .collect();
 
         let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
         let uniform_pds =
@@ -184,8 +342,9 @@ void main() {
         vulkan.wait_for(Box::new(fut));
 
         Self {
-            sampler,
             pipeline,
+            mode_pipelines,
+            mode: RenderMode::Shaded,
             uniform_buffers,
             uniform_pds,
             index_buf,
@@ -194,6 +353,37 @@ void main() {
             uniform_binding: None,
         }
     }
+    fn build_pipeline<S: SpecializationConstants>(
+        vulkan: &Vulkan,
+        vs: EntryPoint,
+        fs: EntryPoint,
+        fs_constants: S,
+        mode: RenderMode,
+    ) -> Arc<GraphicsPipeline> {
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().instance::<InstanceData>())
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, fs_constants)
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(
+                RasterizationState::new()
+                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
+                    .front_face(
+                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                    )
+                    .polygon_mode(mode.polygon_mode(vulkan)),
+            )
+            .depth_stencil_state(mode.depth_stencil_state())
+            .color_blend_state(mode.color_blend_state())
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    #[allow(clippy::too_many_arguments)]
     pub fn push_model(
         &mut self,
         tr: assets::TextureRef,
@@ -201,6 +391,8 @@ void main() {
         region: Rect,
         trf: Isometry3,
         size: Vec2,
+        billboard: Billboard,
+        style: InstanceStyle,
     ) {
         use std::collections::hash_map::Entry;
         let inst = InstanceData {
@@ -208,15 +400,18 @@ void main() {
                 * Mat4::from_nonuniform_scale(Vec3::new(size.x, size.y, 1.0)))
             .as_array(),
             size_uv: [region.sz.x, region.sz.y, region.pos.x, region.pos.y],
+            billboard: match billboard {
+                Billboard::None => 0.0,
+                Billboard::Spherical => 1.0,
+                Billboard::Cylindrical => 2.0,
+            },
+            tint: style.tint.into(),
+            params: style.params.into(),
         };
         match self.batches.entry(tr) {
             Entry::Vacant(v) => {
-                let mut b = Self::create_batch(
-                    self.pipeline.clone(),
-                    self.sampler.clone(),
-                    texture,
-                    self.index_buf.clone(),
-                );
+                let mut b =
+                    Self::create_batch(self.pipeline.clone(), texture, self.index_buf.clone());
                 b.push_instance(inst);
                 v.insert(b);
             }
@@ -225,7 +420,6 @@ void main() {
     }
     fn create_batch(
         pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
-        sampler: Arc<Sampler>,
         texture: &Texture,
         index_buf: Arc<ImmutableBuffer<[u16]>>,
     ) -> BatchData {
@@ -238,9 +432,8 @@ void main() {
                 [
                     vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
                         0,
-                        vulkano::image::view::ImageView::new_default(texture.texture.clone())
-                            .unwrap(),
-                        sampler,
+                        texture.view.clone(),
+                        texture.sampler.clone(),
                     ),
                 ],
             )
@@ -249,13 +442,29 @@ void main() {
     }
     pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
         for v in rs.sprites.values() {
-            let tex = assets.texture(v.texture);
-            self.push_model(v.texture, tex, v.region, v.transform, v.size);
+            let (texture, region) = match v.cel {
+                Cel::Region(texture, region) => (texture, region),
+                Cel::Sheet(sheet, state) => {
+                    let sheet = assets.sprite_sheet(sheet);
+                    (sheet.texture, sheet.region(&state))
+                }
+            };
+            let tex = assets.texture(texture);
+            self.push_model(
+                texture,
+                tex,
+                region,
+                v.transform,
+                v.size,
+                v.billboard,
+                v.style,
+            );
         }
-        self.prepare_draw(camera);
+        self.mode = rs.render_mode;
+        self.prepare_draw(super::FrameData::new(camera, rs.fog));
     }
-    fn prepare_draw(&mut self, camera: &Camera) {
-        let buf = self.uniform_buffers.next(camera.as_matrix()).unwrap();
+    fn prepare_draw(&mut self, frame: super::FrameData) {
+        let buf = self.uniform_buffers.next(frame).unwrap();
         let uds = self
             .uniform_pds
             .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
@@ -269,11 +478,16 @@ void main() {
     }
     pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
         let uds = self.uniform_binding.clone().unwrap();
+        let pipeline = self
+            .mode_pipelines
+            .get(&self.mode)
+            .unwrap_or(&self.pipeline)
+            .clone();
 
-        builder.bind_pipeline_graphics(self.pipeline.clone());
+        builder.bind_pipeline_graphics(pipeline.clone());
 
         for (_b, dat) in self.batches.iter() {
-            dat.draw(self.pipeline.clone(), uds.clone(), builder);
+            dat.draw(pipeline.clone(), uds.clone(), builder);
         }
         self.clear_frame();
     }
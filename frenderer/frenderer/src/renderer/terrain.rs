@@ -0,0 +1,630 @@
+use super::{RenderMode, RenderState};
+use crate::assets;
+use crate::camera::Camera;
+use crate::color_eyre::eyre::ensure;
+use crate::image::Image;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use crate::Result;
+use bytemuck::{Pod, Zeroable};
+use std::collections::HashMap;
+use std::sync::Arc;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::ImmutableBuffer;
+use vulkano::buffer::TypedBufferAccess;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::descriptor_set::WriteDescriptorSet;
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::{CullMode, RasterizationState};
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::ViewportState;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::Pipeline;
+use vulkano::render_pass::Subpass;
+use vulkano::shader::{EntryPoint, SpecializationConstants};
+use vulkano::sync::GpuFuture;
+
+#[repr(C)]
+#[derive(Default, Debug, Clone, Copy, Pod, Zeroable)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    // 0..1 across the whole terrain, for the splat map
+    pub uv: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position, normal, uv);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TerrainLayer {
+    pub texture: assets::TextureRef,
+    // how many times the texture repeats across the whole terrain
+    pub repeat: f32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TerrainSettings {
+    // where the heightmap's top left pixel ends up; rows run along +Z and
+    // columns along +X
+    pub origin: Vec3,
+    // world distance between neighboring heightmap pixels
+    pub cell_size: f32,
+    // height of a white pixel above `origin`; black pixels are at `origin.y`
+    pub height_scale: f32,
+    // cells along each side of a chunk, the unit of culling
+    pub chunk_size: u32,
+    // stretched over the whole terrain: red, green and blue weight the first
+    // three layers, and whatever's left of 1 weights the fourth.  Load it
+    // with `Engine::load_splat_map` so the weights come through as painted.
+    pub splat: assets::TextureRef,
+    // one to four textures, blended by the splat map
+    pub layers: Vec<TerrainLayer>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+struct LayerData {
+    repeat: [f32; 4],
+    count: i32,
+    _pad: [i32; 3],
+}
+
+struct Chunk {
+    verts: Arc<ImmutableBuffer<[Vertex]>>,
+    idx: Arc<ImmutableBuffer<[u32]>>,
+    // in world space, since terrains don't move
+    bounds: Bounds,
+}
+
+// A grid of heights from a heightmap, drawn in chunks.  Its heights can be
+// queried to keep characters on the ground.
+pub struct Terrain {
+    settings: TerrainSettings,
+    // samples per row and rows
+    size: (u32, u32),
+    // world-space heights, row by row
+    heights: Vec<f32>,
+    chunks: Vec<Chunk>,
+    layer_data: Arc<ImmutableBuffer<LayerData>>,
+}
+
+impl Terrain {
+    // Heights come from the heightmap's red channel
+    pub(crate) fn new(
+        heightmap: &Image,
+        settings: TerrainSettings,
+        vulkan: &mut Vulkan,
+    ) -> Result<Self> {
+        let (w, h) = (heightmap.sz.x, heightmap.sz.y);
+        ensure!(w >= 2 && h >= 2, "Heightmap must be at least 2x2 pixels");
+        ensure!(
+            (1..=4).contains(&settings.layers.len()),
+            "Terrain needs one to four layers, not {}",
+            settings.layers.len()
+        );
+        ensure!(
+            settings.chunk_size > 0,
+            "Terrain chunk size must be positive"
+        );
+        ensure!(
+            settings.cell_size > 0.0,
+            "Terrain cell size must be positive"
+        );
+        let heights = heightmap
+            .as_slice()
+            .iter()
+            .map(|c| settings.origin.y + c.0 as f32 / 255.0 * settings.height_scale)
+            .collect();
+        let layer_data = Self::upload_layers(&settings.layers, vulkan)?;
+        let mut terrain = Self {
+            settings,
+            size: (w, h),
+            heights,
+            chunks: vec![],
+            layer_data,
+        };
+        let cs = terrain.settings.chunk_size;
+        for z0 in (0..h - 1).step_by(cs as usize) {
+            for x0 in (0..w - 1).step_by(cs as usize) {
+                let chunk = terrain.build_chunk(
+                    x0,
+                    z0,
+                    (x0 + cs).min(w - 1),
+                    (z0 + cs).min(h - 1),
+                    vulkan,
+                )?;
+                terrain.chunks.push(chunk);
+            }
+        }
+        Ok(terrain)
+    }
+    fn upload_layers(
+        layers: &[TerrainLayer],
+        vulkan: &mut Vulkan,
+    ) -> Result<Arc<ImmutableBuffer<LayerData>>> {
+        let mut data = LayerData {
+            count: layers.len() as i32,
+            ..LayerData::default()
+        };
+        for (r, l) in data.repeat.iter_mut().zip(layers.iter()) {
+            *r = l.repeat;
+        }
+        let (buffer, fut) = ImmutableBuffer::from_data(
+            data,
+            vulkano::buffer::BufferUsage::uniform_buffer(),
+            vulkan.queue.clone(),
+        )?;
+        vulkan.wait_for(Box::new(fut));
+        Ok(buffer)
+    }
+    // The samples from (x0, z0) to (x1, z1) inclusive
+    fn build_chunk(
+        &self,
+        x0: u32,
+        z0: u32,
+        x1: u32,
+        z1: u32,
+        vulkan: &mut Vulkan,
+    ) -> Result<Chunk> {
+        let (w, h) = self.size;
+        let mut verts = vec![];
+        let mut points = vec![];
+        for z in z0..=z1 {
+            for x in x0..=x1 {
+                let p = self.sample_position(x, z);
+                // central differences, one-sided at the edges
+                let dx = self.sample(x.saturating_sub(1), z) - self.sample((x + 1).min(w - 1), z);
+                let dz = self.sample(x, z.saturating_sub(1)) - self.sample(x, (z + 1).min(h - 1));
+                let n = Vec3::new(dx, 2.0 * self.settings.cell_size, dz).normalized();
+                verts.push(Vertex {
+                    position: p.into(),
+                    normal: n.into(),
+                    uv: [x as f32 / (w - 1) as f32, z as f32 / (h - 1) as f32],
+                });
+                points.push(p);
+            }
+        }
+        let cols = x1 - x0 + 1;
+        let mut idxs = vec![];
+        for z in 0..z1 - z0 {
+            for x in 0..x1 - x0 {
+                // same split as `height_at`
+                let a = z * cols + x;
+                let (b, c, d) = (a + cols, a + cols + 1, a + 1);
+                idxs.extend_from_slice(&[a, b, c, a, c, d]);
+            }
+        }
+        let (vb, vb_fut) = ImmutableBuffer::from_iter(
+            verts.into_iter(),
+            vulkano::buffer::BufferUsage::vertex_buffer(),
+            vulkan.queue.clone(),
+        )?;
+        let (ib, ib_fut) = ImmutableBuffer::from_iter(
+            idxs.into_iter(),
+            vulkano::buffer::BufferUsage::index_buffer(),
+            vulkan.queue.clone(),
+        )?;
+        vulkan.wait_for(Box::new(vb_fut.join(ib_fut)));
+        Ok(Chunk {
+            verts: vb,
+            idx: ib,
+            bounds: Bounds::from_points(&points),
+        })
+    }
+    fn sample(&self, x: u32, z: u32) -> f32 {
+        self.heights[(z * self.size.0 + x) as usize]
+    }
+    fn sample_position(&self, x: u32, z: u32) -> Vec3 {
+        let o = self.settings.origin;
+        let cell = self.settings.cell_size;
+        Vec3::new(
+            o.x + x as f32 * cell,
+            self.sample(x, z),
+            o.z + z as f32 * cell,
+        )
+    }
+    // Which cell (x, z) is over, and how far across it, or None off the edge
+    fn cell(&self, x: f32, z: f32) -> Option<(u32, u32, f32, f32)> {
+        let gx = (x - self.settings.origin.x) / self.settings.cell_size;
+        let gz = (z - self.settings.origin.z) / self.settings.cell_size;
+        let (w, h) = self.size;
+        if !(0.0..=(w - 1) as f32).contains(&gx) || !(0.0..=(h - 1) as f32).contains(&gz) {
+            return None;
+        }
+        // the far edges belong to the last cell
+        let cx = (gx as u32).min(w - 2);
+        let cz = (gz as u32).min(h - 2);
+        Some((cx, cz, gx - cx as f32, gz - cz as f32))
+    }
+    // The ground height under world (x, z), matching the drawn triangles,
+    // or None off the edge of the terrain
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let (cx, cz, fx, fz) = self.cell(x, z)?;
+        let a = self.sample(cx, cz);
+        let b = self.sample(cx, cz + 1);
+        let c = self.sample(cx + 1, cz + 1);
+        let d = self.sample(cx + 1, cz);
+        // each cell is triangles a-b-c and a-c-d, split along a-c
+        Some(if fz >= fx {
+            a + (b - a) * fz + (c - b) * fx
+        } else {
+            a + (d - a) * fx + (c - d) * fz
+        })
+    }
+    // The upward normal of the triangle under world (x, z), e.g. to tilt
+    // things to the slope
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vec3> {
+        let (cx, cz, fx, fz) = self.cell(x, z)?;
+        let a = self.sample(cx, cz);
+        let b = self.sample(cx, cz + 1);
+        let c = self.sample(cx + 1, cz + 1);
+        let d = self.sample(cx + 1, cz);
+        let cell = self.settings.cell_size;
+        Some(
+            if fz >= fx {
+                Vec3::new(b - c, cell, a - b)
+            } else {
+                Vec3::new(a - d, cell, d - c)
+            }
+            .normalized(),
+        )
+    }
+    // The world-space corners the terrain spans on the XZ plane
+    pub fn extent(&self) -> (Vec2, Vec2) {
+        let o = Vec2::new(self.settings.origin.x, self.settings.origin.z);
+        let (w, h) = self.size;
+        let size = Vec2::new((w - 1) as f32, (h - 1) as f32) * self.settings.cell_size;
+        (o, o + size)
+    }
+    pub fn settings(&self) -> &TerrainSettings {
+        &self.settings
+    }
+}
+
+pub struct Renderer {
+    pipeline: Arc<GraphicsPipeline>,
+    // alternate pipelines for the debug render modes
+    mode_pipelines: HashMap<RenderMode, Arc<GraphicsPipeline>>,
+    mode: RenderMode,
+    uniform_buffers: CpuBufferPool<super::FrameData>,
+    uniform_pds: SingleLayoutDescSetPool,
+    uniform_binding: Option<Arc<SingleLayoutDescSet>>,
+    // terrains' textures don't change, so their descriptor sets are made once
+    material_pds: HashMap<assets::TerrainRef, Arc<PersistentDescriptorSet>>,
+    // the chunks that passed culling this frame
+    draws: Vec<(
+        Arc<PersistentDescriptorSet>,
+        Arc<ImmutableBuffer<[Vertex]>>,
+        Arc<ImmutableBuffer<[u32]>>,
+    )>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec3 out_normal;
+
+#include <frame.glsl>
+
+void main() {
+  // terrain vertices are already in world space
+  gl_Position = viewproj * vec4(position, 1.0);
+  out_uv = uv;
+  out_world = position;
+  out_normal = normal;
+}
+"
+            }
+        }
+
+        mod fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform sampler2D splat;
+                layout(set = 1, binding = 1) uniform sampler2D layer0;
+                layout(set = 1, binding = 2) uniform sampler2D layer1;
+                layout(set = 1, binding = 3) uniform sampler2D layer2;
+                layout(set = 1, binding = 4) uniform sampler2D layer3;
+                layout(set = 1, binding = 5) uniform LayerData {
+                  vec4 repeat;
+                  int count;
+                };
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec3 normal;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    vec3 s = texture(splat, uv).rgb;
+                    vec4 w = vec4(s, max(1.0 - s.r - s.g - s.b, 0.0));
+                    // weights for layers the terrain doesn't have go unused
+                    w *= vec4(greaterThan(ivec4(count), ivec4(0, 1, 2, 3)));
+                    float total = w.x + w.y + w.z + w.w;
+                    w = total < 0.0001 ? vec4(1.0, 0.0, 0.0, 0.0) : w / total;
+                    vec3 col = texture(layer0, uv * repeat.x).rgb * w.x
+                        + texture(layer1, uv * repeat.y).rgb * w.y
+                        + texture(layer2, uv * repeat.z).rgb * w.z
+                        + texture(layer3, uv * repeat.w).rgb * w.w;
+                    // a fixed light from above, so slopes read
+                    vec3 n = normalize(normal);
+                    col *= 0.55 + 0.45 * max(dot(n, normalize(vec3(0.3, 1.0, 0.2))), 0.0);
+                    f_color = vec4(mix(col, fog_color.rgb, fog_amount(world)), 1.0);
+                }
+            "
+            }
+        }
+
+        mod debug_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec3 out_normal;
+layout(location = 3) out vec3 out_color;
+
+#include <frame.glsl>
+
+void main() {
+  gl_Position = viewproj * vec4(position, 1.0);
+  out_uv = uv;
+  out_world = position;
+  out_normal = normal;
+  // no bones to show
+  out_color = vec3(0.5);
+}
+"
+            }
+        }
+
+        mod debug_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                // which view, from RenderMode::shader_mode
+                layout(constant_id = 0) const int mode = 1;
+
+                #include <frame.glsl>
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec3 normal;
+                layout(location = 3) in vec3 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    vec3 n = normal / max(length(normal), 0.0001);
+                    // a headlight, so shapes still read in the flat-colored views
+                    float light = 0.4 + 0.6 * abs(dot(n, normalize(camera_pos.xyz - world)));
+                    if (mode == 1) {
+                        f_color = vec4(n * 0.5 + 0.5, 1.0);
+                    } else if (mode == 2) {
+                        vec2 cell = floor(uv * 8.0);
+                        float check = mod(cell.x + cell.y, 2.0);
+                        f_color = vec4(mix(vec3(0.1), vec3(0.9), check) * light, 1.0);
+                    } else if (mode == 3) {
+                        // blended additively, once for every layer at this pixel
+                        f_color = vec4(0.1, 0.04, 0.01, 1.0);
+                    } else {
+                        f_color = vec4(color * light, 1.0);
+                    }
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let fs = fs::load(vulkan.device.clone()).unwrap();
+        let debug_vs = debug_vs::load(vulkan.device.clone()).unwrap();
+        let debug_fs = debug_fs::load(vulkan.device.clone()).unwrap();
+        let pipeline = Self::build_pipeline(
+            vulkan,
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+            (),
+            RenderMode::Shaded,
+        );
+        // wireframe keeps the usual shaders; the other views share the debug
+        // ones, which don't need the terrain's textures
+        let mode_pipelines = RenderMode::ALL
+            .into_iter()
+            .filter(|mode| *mode != RenderMode::Shaded)
+            .map(|mode| {
+                let pipeline = if mode == RenderMode::Wireframe {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        fs.entry_point("main").unwrap(),
+                        (),
+                        mode,
+                    )
+                } else {
+                    Self::build_pipeline(
+                        vulkan,
+                        debug_vs.entry_point("main").unwrap(),
+                        debug_fs.entry_point("main").unwrap(),
+                        debug_fs::SpecializationConstants {
+                            mode: mode.shader_mode(),
+                        },
+                        mode,
+                    )
+                };
+                (mode, pipeline)
+            })
+            .collect();
+
+        let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let uniform_pds =
+            SingleLayoutDescSetPool::new(pipeline.layout().set_layouts().get(0).unwrap().clone());
+
+        Self {
+            pipeline,
+            mode_pipelines,
+            mode: RenderMode::Shaded,
+            uniform_buffers,
+            uniform_pds,
+            uniform_binding: None,
+            material_pds: HashMap::new(),
+            draws: vec![],
+        }
+    }
+    fn build_pipeline<S: SpecializationConstants>(
+        vulkan: &Vulkan,
+        vs: EntryPoint,
+        fs: EntryPoint,
+        fs_constants: S,
+        mode: RenderMode,
+    ) -> Arc<GraphicsPipeline> {
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().vertex::<Vertex>())
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, fs_constants)
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(
+                RasterizationState::new()
+                    .cull_mode(CullMode::Back)
+                    .front_face(
+                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                    )
+                    .polygon_mode(mode.polygon_mode(vulkan)),
+            )
+            .depth_stencil_state(mode.depth_stencil_state())
+            .color_blend_state(mode.color_blend_state())
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    fn create_material(
+        &self,
+        terrain: &Terrain,
+        assets: &assets::Assets,
+    ) -> Arc<PersistentDescriptorSet> {
+        let splat = assets.texture(terrain.settings.splat);
+        // missing layers get the first one's texture, with no weight
+        let layer = |i: usize| {
+            let l = terrain
+                .settings
+                .layers
+                .get(i)
+                .unwrap_or(&terrain.settings.layers[0]);
+            let tex = assets.texture(l.texture);
+            WriteDescriptorSet::image_view_sampler(
+                i as u32 + 1,
+                tex.view.clone(),
+                tex.sampler.clone(),
+            )
+        };
+        PersistentDescriptorSet::new(
+            self.pipeline.layout().set_layouts().get(1).unwrap().clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(
+                    0,
+                    splat.view.clone(),
+                    splat.sampler.clone(),
+                ),
+                layer(0),
+                layer(1),
+                layer(2),
+                layer(3),
+                WriteDescriptorSet::buffer(5, terrain.layer_data.clone()),
+            ],
+        )
+        .unwrap()
+    }
+    pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        let frustum = camera.frustum();
+        for tr in rs.terrains.iter() {
+            let terrain = assets.terrain(*tr);
+            if !self.material_pds.contains_key(tr) {
+                let pds = self.create_material(terrain, assets);
+                self.material_pds.insert(*tr, pds);
+            }
+            let pds = &self.material_pds[tr];
+            for chunk in terrain.chunks.iter() {
+                if frustum.contains_sphere(chunk.bounds.center, chunk.bounds.radius) {
+                    self.draws
+                        .push((pds.clone(), chunk.verts.clone(), chunk.idx.clone()));
+                }
+            }
+        }
+        self.mode = rs.render_mode;
+        let buf = self
+            .uniform_buffers
+            .next(super::FrameData::new(camera, rs.fog))
+            .unwrap();
+        let uds = self
+            .uniform_pds
+            .next(vec![WriteDescriptorSet::buffer(0, buf)])
+            .unwrap();
+        self.uniform_binding = Some(uds);
+    }
+    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        if self.draws.is_empty() {
+            return;
+        }
+        let pipeline = self
+            .mode_pipelines
+            .get(&self.mode)
+            .unwrap_or(&self.pipeline)
+            .clone();
+        let layout = pipeline.layout().clone();
+        // the debug views have no texture set to bind
+        let textured = layout.set_layouts().len() > 1;
+        builder
+            .bind_pipeline_graphics(pipeline)
+            .bind_descriptor_sets(
+                vulkano::pipeline::PipelineBindPoint::Graphics,
+                layout.clone(),
+                0,
+                self.uniform_binding.clone().unwrap(),
+            );
+        for (pds, verts, idx) in self.draws.drain(..) {
+            let count = idx.len() as u32;
+            if textured {
+                builder.bind_descriptor_sets(
+                    vulkano::pipeline::PipelineBindPoint::Graphics,
+                    layout.clone(),
+                    1,
+                    pds,
+                );
+            }
+            builder
+                .bind_vertex_buffers(0, [verts])
+                .bind_index_buffer(idx)
+                .draw_indexed(count, 1, 0, 0, 0)
+                .unwrap();
+        }
+    }
+}
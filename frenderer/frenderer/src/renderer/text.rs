@@ -0,0 +1,414 @@
+use super::{RenderMode, RenderState};
+use crate::assets;
+use crate::camera::Camera;
+use crate::font::TextStyle;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use std::collections::HashMap;
+use std::sync::Arc;
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::pipeline::graphics::color_blend::{
+    AttachmentBlend, BlendFactor, BlendOp, ColorBlendState,
+};
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::ViewportState;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::Pipeline;
+use vulkano::render_pass::Subpass;
+use vulkano::shader::{EntryPoint, SpecializationConstants};
+
+// A block of text standing in the scene.  It's laid out on the transform's
+// XY plane facing +Z, centered on its origin, with `style.size` world units
+// per line before the transform's scale.
+pub struct SingleRenderState {
+    font: assets::FontRef,
+    text: String,
+    style: TextStyle,
+    transform: Similarity3,
+}
+impl SingleRenderState {
+    pub(crate) fn new(
+        font: assets::FontRef,
+        text: &str,
+        style: TextStyle,
+        transform: Similarity3,
+    ) -> Self {
+        Self {
+            font,
+            text: text.to_string(),
+            style,
+            transform,
+        }
+    }
+    pub(crate) fn uses_texture(&self, tex: assets::TextureRef, assets: &assets::Assets) -> bool {
+        assets.font(self.font).texture == tex
+    }
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        Self {
+            font: other.font,
+            text: other.text.clone(),
+            style: self.style.interpolate(&other.style, r),
+            transform: self.transform.lerp(&other.transform, r),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
+struct InstanceData {
+    model: [f32; 4 * 4],
+    size_uv: [f32; 4],
+    color: [f32; 4],
+}
+vulkano::impl_vertex!(InstanceData, model, size_uv, color);
+struct BatchData {
+    material_pds: Arc<PersistentDescriptorSet>,
+    instance_data: Vec<InstanceData>,
+    instance_buf:
+        Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
+}
+
+pub struct Renderer {
+    pipeline: Arc<GraphicsPipeline>,
+    // alternate pipelines for the debug render modes
+    mode_pipelines: HashMap<RenderMode, Arc<GraphicsPipeline>>,
+    mode: RenderMode,
+    uniform_buffers: CpuBufferPool<super::FrameData>,
+    uniform_pds: SingleLayoutDescSetPool,
+    uniform_binding: Option<Arc<SingleLayoutDescSet>>,
+    instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
+    batches: HashMap<assets::TextureRef, BatchData>,
+}
+
+impl Renderer {
+    pub fn new(vulkan: &mut Vulkan) -> Self {
+        mod vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                include: ["src/shaders"],
+                src: "
+#version 450
+
+// instance data
+layout(location = 0) in mat4 model;
+layout(location = 4) in vec4 size_uv;
+layout(location = 5) in vec4 color;
+
+// outputs
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_world;
+layout(location = 2) out vec4 out_color;
+
+// uniforms
+#include <frame.glsl>
+
+void main() {
+  // 0: TL, 1: BL, 2: BR, 3: TL, 4: BR, 5: TR
+  vec2 posns[] = {
+    vec2(-0.5, 0.5),
+    vec2(-0.5, -0.5),
+    vec2(0.5, -0.5),
+    vec2(-0.5, 0.5),
+    vec2(0.5, -0.5),
+    vec2(0.5, 0.5),
+  };
+  vec2 pos = posns[gl_VertexIndex];
+  vec4 world = model * vec4(pos, 0.0, 1.0);
+  gl_Position = viewproj * world;
+  out_uv = vec2(size_uv.z, 1.0 - size_uv.w) + size_uv.xy * vec2(pos.x + 0.5, 0.5 - pos.y);
+  out_world = world.xyz;
+  out_color = vec4(color.rgb * color.a, color.a);
+}
+"
+            }
+        }
+
+        mod fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec4 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    vec4 col = texture(tex, uv) * color;
+                    f_color = vec4(mix(col.rgb, fog_color.rgb * col.a, fog_amount(world)), col.a);
+                }
+            "
+            }
+        }
+
+        mod debug_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                include: ["src/shaders"],
+                src: "
+                #version 450
+
+                // which view, from RenderMode::shader_mode
+                layout(constant_id = 0) const int mode = 1;
+
+                #include <frame.glsl>
+                layout(set = 1, binding = 0) uniform sampler2D tex;
+                layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 world;
+                layout(location = 2) in vec4 color;
+                layout(location = 0) out vec4 f_color;
+
+                void main() {
+                    // skip what the shaded glyph would leave clear, except when
+                    // counting overdraw, which costs the same either way
+                    vec4 col = texture(tex, uv) * color;
+                    if (mode != 3 && max(col.a, max(col.r, max(col.g, col.b))) < 0.1) {
+                        discard;
+                    }
+                    // quads carry no normals, so take the face's from the world position
+                    vec3 n = normalize(cross(dFdx(world), dFdy(world)));
+                    vec3 to_eye = normalize(camera_pos.xyz - world);
+                    if (dot(n, to_eye) < 0.0) { n = -n; }
+                    // a headlight, so shapes still read in the flat-colored views
+                    float light = 0.4 + 0.6 * abs(dot(n, to_eye));
+                    if (mode == 1) {
+                        f_color = vec4(n * 0.5 + 0.5, 1.0);
+                    } else if (mode == 2) {
+                        vec2 cell = floor(uv * 8.0);
+                        float check = mod(cell.x + cell.y, 2.0);
+                        f_color = vec4(mix(vec3(0.1), vec3(0.9), check) * light, 1.0);
+                    } else if (mode == 3) {
+                        // blended additively, once for every layer at this pixel
+                        f_color = vec4(0.1, 0.04, 0.01, 1.0);
+                    } else {
+                        // no bones to show
+                        f_color = vec4(vec3(0.5) * light, 1.0);
+                    }
+                }
+            "
+            }
+        }
+
+        let vs = vs::load(vulkan.device.clone()).unwrap();
+        let fs = fs::load(vulkan.device.clone()).unwrap();
+        let debug_fs = debug_fs::load(vulkan.device.clone()).unwrap();
+        let pipeline = Self::build_pipeline(
+            vulkan,
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+            (),
+            RenderMode::Shaded,
+        );
+        // wireframe keeps the usual shaders; the other views share the debug one
+        let mode_pipelines = RenderMode::ALL
+            .into_iter()
+            .filter(|mode| *mode != RenderMode::Shaded)
+            .map(|mode| {
+                let pipeline = if mode == RenderMode::Wireframe {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        fs.entry_point("main").unwrap(),
+                        (),
+                        mode,
+                    )
+                } else {
+                    Self::build_pipeline(
+                        vulkan,
+                        vs.entry_point("main").unwrap(),
+                        debug_fs.entry_point("main").unwrap(),
+                        debug_fs::SpecializationConstants {
+                            mode: mode.shader_mode(),
+                        },
+                        mode,
+                    )
+                };
+                (mode, pipeline)
+            })
+            .collect();
+
+        let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let uniform_pds =
+            SingleLayoutDescSetPool::new(pipeline.layout().set_layouts().get(0).unwrap().clone());
+        let instance_pool = CpuBufferPool::vertex_buffer(vulkan.device.clone());
+
+        Self {
+            pipeline,
+            mode_pipelines,
+            mode: RenderMode::Shaded,
+            uniform_buffers,
+            uniform_pds,
+            uniform_binding: None,
+            instance_pool,
+            batches: HashMap::new(),
+        }
+    }
+    fn build_pipeline<S: SpecializationConstants>(
+        vulkan: &Vulkan,
+        vs: EntryPoint,
+        fs: EntryPoint,
+        fs_constants: S,
+        mode: RenderMode,
+    ) -> Arc<GraphicsPipeline> {
+        use vulkano::pipeline::graphics::depth_stencil::*;
+        // overdraw counts every layer, so it needs its own blending and no depth test
+        let (color_blend, depth_stencil) = if mode == RenderMode::Overdraw {
+            (mode.color_blend_state(), mode.depth_stencil_state())
+        } else {
+            (
+                // glyph atlases are premultiplied
+                ColorBlendState::new(1).blend(AttachmentBlend {
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::One,
+                    color_destination: BlendFactor::OneMinusSrcAlpha,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::One,
+                    alpha_destination: BlendFactor::OneMinusSrcAlpha,
+                }),
+                // hidden by the scene, but glyph quads don't hide each other
+                DepthStencilState {
+                    depth: Some(DepthState {
+                        compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
+                        enable_dynamic: false,
+                        write_enable: vulkano::pipeline::StateMode::Fixed(false),
+                    }),
+                    depth_bounds: None,
+                    stencil: None,
+                },
+            )
+        };
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().instance::<InstanceData>())
+            .vertex_shader(vs, ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fs, fs_constants)
+            .multisample_state(vulkan.multisample_state())
+            .rasterization_state(
+                RasterizationState::new()
+                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
+                    .front_face(
+                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                    )
+                    .polygon_mode(mode.polygon_mode(vulkan)),
+            )
+            .color_blend_state(color_blend)
+            .depth_stencil_state(depth_stencil)
+            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap()
+    }
+    pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        use std::collections::hash_map::Entry;
+        for v in rs.texts.values() {
+            let font = assets.font(v.font);
+            let layout = font.layout(&v.text, &v.style);
+            let trf = v.transform.into_homogeneous_matrix();
+            let batch = match self.batches.entry(font.texture) {
+                Entry::Occupied(o) => o.into_mut(),
+                Entry::Vacant(e) => {
+                    let texture = assets.texture(font.texture);
+                    e.insert(BatchData {
+                        material_pds: PersistentDescriptorSet::new(
+                            self.pipeline.layout().set_layouts().get(1).unwrap().clone(),
+                            [
+                                vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
+                                    0,
+                                    texture.view.clone(),
+                                    texture.sampler.clone(),
+                                ),
+                            ],
+                        )
+                        .unwrap(),
+                        instance_data: vec![],
+                        instance_buf: None,
+                    })
+                }
+            };
+            for g in layout.glyphs {
+                // layout is y-down from the block's top left; the scene is y-up around its center
+                let center = g.pos + g.size / 2.0 - layout.size / 2.0;
+                let model = trf
+                    * Mat4::from_translation(Vec3::new(center.x, -center.y, 0.0))
+                    * Mat4::from_nonuniform_scale(Vec3::new(g.size.x, g.size.y, 1.0));
+                batch.instance_data.push(InstanceData {
+                    model: *model.as_array(),
+                    size_uv: [g.region.sz.x, g.region.sz.y, g.region.pos.x, g.region.pos.y],
+                    color: v.style.color.into(),
+                });
+            }
+        }
+        let buf = self
+            .uniform_buffers
+            .next(super::FrameData::new(camera, rs.fog))
+            .unwrap();
+        let uds = self
+            .uniform_pds
+            .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
+                0, buf,
+            )])
+            .unwrap();
+        self.uniform_binding = Some(uds);
+        self.mode = rs.render_mode;
+        for b in self.batches.values_mut() {
+            b.instance_buf = if b.instance_data.is_empty() {
+                None
+            } else {
+                Some(
+                    self.instance_pool
+                        .chunk(b.instance_data.iter().copied())
+                        .unwrap(),
+                )
+            };
+        }
+    }
+    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+        let uds = self.uniform_binding.clone().unwrap();
+        let pipeline = self
+            .mode_pipelines
+            .get(&self.mode)
+            .unwrap_or(&self.pipeline)
+            .clone();
+        builder.bind_pipeline_graphics(pipeline.clone());
+        for b in self.batches.values() {
+            if let Some(buf) = b.instance_buf.clone() {
+                builder
+                    .bind_vertex_buffers(0, [buf])
+                    .bind_descriptor_sets(
+                        vulkano::pipeline::PipelineBindPoint::Graphics,
+                        pipeline.layout().clone(),
+                        0,
+                        uds.clone(),
+                    )
+                    .bind_descriptor_sets(
+                        vulkano::pipeline::PipelineBindPoint::Graphics,
+                        pipeline.layout().clone(),
+                        1,
+                        b.material_pds.clone(),
+                    )
+                    .draw(6, b.instance_data.len() as u32, 0, 0)
+                    .unwrap();
+            }
+        }
+        // delete batches for fonts that didn't get drawn this frame, and
+        // clear the rest without throwing away their allocations
+        self.batches.retain(|_k, b| !b.instance_data.is_empty());
+        self.batches
+            .values_mut()
+            .for_each(|b| b.instance_data.clear());
+    }
+}
@@ -0,0 +1,25 @@
+// Per-frame data shared by the scene shaders; FrameData in renderer.rs
+layout(set = 0, binding = 0) uniform FrameData {
+  mat4 viewproj;
+  vec4 camera_pos;
+  vec4 fog_color;
+  // mode (0 off, 1 linear, 2 exp, 3 exp2), density, start, end
+  vec4 fog_params;
+  mat4 view;
+};
+
+// How much of the fog color to mix in at a world position
+float fog_amount(vec3 world) {
+  float dist = distance(world, camera_pos.xyz);
+  if (fog_params.x < 0.5) {
+    return 0.0;
+  } else if (fog_params.x < 1.5) {
+    float range = max(fog_params.w - fog_params.z, 0.0001);
+    return clamp((dist - fog_params.z) / range, 0.0, 1.0);
+  } else if (fog_params.x < 2.5) {
+    return 1.0 - exp(-fog_params.y * dist);
+  } else {
+    float d = fog_params.y * dist;
+    return 1.0 - exp(-d * d);
+  }
+}
@@ -0,0 +1,235 @@
+use crate::assets::TextureRef;
+use crate::color_eyre::eyre::{ensure, eyre};
+use crate::types::*;
+use crate::Result;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Frame {
+    // same convention as sprite regions
+    pub region: Rect,
+    // in seconds
+    pub duration: f32,
+}
+
+pub struct Sequence {
+    pub name: String,
+    pub frames: Vec<Frame>,
+}
+impl Sequence {
+    pub fn duration(&self) -> f32 {
+        self.frames.iter().map(|f| f.duration).sum()
+    }
+    // The frame showing `t` seconds in; holds the last frame once it's done unless looping
+    pub fn frame_at(&self, t: f32, looping: bool) -> &Frame {
+        let duration = self.duration();
+        let mut t = t.max(0.0);
+        if looping && duration > 0.0 {
+            t %= duration;
+        }
+        for f in self.frames.iter() {
+            if t < f.duration {
+                return f;
+            }
+            t -= f.duration;
+        }
+        self.frames.last().unwrap()
+    }
+}
+
+// A texture cut up into frames, grouped into named sequences
+pub struct SpriteSheet {
+    pub texture: TextureRef,
+    sequences: Vec<Sequence>,
+    sequences_by_name: HashMap<String, usize>,
+}
+impl SpriteSheet {
+    pub(crate) fn new(texture: TextureRef) -> Self {
+        Self {
+            texture,
+            sequences: vec![],
+            sequences_by_name: HashMap::new(),
+        }
+    }
+    pub(crate) fn add_sequence(&mut self, name: &str, frames: Vec<Frame>) -> Result<()> {
+        ensure!(!frames.is_empty(), "Sprite sequence {} has no frames", name);
+        self.sequences_by_name
+            .insert(name.to_string(), self.sequences.len());
+        self.sequences.push(Sequence {
+            name: name.to_string(),
+            frames,
+        });
+        Ok(())
+    }
+    // A grid of `cell` sized frames, numbered in reading order.  Each
+    // sequence is a name, a list of cell numbers, and seconds per frame.
+    pub(crate) fn from_grid(
+        texture: TextureRef,
+        tex_size: (u32, u32),
+        cell: (u32, u32),
+        sequences: &[(&str, &[u32], f32)],
+    ) -> Result<Self> {
+        ensure!(
+            cell.0 > 0 && cell.1 > 0,
+            "Sprite sheet cells must not be empty ({}x{})",
+            cell.0,
+            cell.1
+        );
+        let (w, h) = (tex_size.0 as f32, tex_size.1 as f32);
+        let cols = tex_size.0 / cell.0;
+        let cells = cols * (tex_size.1 / cell.1);
+        let mut sheet = Self::new(texture);
+        for (name, indices, duration) in sequences.iter() {
+            let frames = indices
+                .iter()
+                .map(|&i| {
+                    ensure!(
+                        i < cells,
+                        "Sprite sequence {} uses missing cell {}",
+                        name,
+                        i
+                    );
+                    let (x, y) = ((i % cols) * cell.0, (i / cols) * cell.1);
+                    Ok(Frame {
+                        region: Rect::new(
+                            x as f32 / w,
+                            1.0 - y as f32 / h,
+                            cell.0 as f32 / w,
+                            cell.1 as f32 / h,
+                        ),
+                        duration: *duration,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            sheet.add_sequence(name, frames)?;
+        }
+        Ok(sheet)
+    }
+    // Reads Aseprite-style JSON (the hash or array layout).  Every frame gets
+    // a one-frame sequence under its file name, and each frame tag becomes a
+    // sequence too.
+    pub(crate) fn from_json(texture: TextureRef, json: &serde_json::Value) -> Result<Self> {
+        let meta = &json["meta"];
+        let w = meta["size"]["w"]
+            .as_f64()
+            .ok_or_else(|| eyre!("Sprite sheet JSON missing meta.size"))? as f32;
+        let h = meta["size"]["h"]
+            .as_f64()
+            .ok_or_else(|| eyre!("Sprite sheet JSON missing meta.size"))? as f32;
+        let entries: Vec<(String, &serde_json::Value)> = match &json["frames"] {
+            serde_json::Value::Object(frames) => {
+                frames.iter().map(|(name, f)| (name.clone(), f)).collect()
+            }
+            serde_json::Value::Array(frames) => frames
+                .iter()
+                .enumerate()
+                .map(|(i, f)| {
+                    let name = f["filename"]
+                        .as_str()
+                        .map(|s| s.to_string())
+                        .unwrap_or_else(|| i.to_string());
+                    (name, f)
+                })
+                .collect(),
+            _ => return Err(eyre!("Sprite sheet JSON missing frames")),
+        };
+        let mut frames = Vec::with_capacity(entries.len());
+        for (name, f) in entries.iter() {
+            let rect = &f["frame"];
+            let coord = |k: &str| {
+                rect[k]
+                    .as_f64()
+                    .map(|v| v as f32)
+                    .ok_or_else(|| eyre!("Sprite sheet frame {} missing frame.{}", name, k))
+            };
+            let frame = Frame {
+                region: Rect::new(
+                    coord("x")? / w,
+                    1.0 - coord("y")? / h,
+                    coord("w")? / w,
+                    coord("h")? / h,
+                ),
+                // milliseconds in the file
+                duration: f["duration"].as_f64().unwrap_or(100.0) as f32 / 1000.0,
+            };
+            frames.push(frame);
+        }
+        let mut sheet = Self::new(texture);
+        for ((name, _f), frame) in entries.iter().zip(frames.iter()) {
+            sheet.add_sequence(name, vec![*frame])?;
+        }
+        if let Some(tags) = meta["frameTags"].as_array() {
+            for tag in tags {
+                let name = tag["name"]
+                    .as_str()
+                    .ok_or_else(|| eyre!("Sprite sheet frame tag missing name"))?;
+                let from = tag["from"].as_u64().unwrap_or(0) as usize;
+                let to = tag["to"].as_u64().unwrap_or(0) as usize;
+                ensure!(
+                    from <= to && to < frames.len(),
+                    "Sprite sheet frame tag {} out of range",
+                    name
+                );
+                let forward = frames[from..=to].iter().copied();
+                let seq: Vec<Frame> = match tag["direction"].as_str().unwrap_or("forward") {
+                    "reverse" => forward.rev().collect(),
+                    // back down again without repeating either end
+                    "pingpong" if to > from => forward
+                        .chain(frames[from + 1..to].iter().copied().rev())
+                        .collect(),
+                    _ => forward.collect(),
+                };
+                sheet.add_sequence(name, seq)?;
+            }
+        }
+        Ok(sheet)
+    }
+    pub fn sequence_index(&self, name: &str) -> Option<usize> {
+        self.sequences_by_name.get(name).copied()
+    }
+    pub fn sequence(&self, which: usize) -> &Sequence {
+        &self.sequences[which]
+    }
+    pub fn region(&self, state: &SpriteAnimationState) -> Rect {
+        self.sequence(state.sequence)
+            .frame_at(state.t, state.looping)
+            .region
+    }
+}
+
+// Playback position in one of a sprite sheet's sequences
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SpriteAnimationState {
+    pub sequence: usize,
+    pub t: f32,
+    pub looping: bool,
+}
+impl SpriteAnimationState {
+    pub fn new(sequence: usize, looping: bool) -> Self {
+        Self {
+            sequence,
+            t: 0.0,
+            looping,
+        }
+    }
+    // Switches to another sequence from its start; keeps going if it's already playing
+    pub fn play(&mut self, sequence: usize, looping: bool) {
+        if self.sequence != sequence {
+            self.sequence = sequence;
+            self.t = 0.0;
+        }
+        self.looping = looping;
+    }
+    pub fn tick(&mut self, dt: f64) {
+        self.t += dt as f32;
+    }
+    pub fn interpolate(&self, other: &Self, r: f32) -> Self {
+        if self.sequence != other.sequence {
+            return *other;
+        }
+        Self {
+            t: self.t.lerp(other.t, r),
+            ..*other
+        }
+    }
+}
@@ -27,6 +27,49 @@ impl Rect {
     }
 }
 
+// A box and a sphere around a set of points, in the points' own space
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Bounds {
+    pub fn from_points(points: &[Vec3]) -> Self {
+        if points.is_empty() {
+            return Self {
+                min: Vec3::zero(),
+                max: Vec3::zero(),
+                center: Vec3::zero(),
+                radius: 0.0,
+            };
+        }
+        let (min, max) = points.iter().fold((points[0], points[0]), |(lo, hi), p| {
+            (lo.min_by_component(*p), hi.max_by_component(*p))
+        });
+        let center = (min + max) / 2.0;
+        let radius = points
+            .iter()
+            .map(|p| (*p - center).mag())
+            .fold(0.0, f32::max);
+        Self {
+            min,
+            max,
+            center,
+            radius,
+        }
+    }
+    // The bounding sphere once the points are moved by `trf`
+    pub fn sphere(&self, trf: Similarity3) -> (Vec3, f32) {
+        (
+            trf.transform_vec(self.center),
+            self.radius * trf.scale.abs(),
+        )
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Zeroable, Pod)]
 pub struct Color(pub u8, pub u8, pub u8, pub u8);
@@ -4,10 +4,12 @@ use vulkano::command_buffer::PrimaryAutoCommandBuffer;
 use vulkano::device::physical::{PhysicalDevice, PhysicalDeviceType};
 use vulkano::device::Device;
 use vulkano::device::DeviceExtensions;
-use vulkano::image::view::ImageView;
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewAbstract};
 use vulkano::image::AttachmentImage;
 use vulkano::image::ImageAccess;
 use vulkano::image::ImageUsage;
+use vulkano::image::SampleCount;
 use vulkano::image::SwapchainImage;
 use vulkano::instance::Instance;
 use vulkano::pipeline::graphics::viewport::Viewport;
@@ -20,21 +22,40 @@ use winit::window::Window;
 use winit::window::WindowBuilder;
 
 pub struct Vulkan {
-    pub surface: Arc<vulkano::swapchain::Surface<winit::window::Window>>,
+    // None when running headless
+    pub surface: Option<Arc<vulkano::swapchain::Surface<winit::window::Window>>>,
     pub device: Arc<vulkano::device::Device>,
     pub present_mode: vulkano::swapchain::PresentMode,
     pub min_image_count: u32,
     pub queue: Arc<vulkano::device::Queue>,
+    // the scene is drawn into an HDR image with this pass...
     pub render_pass: Arc<vulkano::render_pass::RenderPass>,
-    pub swapchain: Arc<Swapchain<winit::window::Window>>,
+    // ...post-processed from one HDR image into another with this one...
+    pub post_render_pass: Arc<vulkano::render_pass::RenderPass>,
+    // ...and finally copied out to the swapchain or offscreen image with this one
+    pub output_render_pass: Arc<vulkano::render_pass::RenderPass>,
+    pub samples: SampleCount,
+    pub swapchain: Option<Arc<Swapchain<winit::window::Window>>>,
+    pub images: Vec<Arc<SwapchainImage<Window>>>,
+    // what headless frames are drawn into, and read back from
+    pub offscreen: Option<Arc<AttachmentImage>>,
+    // of the final color attachment, whether swapchain or offscreen image
+    pub color_format: Format,
     pub viewport: Viewport,
+    // the scene's resolved HDR color, the size of the window
+    pub scene: Arc<dyn ImageViewAbstract>,
+    pub scene_framebuffer: Arc<vulkano::render_pass::Framebuffer>,
+    // one per swapchain image, for the output pass
     pub framebuffers: Vec<Arc<vulkano::render_pass::Framebuffer>>,
     pub recreate_swapchain: bool,
     pub previous_frame_end: Option<Box<dyn vulkano::sync::GpuFuture>>,
 }
 
 impl Vulkan {
-    pub fn new(wb: WindowBuilder, event_loop: &EventLoop<()>) -> Self {
+    // Scenes and render targets are drawn in linear color with room above 1.0
+    pub const SCENE_FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+
+    pub fn new(wb: WindowBuilder, event_loop: &EventLoop<()>, samples: u32) -> Self {
         dbg!(vulkano::Version::HEADER_VERSION);
         let required_extensions = vulkano::instance::InstanceExtensions {
             ext_debug_report: true,
@@ -88,6 +109,12 @@ impl Vulkan {
                 enabled_extensions: physical_device
                     .required_extensions()
                     .union(&device_extensions),
+                enabled_features: vulkano::device::Features {
+                    sampler_anisotropy: physical_device.supported_features().sampler_anisotropy,
+                    // for the wireframe render mode
+                    fill_mode_non_solid: physical_device.supported_features().fill_mode_non_solid,
+                    ..vulkano::device::Features::none()
+                },
                 queue_create_infos: vec![vulkano::device::QueueCreateInfo::family(queue_family)],
                 ..Default::default()
             },
@@ -106,7 +133,7 @@ impl Vulkan {
                 surface.clone(),
                 vulkano::swapchain::SwapchainCreateInfo {
                     image_extent: dimensions,
-                    image_usage: ImageUsage::color_attachment(),
+                    image_usage: Self::swapchain_usage(),
                     min_image_count,
                     present_mode,
                     ..Default::default()
@@ -114,28 +141,11 @@ impl Vulkan {
             )
             .unwrap()
         };
-        let render_pass = vulkano::single_pass_renderpass!(
-            device.clone(),
-            attachments: {
-                color: {
-                    load: Clear,
-                    store: Store,
-                    format: swapchain.image_format(),
-                    samples: 1,
-                },
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: vulkano::format::Format::D32_SFLOAT,
-                    samples: 1,
-                }
-            },
-            pass: {
-                color: [color],
-                depth_stencil: {depth}
-            }
-        )
-        .unwrap();
+        let samples = Self::best_sample_count(&physical_device, samples);
+        let render_pass = Self::create_render_pass(device.clone(), Self::SCENE_FORMAT, samples);
+        let post_render_pass = Self::create_post_render_pass(device.clone(), Self::SCENE_FORMAT);
+        let output_render_pass =
+            Self::create_post_render_pass(device.clone(), swapchain.image_format());
 
         let mut viewport = Viewport {
             origin: [0.0, 0.0],
@@ -143,33 +153,223 @@ impl Vulkan {
             depth_range: 0.0..1.0,
         };
 
-        let framebuffers = Self::window_size_dependent_setup(
+        let framebuffers =
+            Self::window_size_dependent_setup(&images, output_render_pass.clone(), &mut viewport);
+        let (scene, scene_framebuffer) = Self::create_scene_target(
             device.clone(),
-            &images,
             render_pass.clone(),
-            &mut viewport,
+            samples,
+            images[0].dimensions().width_height(),
         );
         let recreate_swapchain = false;
         let previous_frame_end = Some(sync::now(device.clone()).boxed());
 
         Self {
-            surface,
+            surface: Some(surface),
             device,
             present_mode,
             min_image_count,
             render_pass,
+            post_render_pass,
+            output_render_pass,
+            samples,
             queue,
-            swapchain,
+            color_format: swapchain.image_format(),
+            swapchain: Some(swapchain),
+            images,
+            offscreen: None,
             viewport,
+            scene,
+            scene_framebuffer,
             framebuffers,
             recreate_swapchain,
             previous_frame_end,
         }
     }
-    fn window_size_dependent_setup(
+    // Renders into a `width` by `height` image instead of a window, for
+    // screenshots and tests on machines without a display.  Validation is
+    // only turned on if the layer is installed.
+    pub fn headless(width: u32, height: u32, samples: u32) -> Self {
+        let validation = "VK_LAYER_KHRONOS_validation";
+        let has_validation = vulkano::instance::layers_list()
+            .map(|mut layers| layers.any(|l| l.name() == validation))
+            .unwrap_or(false);
+        let enabled_layers = if has_validation {
+            vec![validation.to_string()]
+        } else {
+            vec![]
+        };
+        let instance = Instance::new(vulkano::instance::InstanceCreateInfo {
+            enabled_layers,
+            max_api_version: Some(vulkano::Version::V1_2),
+            ..Default::default()
+        })
+        .unwrap();
+        // no swapchain needed, and software rasterizers like lavapipe are fine
+        let (physical_device, queue_family) = PhysicalDevice::enumerate(&instance)
+            .filter_map(|p| {
+                p.queue_families()
+                    .find(|&q| q.supports_graphics())
+                    .map(|q| (p, q))
+            })
+            .min_by_key(|(p, _)| match p.properties().device_type {
+                PhysicalDeviceType::DiscreteGpu => 0,
+                PhysicalDeviceType::IntegratedGpu => 1,
+                PhysicalDeviceType::VirtualGpu => 2,
+                PhysicalDeviceType::Cpu => 3,
+                PhysicalDeviceType::Other => 4,
+            })
+            .unwrap();
+        let (device, mut queues) = Device::new(
+            physical_device,
+            vulkano::device::DeviceCreateInfo {
+                enabled_extensions: physical_device.required_extensions(),
+                enabled_features: vulkano::device::Features {
+                    sampler_anisotropy: physical_device.supported_features().sampler_anisotropy,
+                    // for the wireframe render mode
+                    fill_mode_non_solid: physical_device.supported_features().fill_mode_non_solid,
+                    ..vulkano::device::Features::none()
+                },
+                queue_create_infos: vec![vulkano::device::QueueCreateInfo::family(queue_family)],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let queue = queues.next().unwrap();
+        // matches the byte order of `Image`, so frames read straight back
+        let color_format = Format::R8G8B8A8_SRGB;
+        let samples = Self::best_sample_count(&physical_device, samples);
+        let render_pass = Self::create_render_pass(device.clone(), Self::SCENE_FORMAT, samples);
+        let post_render_pass = Self::create_post_render_pass(device.clone(), Self::SCENE_FORMAT);
+        let output_render_pass = Self::create_post_render_pass(device.clone(), color_format);
+        let dimensions = [width, height];
+        let offscreen = AttachmentImage::with_usage(
+            device.clone(),
+            dimensions,
+            color_format,
+            ImageUsage {
+                color_attachment: true,
+                transfer_source: true,
+                ..ImageUsage::none()
+            },
+        )
+        .unwrap();
+        let framebuffer = Self::create_output_framebuffer(
+            output_render_pass.clone(),
+            ImageView::new_default(offscreen.clone()).unwrap(),
+        );
+        let (scene, scene_framebuffer) =
+            Self::create_scene_target(device.clone(), render_pass.clone(), samples, dimensions);
+        let previous_frame_end = Some(sync::now(device.clone()).boxed());
+        Self {
+            surface: None,
+            device,
+            present_mode: vulkano::swapchain::PresentMode::Fifo,
+            min_image_count: 0,
+            render_pass,
+            post_render_pass,
+            output_render_pass,
+            samples,
+            queue,
+            swapchain: None,
+            images: vec![],
+            offscreen: Some(offscreen),
+            color_format,
+            viewport: Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [width as f32, height as f32],
+                depth_range: 0.0..1.0,
+            },
+            scene,
+            scene_framebuffer,
+            framebuffers: vec![framebuffer],
+            recreate_swapchain: false,
+            previous_frame_end,
+        }
+    }
+    fn create_render_pass(
         device: Arc<Device>,
+        format: Format,
+        samples: SampleCount,
+    ) -> Arc<RenderPass> {
+        if samples == SampleCount::Sample1 {
+            vulkano::single_pass_renderpass!(
+                device,
+                attachments: {
+                    color: {
+                        load: Clear,
+                        store: Store,
+                        format: format,
+                        samples: 1,
+                    },
+                    depth: {
+                        load: Clear,
+                        store: DontCare,
+                        format: Format::D32_SFLOAT,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [color],
+                    depth_stencil: {depth}
+                }
+            )
+            .unwrap()
+        } else {
+            // Draw into multisampled color and depth, then resolve into the final color image
+            vulkano::single_pass_renderpass!(
+                device,
+                attachments: {
+                    intermediary: {
+                        load: Clear,
+                        store: DontCare,
+                        format: format,
+                        samples: samples,
+                    },
+                    depth: {
+                        load: Clear,
+                        store: DontCare,
+                        format: Format::D32_SFLOAT,
+                        samples: samples,
+                    },
+                    color: {
+                        load: DontCare,
+                        store: Store,
+                        format: format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [intermediary],
+                    depth_stencil: {depth},
+                    resolve: [color]
+                }
+            )
+            .unwrap()
+        }
+    }
+    // Full-screen passes read earlier images, so there's nothing to load or clear
+    fn create_post_render_pass(device: Arc<Device>, format: Format) -> Arc<RenderPass> {
+        vulkano::single_pass_renderpass!(
+            device,
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: format,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap()
+    }
+    fn window_size_dependent_setup(
         images: &[Arc<SwapchainImage<Window>>],
-        render_pass: Arc<RenderPass>,
+        output_render_pass: Arc<RenderPass>,
         viewport: &mut Viewport,
     ) -> Vec<Arc<Framebuffer>> {
         let dimensions = images[0].dimensions().width_height();
@@ -177,33 +377,146 @@ impl Vulkan {
         images
             .iter()
             .map(|image| {
-                let view = ImageView::new_default(image.clone()).unwrap();
-                let depth_buffer = ImageView::new_default(
-                    AttachmentImage::with_usage(
-                        device.clone(),
-                        dimensions,
-                        vulkano::format::Format::D32_SFLOAT,
-                        ImageUsage {
-                            depth_stencil_attachment: true,
-                            transient_attachment: true,
-                            ..ImageUsage::none()
-                        },
-                    )
-                    .unwrap(),
+                Self::create_output_framebuffer(
+                    output_render_pass.clone(),
+                    ImageView::new_default(image.clone()).unwrap(),
                 )
-                .unwrap();
-
-                Framebuffer::new(
-                    render_pass.clone(),
-                    vulkano::render_pass::FramebufferCreateInfo {
-                        attachments: vec![view, depth_buffer],
-                        ..Default::default()
-                    },
-                )
-                .unwrap()
             })
             .collect::<Vec<_>>()
     }
+    fn create_output_framebuffer(
+        render_pass: Arc<RenderPass>,
+        target: Arc<dyn ImageViewAbstract>,
+    ) -> Arc<Framebuffer> {
+        Framebuffer::new(
+            render_pass,
+            vulkano::render_pass::FramebufferCreateInfo {
+                attachments: vec![target],
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+    // A framebuffer for the main render pass whose final color lands in `target`
+    fn create_framebuffer(
+        device: Arc<Device>,
+        render_pass: Arc<RenderPass>,
+        samples: SampleCount,
+        target: Arc<dyn ImageViewAbstract>,
+        format: Format,
+        dimensions: [u32; 2],
+    ) -> Arc<Framebuffer> {
+        let depth_buffer = ImageView::new_default(
+            AttachmentImage::multisampled_with_usage(
+                device.clone(),
+                dimensions,
+                samples,
+                Format::D32_SFLOAT,
+                ImageUsage {
+                    depth_stencil_attachment: true,
+                    transient_attachment: true,
+                    ..ImageUsage::none()
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let attachments: Vec<Arc<dyn ImageViewAbstract>> = if samples == SampleCount::Sample1 {
+            vec![target, depth_buffer]
+        } else {
+            let intermediary = ImageView::new_default(
+                AttachmentImage::transient_multisampled(device, dimensions, samples, format)
+                    .unwrap(),
+            )
+            .unwrap();
+            vec![intermediary, depth_buffer, target]
+        };
+
+        Framebuffer::new(
+            render_pass,
+            vulkano::render_pass::FramebufferCreateInfo {
+                attachments,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+    // An offscreen image the size of `dimensions` that can be drawn into
+    // with the main render pass and then sampled like any other texture
+    pub fn create_render_target(
+        &self,
+        dimensions: [u32; 2],
+    ) -> (Arc<dyn ImageViewAbstract>, Arc<Framebuffer>) {
+        Self::create_scene_target(
+            self.device.clone(),
+            self.render_pass.clone(),
+            self.samples,
+            dimensions,
+        )
+    }
+    fn create_scene_target(
+        device: Arc<Device>,
+        render_pass: Arc<RenderPass>,
+        samples: SampleCount,
+        dimensions: [u32; 2],
+    ) -> (Arc<dyn ImageViewAbstract>, Arc<Framebuffer>) {
+        let color = ImageView::new_default(
+            AttachmentImage::sampled(device.clone(), dimensions, Self::SCENE_FORMAT).unwrap(),
+        )
+        .unwrap();
+        let framebuffer = Self::create_framebuffer(
+            device,
+            render_pass,
+            samples,
+            color.clone(),
+            Self::SCENE_FORMAT,
+            dimensions,
+        );
+        (color, framebuffer)
+    }
+    // An HDR image for post-processing passes to draw into and sample from
+    pub fn create_post_target(
+        &self,
+        dimensions: [u32; 2],
+    ) -> (Arc<dyn ImageViewAbstract>, Arc<Framebuffer>) {
+        let color: Arc<dyn ImageViewAbstract> = ImageView::new_default(
+            AttachmentImage::sampled(self.device.clone(), dimensions, Self::SCENE_FORMAT).unwrap(),
+        )
+        .unwrap();
+        let framebuffer =
+            Self::create_output_framebuffer(self.post_render_pass.clone(), color.clone());
+        (color, framebuffer)
+    }
+    // Clear values for the main render pass, in attachment order
+    pub fn clear_values(&self, color: [f32; 4]) -> Vec<vulkano::format::ClearValue> {
+        use vulkano::format::ClearValue;
+        if self.samples == SampleCount::Sample1 {
+            vec![color.into(), (0.0).into()]
+        } else {
+            vec![color.into(), (0.0).into(), ClearValue::None]
+        }
+    }
+    pub fn multisample_state(&self) -> vulkano::pipeline::graphics::multisample::MultisampleState {
+        vulkano::pipeline::graphics::multisample::MultisampleState {
+            rasterization_samples: self.samples,
+            ..Default::default()
+        }
+    }
+    // Highest sample count up to `requested` that both color and depth attachments support
+    fn best_sample_count(dev: &PhysicalDevice, requested: u32) -> SampleCount {
+        let props = dev.properties();
+        let color = props.framebuffer_color_sample_counts;
+        let depth = props.framebuffer_depth_sample_counts;
+        [
+            (8, color.sample8 && depth.sample8, SampleCount::Sample8),
+            (4, color.sample4 && depth.sample4, SampleCount::Sample4),
+            (2, color.sample2 && depth.sample2, SampleCount::Sample2),
+        ]
+        .into_iter()
+        .find(|&(n, supported, _)| n <= requested && supported)
+        .map(|(_, _, samples)| samples)
+        .unwrap_or(SampleCount::Sample1)
+    }
 
     pub fn recreate_swapchain_if_necessary(&mut self) {
         {
@@ -214,34 +527,58 @@ impl Vulkan {
                 // Dropping the future will block until it's done.
             }
         }
-        if self.recreate_swapchain {
-            let dimensions: [u32; 2] = self.surface.window().inner_size().into();
+        // headless images never change size
+        if let (true, Some(surface), Some(swapchain)) =
+            (self.recreate_swapchain, &self.surface, &self.swapchain)
+        {
+            let dimensions: [u32; 2] = surface.window().inner_size().into();
             let (new_swapchain, new_images) =
-                match self
-                    .swapchain
-                    .recreate(vulkano::swapchain::SwapchainCreateInfo {
-                        image_extent: dimensions,
-                        image_usage: ImageUsage::color_attachment(),
-                        present_mode: self.present_mode,
-                        min_image_count: self.min_image_count,
-                        ..Default::default()
-                    }) {
+                match swapchain.recreate(vulkano::swapchain::SwapchainCreateInfo {
+                    image_extent: dimensions,
+                    image_usage: Self::swapchain_usage(),
+                    present_mode: self.present_mode,
+                    min_image_count: self.min_image_count,
+                    ..Default::default()
+                }) {
                     Ok(r) => r,
                     Err(SwapchainCreationError::ImageExtentNotSupported { .. }) => return,
                     Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
                 };
 
-            self.swapchain = new_swapchain;
+            self.swapchain = Some(new_swapchain);
             self.framebuffers = Self::window_size_dependent_setup(
-                self.device.clone(),
                 &new_images,
-                self.render_pass.clone(),
+                self.output_render_pass.clone(),
                 &mut self.viewport,
             );
+            let (scene, scene_framebuffer) = Self::create_scene_target(
+                self.device.clone(),
+                self.render_pass.clone(),
+                self.samples,
+                new_images[0].dimensions().width_height(),
+            );
+            self.scene = scene;
+            self.scene_framebuffer = scene_framebuffer;
+            self.images = new_images;
             self.recreate_swapchain = false;
         }
     }
 
+    // Swapchain images can be copied out of for screenshots
+    fn swapchain_usage() -> ImageUsage {
+        ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        }
+    }
+    // The image framebuffer `image_num` finally lands in
+    pub fn frame_image(&self, image_num: usize) -> Arc<dyn ImageAccess> {
+        match &self.offscreen {
+            Some(offscreen) => offscreen.clone(),
+            None => self.images[image_num].clone(),
+        }
+    }
     fn best_present_mode<W>(
         dev: &vulkano::device::physical::PhysicalDevice,
         surf: &vulkano::swapchain::Surface<W>,
@@ -253,8 +590,12 @@ impl Vulkan {
     }
 
     pub fn get_next_image(&mut self) -> Option<usize> {
+        let swapchain = self
+            .swapchain
+            .clone()
+            .expect("Headless Vulkan has no swapchain images");
         let (image_num, suboptimal, acquire_future) =
-            match swapchain::acquire_next_image(self.swapchain.clone(), None) {
+            match swapchain::acquire_next_image(swapchain, None) {
                 Ok(r) => r,
                 Err(AcquireError::OutOfDate) => {
                     self.recreate_swapchain = true;
@@ -273,12 +614,16 @@ impl Vulkan {
         Some(image_num)
     }
     pub fn execute_commands(&mut self, command_buffer: PrimaryAutoCommandBuffer, image_num: usize) {
+        let swapchain = self
+            .swapchain
+            .clone()
+            .expect("Headless Vulkan can't present");
         let old_fut = self.previous_frame_end.take();
         let future = old_fut
             .unwrap_or_else(|| vulkano::sync::now(self.device.clone()).boxed())
             .then_execute(self.queue.clone(), command_buffer)
             .unwrap()
-            .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), image_num)
+            .then_swapchain_present(self.queue.clone(), swapchain, image_num)
             .then_signal_fence_and_flush();
 
         match future {
@@ -295,6 +640,20 @@ impl Vulkan {
             }
         }
     }
+    // Runs `command_buffer` and blocks until it's done, so anything it
+    // copied out of the GPU can be read right away
+    pub fn execute_and_wait(&mut self, command_buffer: PrimaryAutoCommandBuffer) {
+        let old_fut = self.previous_frame_end.take();
+        old_fut
+            .unwrap_or_else(|| vulkano::sync::now(self.device.clone()).boxed())
+            .then_execute(self.queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+        self.previous_frame_end = Some(vulkano::sync::now(self.device.clone()).boxed());
+    }
     pub fn wait_for(&mut self, f: Box<dyn GpuFuture>) {
         let old_fut = self.previous_frame_end.take();
         self.previous_frame_end = match old_fut {